@@ -1,21 +1,201 @@
-use crate::Amount;
+use std::collections::BTreeMap;
+
+use crate::{Amount, Balances, Currency, LedgerAccount, LedgerEventKind, Posting};
 
 /// Possible errors to occur during account operations
 #[derive(Debug, thiserror::Error)]
 pub enum AccountError {
     #[error("The account is locked")]
     Locked,
+    #[error("The account is frozen")]
+    Frozen,
+    #[error("The account is closed")]
+    Closed,
+    #[error("The account is neither locked nor frozen")]
+    NotLocked,
+    #[error("The account still holds back funds")]
+    FundsHeld,
     #[error("The account does not hold enough available funds")]
     InsufficientFunds,
+    #[error("The operation would overflow the account's funds")]
+    Overflow,
+}
+
+impl AccountError {
+    /// A stable, machine-readable code identifying the error, i.e. `insufficient_funds`
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Locked => "account_locked",
+            Self::Frozen => "account_frozen",
+            Self::Closed => "account_closed",
+            Self::NotLocked => "account_not_locked",
+            Self::FundsHeld => "funds_held",
+            Self::InsufficientFunds => "insufficient_funds",
+            Self::Overflow => "overflow",
+        }
+    }
 }
 
 /// The unique identifier of an account
-#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct AccountId(u16);
 
+impl AccountId {
+    /// Creates an id from its numeric value
+    pub fn new(value: u16) -> Self {
+        Self(value)
+    }
+
+    /// The numeric value of the id
+    pub fn value(self) -> u16 {
+        self.0
+    }
+}
+
+impl std::fmt::Display for AccountId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The operations that can be performed on an [`Account`]
+///
+/// Operations that move funds are performed by posting the matching [`LedgerEventKind`],
+/// see [`Account::post`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AccountOperation {
+    /// See [`LedgerEventKind::FundsDeposited`]
+    Deposit,
+    /// See [`LedgerEventKind::FundsWithdrawn`]
+    Withdrawal,
+    /// See [`LedgerEventKind::FundsHeld`]
+    HoldBack,
+    /// See [`LedgerEventKind::FundsReleased`]
+    SetFree,
+    /// See [`LedgerEventKind::FundsChargedBack`]
+    ChargeBack,
+    /// See [`LedgerEventKind::CreditProvisioned`]
+    ProvisionallyCredit,
+    /// See [`LedgerEventKind::CreditRevoked`]
+    RevokeCredit,
+    /// See [`LedgerEventKind::WithdrawalReversed`]
+    ReverseWithdrawal,
+    /// See [`LedgerEventKind::TransferReversed`]
+    ReverseTransfer,
+    /// See [`LedgerEventKind::TransferRefunded`]
+    RefundTransfer,
+    /// See [`Account::unlock`]
+    Unlock,
+    /// See [`Account::freeze`]
+    Freeze,
+    /// See [`Account::close`]
+    Close,
+    /// See [`LedgerEventKind::FundsConvertedOut`] and [`LedgerEventKind::FundsConvertedIn`]
+    Convert,
+}
+
+impl AccountOperation {
+    /// Whether the operation completes an open dispute, i.e. resolves it or charges it back
+    pub fn completes_dispute(self) -> bool {
+        matches!(
+            self,
+            Self::SetFree | Self::ChargeBack | Self::RevokeCredit | Self::ReverseWithdrawal | Self::ReverseTransfer | Self::RefundTransfer
+        )
+    }
+}
+
+/// The status of an account
+///
+/// The status decides which [`AccountOperation`]s are permitted on the account.
+/// See [`AccountStatus::permits`] for more info.
+///
+/// An account has exactly one status. A closed account stays closed, and a freeze takes
+/// precedence over the lock of a chargeback, so the reason of the freeze is kept until an
+/// operator unlocks the account.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum AccountStatus {
+    /// The account can be used without restrictions
+    #[default]
+    Active,
+    /// The account was locked after a chargeback
+    Locked,
+    /// The account was temporarily frozen by an operator
+    Frozen {
+        /// Why the account was frozen
+        reason: String,
+    },
+    /// The account was closed by an operator, and cannot be used anymore
+    Closed,
+}
+
+impl AccountStatus {
+    /// Whether the operation can be performed on an account with this status
+    ///
+    /// Locked and frozen accounts cannot be used to move funds in or out, or to open
+    /// new disputes. But open disputes can still be completed, i.e. resolved or charged
+    /// back, since the held funds would otherwise be stuck forever. Closed accounts
+    /// cannot be used at all.
+    pub fn permits(&self, operation: AccountOperation) -> bool {
+        match self {
+            Self::Active => operation != AccountOperation::Unlock,
+            Self::Locked | Self::Frozen { .. } => operation.completes_dispute()
+                || matches!(operation, AccountOperation::Unlock | AccountOperation::Close),
+            Self::Closed => false,
+        }
+    }
+
+    /// Whether the account cannot be used without restrictions
+    pub fn is_locked(&self) -> bool {
+        *self != Self::Active
+    }
+
+    /// Why the account was frozen, if it is frozen
+    pub fn reason(&self) -> Option<&str> {
+        match self {
+            Self::Frozen { reason } => Some(reason),
+            _ => None,
+        }
+    }
+
+    /// The lowercase name of the status, i.e. `frozen`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Active => "active",
+            Self::Locked => "locked",
+            Self::Frozen { .. } => "frozen",
+            Self::Closed => "closed",
+        }
+    }
+
+    /// The status with the specified name, see [`AccountStatus::name`]
+    ///
+    /// Fails for unknown names, so a malformed record never unlocks an account. The reason
+    /// is only used for frozen accounts.
+    pub(crate) fn from_name(name: &str, reason: Option<String>) -> Result<Self, String> {
+        match name {
+            "active" => Ok(Self::Active),
+            "locked" => Ok(Self::Locked),
+            "frozen" => Ok(Self::Frozen { reason: reason.unwrap_or_default() }),
+            "closed" => Ok(Self::Closed),
+            _ => Err(format!("unknown account status `{}`", name)),
+        }
+    }
+
+    /// The error returned for operations that are not permitted by this status
+    fn error(&self) -> AccountError {
+        match self {
+            Self::Active => AccountError::NotLocked,
+            Self::Locked => AccountError::Locked,
+            Self::Frozen { .. } => AccountError::Frozen,
+            Self::Closed => AccountError::Closed,
+        }
+    }
+}
+
 /// A user account
 ///
-/// The user account consists of two sub accounts:
+/// The user account holds funds in any number of currencies. In every currency, the
+/// funds are split into two sub accounts:
 /// 1. The available funds:
 ///    Available funds are funds that can withdrawn,
 ///    or used for other purposes.
@@ -24,13 +204,15 @@ pub struct AccountId(u16);
 ///    possible future claims, like chargebacks. The
 ///    client cannot use these funds until they are
 ///    either charged back, or freed.
-#[derive(Debug, serde::Deserialize, PartialEq, Eq)]
+///
+/// Funds in different currencies are never mixed. The status of the account applies to
+/// all currencies.
+#[derive(Clone, Debug, serde::Deserialize, PartialEq, Eq)]
+#[serde(try_from = "SerializedAccount")]
 pub struct Account {
-    #[serde(rename = "client")]
     id: AccountId,
-    available: Amount,
-    held: Amount,
-    locked: bool,
+    funds: BTreeMap<Currency, Balances>,
+    status: AccountStatus,
 }
 
 impl Account {
@@ -38,86 +220,194 @@ impl Account {
     pub fn new(id: AccountId) -> Self {
         Self {
             id,
-            available: Amount::from_num(0),
-            held: Amount::from_num(0),
-            locked: false,
+            funds: BTreeMap::new(),
+            status: AccountStatus::Active,
         }
     }
 
+    /// Recreates a previously stored account
+    ///
+    /// Fails if the total funds in any currency overflow, so a corrupted record cannot break
+    /// the totals of the account.
+    pub(crate) fn restore(id: AccountId, funds: BTreeMap<Currency, Balances>, status: AccountStatus) -> Result<Self, AccountError> {
+        for balances in funds.values() {
+            balances.available
+                .checked_add(balances.held)
+                .ok_or(AccountError::Overflow)?;
+        }
+
+        Ok(Self { id, funds, status })
+    }
+
     /// The identifier of the account
     pub fn id(&self) -> AccountId {
         self.id
     }
 
-    /// The total funds in the account
+    /// The currencies the account ever held funds in, ordered by their codes
+    pub fn currencies(&self) -> impl Iterator<Item = Currency> + '_ {
+        self.funds.keys().copied()
+    }
+
+    /// The funds in the specified currency
+    pub fn balances(&self, currency: Currency) -> Balances {
+        self.funds
+            .get(&currency)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The funds in the specified currency that can be withdrawn
+    pub fn available(&self, currency: Currency) -> Amount {
+        self.balances(currency).available
+    }
+
+    /// The funds in the specified currency that are held back for open disputes
+    pub fn held(&self, currency: Currency) -> Amount {
+        self.balances(currency).held
+    }
+
+    /// The total funds in the specified currency
     ///
     /// The total funds are the sum of available and held back funds.
     /// See [`Account`] for more info.
-    pub fn total(&self) -> Amount {
-        self.available + self.held
+    pub fn total(&self, currency: Currency) -> Amount {
+        // restoring an account and all account operations make sure the total cannot overflow
+        self.balances(currency).total()
     }
 
-    /// Deposits the specified amount on the account
-    pub fn deposit(&mut self, amount: Amount) -> Result<(), AccountError> {
-        self.check_locked()?;
-        self.available += amount;
+    /// The funds of the account, one row per currency
+    ///
+    /// Accounts without any funds are represented by a single row in the default currency.
+    pub fn rows(&self) -> Vec<AccountRow> {
+        let mut currencies = self.currencies().collect::<Vec<_>>();
+        if currencies.is_empty() {
+            currencies.push(Currency::default());
+        }
 
-        Ok(())
+        currencies
+            .into_iter()
+            .map(|currency| AccountRow {
+                client: self.id,
+                currency,
+                available: self.available(currency),
+                held: self.held(currency),
+                total: self.total(currency),
+                locked: self.status.is_locked(),
+                status: self.status.name(),
+                reason: self.status.reason().map(str::to_owned),
+            })
+            .collect()
     }
 
-    /// Withdrawals the specified amount from the account
-    pub fn withdrawal(&mut self, amount: Amount) -> Result<(), AccountError> {
-        self.check_locked()?;
-        self.available = self.available
-            .checked_sub(amount)
-            .ok_or(AccountError::InsufficientFunds)?;
+    /// Applies the client's side of ledger postings to the funds of the account
+    ///
+    /// The funds of an account only ever change by the postings of its [`LedgerEvent`]s, so
+    /// they always match the general ledger. Postings of other ledger accounts are ignored.
+    /// Fails if the account doesn't permit the operation, if a debit exceeds the funds, or if
+    /// the funds would overflow, in which case the account is left unchanged.
+    ///
+    /// ### Important
+    /// A chargeback leaves an active account locked. After the account is locked, it can only
+    /// be used to complete other open disputes until it is unlocked again. A frozen account
+    /// stays frozen. See [`AccountStatus`] for more info.
+    ///
+    /// [`LedgerEvent`]: crate::LedgerEvent
+    pub fn post(&mut self, operation: AccountOperation, postings: &[Posting]) -> Result<(), AccountError> {
+        self.check_permitted(operation)?;
+        let mut funds = self.funds.clone();
+        for posting in postings {
+            for (account, credit) in [(posting.debit, false), (posting.credit, true)] {
+                let balance = match account {
+                    LedgerAccount::Available(client) if client == self.id => &mut funds.entry(posting.currency).or_default().available,
+                    LedgerAccount::Held(client) if client == self.id => &mut funds.entry(posting.currency).or_default().held,
+                    _ => continue,
+                };
+                *balance = match credit {
+                    true => balance.checked_add(posting.amount).ok_or(AccountError::Overflow)?,
+                    false => balance.checked_sub(posting.amount).ok_or(AccountError::InsufficientFunds)?,
+                };
+            }
+        }
+        for balances in funds.values() {
+            balances.available
+                .checked_add(balances.held)
+                .ok_or(AccountError::Overflow)?;
+        }
+
+        self.funds = funds;
+        if operation == AccountOperation::ChargeBack && self.status == AccountStatus::Active {
+            self.status = AccountStatus::Locked;
+        }
 
         Ok(())
     }
 
-    /// Holds the specified amount back from future withdrawals
-    /// *To release the funds again, you can use [`Account::set_free`]*
-    pub fn hold_back(&mut self, amount: Amount) -> Result<(), AccountError> {
-        self.check_locked()?;
-        self.available = self.available
-            .checked_sub(amount)
-            .ok_or(AccountError::InsufficientFunds)?;
-        self.held += amount;
+    /// Lifts a lock or freeze from the account
+    pub fn unlock(&mut self) -> Result<(), AccountError> {
+        self.check_permitted(AccountOperation::Unlock)?;
+        self.status = AccountStatus::Active;
 
         Ok(())
     }
 
-    /// Releases the specified amount for future withdrawals
-    /// *To  hold funds back, you can use [`Account::withdrawal`]*
-    pub fn set_free(&mut self, amount: Amount) -> Result<(), AccountError> {
-        self.check_locked()?;
-        self.held = self.held
-            .checked_sub(amount)
-            .ok_or(AccountError::InsufficientFunds)?;
-        self.available += amount;
+    /// Temporarily freezes the account
+    ///
+    /// Frozen accounts behave like locked accounts, until they are unlocked again.
+    /// *To unfreeze the account, you can use [`Account::unlock`]*
+    pub fn freeze(&mut self, reason: String) -> Result<(), AccountError> {
+        self.check_permitted(AccountOperation::Freeze)?;
+        self.status = AccountStatus::Frozen { reason };
 
         Ok(())
     }
 
-    /// Reveres a transaction and returns held back funds
+    /// Closes the account and pays out all available funds
+    ///
+    /// Returns the final payout in every currency of the account. Accounts that still
+    /// hold back funds, i.e. because of open disputes, cannot be closed.
     ///
     /// ### Important
-    /// This will leave the account locked. After the account is locked, it can no
-    /// longer be used for any purpose until it is unlocked again.
-    pub fn charge_back(&mut self, amount: Amount) -> Result<(), AccountError> {
-        self.check_locked()?;
-        self.held = self.held
-            .checked_sub(amount)
-            .ok_or(AccountError::InsufficientFunds)?;
-        self.locked = true;
+    /// Closing an account is final. A closed account cannot be used for any purpose.
+    pub fn close(&mut self) -> Result<Vec<(Currency, Amount)>, AccountError> {
+        self.check_permitted(AccountOperation::Close)?;
+        if self.funds.values().any(|funds| !funds.held.is_zero()) {
+            return Err(AccountError::FundsHeld);
+        }
 
-        Ok(())
+        let payouts = self.funds
+            .iter()
+            .map(|(&currency, funds)| (currency, funds.available))
+            .collect::<Vec<_>>();
+        let postings = payouts
+            .iter()
+            .flat_map(|&(currency, payout)| LedgerEventKind::AccountClosed { payout }.postings(self.id, currency))
+            .collect::<Vec<_>>();
+        self.post(AccountOperation::Close, &postings)?;
+        self.status = AccountStatus::Closed;
+
+        Ok(payouts)
+    }
+
+    /// Adds the funds of another copy of the account, i.e. one read from a different row
+    pub(crate) fn merge(&mut self, other: Account) {
+        self.funds.extend(other.funds);
     }
 
-    fn check_locked(&self) -> Result<(), AccountError> {
-        match self.locked {
-            false => Ok(()),
-            true => Err(AccountError::Locked),
+    /// The current status of the account
+    pub fn status(&self) -> &AccountStatus {
+        &self.status
+    }
+
+    /// Whether the operation can currently be performed on the account
+    pub fn permits(&self, operation: AccountOperation) -> bool {
+        self.status.permits(operation)
+    }
+
+    fn check_permitted(&self, operation: AccountOperation) -> Result<(), AccountError> {
+        match self.permits(operation) {
+            true => Ok(()),
+            false => Err(self.status.error()),
         }
     }
 }
@@ -130,224 +420,574 @@ impl serde::Serialize for Account {
         let mut map = serializer.serialize_struct("Account", 5)?;
 
         map.serialize_field("client", &self.id)?;
-        map.serialize_field("available", &self.available)?;
-        map.serialize_field("held", &self.held)?;
-        map.serialize_field("total", &self.total())?;
-        map.serialize_field("locked", &self.locked)?;
+        map.serialize_field("funds", &self.funds)?;
+        map.serialize_field("locked", &self.status.is_locked())?;
+        map.serialize_field("status", self.status.name())?;
+        map.serialize_field("reason", &self.status.reason())?;
 
         map.end()
     }
 }
 
+/// The funds of an account in one currency, together with the status of the account
+///
+/// Rows serialize to flat records with the columns `client`, `currency`, `available`,
+/// `held`, `total`, `locked`, `status` and `reason`. See [`Account::rows`].
+#[derive(Clone, Debug, serde::Serialize, PartialEq, Eq)]
+pub struct AccountRow {
+    pub client: AccountId,
+    pub currency: Currency,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub locked: bool,
+    pub status: &'static str,
+    pub reason: Option<String>,
+}
+
+/// The serialized form of an [`Account`]
+///
+/// Accounts are either serialized with all their `funds`, or as a single row of
+/// [`Account::rows`]. A row without a `currency` column holds funds in the default
+/// currency. The `status` column is optional, in which case the status is derived from
+/// the `locked` column. The `total` column is ignored, since it's derived from the
+/// other funds.
+#[derive(serde::Deserialize)]
+struct SerializedAccount {
+    client: AccountId,
+    funds: Option<BTreeMap<Currency, Balances>>,
+    currency: Option<Currency>,
+    available: Option<Amount>,
+    held: Option<Amount>,
+    #[serde(default)]
+    locked: bool,
+    status: Option<String>,
+    reason: Option<String>,
+}
+
+impl TryFrom<SerializedAccount> for Account {
+    type Error = String;
+
+    fn try_from(account: SerializedAccount) -> Result<Self, Self::Error> {
+        let status = match account.status {
+            Some(name) => AccountStatus::from_name(&name, account.reason)?,
+            None if account.locked => AccountStatus::Locked,
+            None => AccountStatus::Active,
+        };
+        let funds = match account.funds {
+            Some(funds) => funds,
+            None if account.available.is_none() && account.held.is_none() => BTreeMap::new(),
+            None => {
+                let funds = Balances {
+                    available: account.available.unwrap_or_default(),
+                    held: account.held.unwrap_or_default(),
+                };
+                BTreeMap::from([(account.currency.unwrap_or_default(), funds)])
+            }
+        };
+
+        Self::restore(account.client, funds, status).map_err(|error| error.to_string())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::LedgerBalance;
+
+    const USD: Currency = Currency::USD;
+
+    /// Posts the event to the account, like the engine does
+    fn post(account: &mut Account, operation: AccountOperation, currency: Currency, kind: LedgerEventKind) -> Result<(), AccountError> {
+        let postings = kind.postings(account.id, currency);
+        account.post(operation, &postings)
+    }
 
     #[test]
     fn deposit_increases_available() {
         let mut account = Account::new(AccountId(0));
 
-        assert_eq!(account.available, Amount::from_num(0));
-        assert_eq!(account.held, Amount::from_num(0));
+        assert_eq!(account.available(USD), Amount::ZERO);
+        assert_eq!(account.held(USD), Amount::ZERO);
 
-        account.deposit(Amount::from_num(100)).unwrap();
+        post(&mut account, AccountOperation::Deposit, USD, LedgerEventKind::FundsDeposited { amount: Amount::from(100) }).unwrap();
 
-        assert_eq!(account.available, Amount::from_num(100));
-        assert_eq!(account.held, Amount::from_num(0));
+        assert_eq!(account.available(USD), Amount::from(100));
+        assert_eq!(account.held(USD), Amount::ZERO);
     }
 
     #[test]
     fn deposit_on_locked_fails() {
         let mut account = Account::new(AccountId(0));
-        account.locked = true;
+        account.status = AccountStatus::Locked;
 
-        assert_eq!(account.available, Amount::from_num(0));
-        assert_eq!(account.held, Amount::from_num(0));
+        assert_eq!(account.available(USD), Amount::ZERO);
+        assert_eq!(account.held(USD), Amount::ZERO);
 
-        account.deposit(Amount::from_num(100)).unwrap_err();
+        post(&mut account, AccountOperation::Deposit, USD, LedgerEventKind::FundsDeposited { amount: Amount::from(100) }).unwrap_err();
 
-        assert_eq!(account.available, Amount::from_num(0));
-        assert_eq!(account.held, Amount::from_num(0));
+        assert_eq!(account.available(USD), Amount::ZERO);
+        assert_eq!(account.held(USD), Amount::ZERO);
+    }
+
+    #[test]
+    fn deposit_overflow_fails() {
+        let mut account = Account::new(AccountId(0));
+        account.funds.entry(USD).or_default().available = Amount::MAX;
+
+        post(&mut account, AccountOperation::Deposit, USD, LedgerEventKind::FundsDeposited { amount: Amount::from_raw(1) }).unwrap_err();
+
+        assert_eq!(account.available(USD), Amount::MAX);
+        assert_eq!(account.held(USD), Amount::ZERO);
+    }
+
+    #[test]
+    fn deposit_total_overflow_fails() {
+        let mut account = Account::new(AccountId(0));
+        account.funds.entry(USD).or_default().held = Amount::MAX;
+
+        assert!(matches!(
+            post(&mut account, AccountOperation::Deposit, USD, LedgerEventKind::FundsDeposited { amount: Amount::from_raw(1) }),
+            Err(AccountError::Overflow)
+        ));
+
+        assert_eq!(account.available(USD), Amount::ZERO);
+        assert_eq!(account.held(USD), Amount::MAX);
     }
 
     #[test]
     fn withdrawal_decreases_available() {
         let mut account = Account::new(AccountId(0));
-        account.available = Amount::from_num(100);
+        account.funds.entry(USD).or_default().available = Amount::from(100);
 
-        assert_eq!(account.available, Amount::from_num(100));
-        assert_eq!(account.held, Amount::from_num(0));
+        assert_eq!(account.available(USD), Amount::from(100));
+        assert_eq!(account.held(USD), Amount::ZERO);
 
-        account.withdrawal(Amount::from_num(100)).unwrap();
+        post(&mut account, AccountOperation::Withdrawal, USD, LedgerEventKind::FundsWithdrawn { amount: Amount::from(100) }).unwrap();
 
-        assert_eq!(account.available, Amount::from_num(0));
-        assert_eq!(account.held, Amount::from_num(0));
+        assert_eq!(account.available(USD), Amount::ZERO);
+        assert_eq!(account.held(USD), Amount::ZERO);
     }
 
     #[test]
     fn withdrawal_underflow_fails() {
         let mut account = Account::new(AccountId(0));
-        account.available = Amount::from_num(100);
+        account.funds.entry(USD).or_default().available = Amount::from(100);
 
-        assert_eq!(account.available, Amount::from_num(100));
-        assert_eq!(account.held, Amount::from_num(0));
+        assert_eq!(account.available(USD), Amount::from(100));
+        assert_eq!(account.held(USD), Amount::ZERO);
 
-        account.withdrawal(Amount::from_num(200)).unwrap_err();
+        post(&mut account, AccountOperation::Withdrawal, USD, LedgerEventKind::FundsWithdrawn { amount: Amount::from(200) }).unwrap_err();
 
-        assert_eq!(account.available, Amount::from_num(100));
-        assert_eq!(account.held, Amount::from_num(0));
+        assert_eq!(account.available(USD), Amount::from(100));
+        assert_eq!(account.held(USD), Amount::ZERO);
     }
 
     #[test]
     fn withdrawal_on_locked_fails() {
         let mut account = Account::new(AccountId(0));
-        account.available = Amount::from_num(100);
-        account.locked = true;
+        account.funds.entry(USD).or_default().available = Amount::from(100);
+        account.status = AccountStatus::Locked;
 
-        assert_eq!(account.available, Amount::from_num(100));
-        assert_eq!(account.held, Amount::from_num(0));
+        assert_eq!(account.available(USD), Amount::from(100));
+        assert_eq!(account.held(USD), Amount::ZERO);
 
-        account.withdrawal(Amount::from_num(100)).unwrap_err();
+        post(&mut account, AccountOperation::Withdrawal, USD, LedgerEventKind::FundsWithdrawn { amount: Amount::from(100) }).unwrap_err();
 
-        assert_eq!(account.available, Amount::from_num(100));
-        assert_eq!(account.held, Amount::from_num(0));
+        assert_eq!(account.available(USD), Amount::from(100));
+        assert_eq!(account.held(USD), Amount::ZERO);
     }
 
     #[test]
     fn hold_back_increases_held() {
         let mut account = Account::new(AccountId(0));
-        account.available = Amount::from_num(100);
+        account.funds.entry(USD).or_default().available = Amount::from(100);
 
-        assert_eq!(account.available, Amount::from_num(100));
-        assert_eq!(account.held, Amount::from_num(0));
+        assert_eq!(account.available(USD), Amount::from(100));
+        assert_eq!(account.held(USD), Amount::ZERO);
 
-        account.hold_back(Amount::from_num(50)).unwrap();
+        post(&mut account, AccountOperation::HoldBack, USD, LedgerEventKind::FundsHeld { amount: Amount::from(50) }).unwrap();
 
-        assert_eq!(account.available, Amount::from_num(50));
-        assert_eq!(account.held, Amount::from_num(50));
+        assert_eq!(account.available(USD), Amount::from(50));
+        assert_eq!(account.held(USD), Amount::from(50));
     }
 
     #[test]
     fn hold_back_underflow_fails() {
         let mut account = Account::new(AccountId(0));
-        account.available = Amount::from_num(100);
+        account.funds.entry(USD).or_default().available = Amount::from(100);
 
-        assert_eq!(account.available, Amount::from_num(100));
-        assert_eq!(account.held, Amount::from_num(0));
+        assert_eq!(account.available(USD), Amount::from(100));
+        assert_eq!(account.held(USD), Amount::ZERO);
 
-        account.hold_back(Amount::from_num(200)).unwrap_err();
+        post(&mut account, AccountOperation::HoldBack, USD, LedgerEventKind::FundsHeld { amount: Amount::from(200) }).unwrap_err();
 
-        assert_eq!(account.available, Amount::from_num(100));
-        assert_eq!(account.held, Amount::from_num(0));
+        assert_eq!(account.available(USD), Amount::from(100));
+        assert_eq!(account.held(USD), Amount::ZERO);
     }
 
     #[test]
     fn hold_back_on_locked_fails() {
         let mut account = Account::new(AccountId(0));
-        account.available = Amount::from_num(100);
-        account.locked = true;
+        account.funds.entry(USD).or_default().available = Amount::from(100);
+        account.status = AccountStatus::Locked;
 
-        assert_eq!(account.available, Amount::from_num(100));
-        assert_eq!(account.held, Amount::from_num(0));
+        assert_eq!(account.available(USD), Amount::from(100));
+        assert_eq!(account.held(USD), Amount::ZERO);
 
-        account.hold_back(Amount::from_num(50)).unwrap_err();
+        post(&mut account, AccountOperation::HoldBack, USD, LedgerEventKind::FundsHeld { amount: Amount::from(50) }).unwrap_err();
 
-        assert_eq!(account.available, Amount::from_num(100));
-        assert_eq!(account.held, Amount::from_num(0));
+        assert_eq!(account.available(USD), Amount::from(100));
+        assert_eq!(account.held(USD), Amount::ZERO);
     }
 
     #[test]
     fn set_free_decreases_held() {
         let mut account = Account::new(AccountId(0));
-        account.available = Amount::from_num(50);
-        account.held = Amount::from_num(50);
+        account.funds.entry(USD).or_default().available = Amount::from(50);
+        account.funds.entry(USD).or_default().held = Amount::from(50);
 
-        assert_eq!(account.available, Amount::from_num(50));
-        assert_eq!(account.held, Amount::from_num(50));
+        assert_eq!(account.available(USD), Amount::from(50));
+        assert_eq!(account.held(USD), Amount::from(50));
 
-        account.set_free(Amount::from_num(50)).unwrap();
+        post(&mut account, AccountOperation::SetFree, USD, LedgerEventKind::FundsReleased { amount: Amount::from(50) }).unwrap();
 
-        assert_eq!(account.available, Amount::from_num(100));
-        assert_eq!(account.held, Amount::from_num(0));
+        assert_eq!(account.available(USD), Amount::from(100));
+        assert_eq!(account.held(USD), Amount::ZERO);
     }
 
     #[test]
     fn set_free_underflow_fails() {
         let mut account = Account::new(AccountId(0));
-        account.available = Amount::from_num(50);
-        account.held = Amount::from_num(50);
+        account.funds.entry(USD).or_default().available = Amount::from(50);
+        account.funds.entry(USD).or_default().held = Amount::from(50);
+
+        assert_eq!(account.available(USD), Amount::from(50));
+        assert_eq!(account.held(USD), Amount::from(50));
+
+        post(&mut account, AccountOperation::SetFree, USD, LedgerEventKind::FundsReleased { amount: Amount::from(100) }).unwrap_err();
+
+        assert_eq!(account.available(USD), Amount::from(50));
+        assert_eq!(account.held(USD), Amount::from(50));
+    }
 
-        assert_eq!(account.available, Amount::from_num(50));
-        assert_eq!(account.held, Amount::from_num(50));
+    #[test]
+    fn set_free_overflow_fails() {
+        let mut account = Account::new(AccountId(0));
+        account.funds.entry(USD).or_default().available = Amount::MAX;
+        account.funds.entry(USD).or_default().held = Amount::from(50);
 
-        account.set_free(Amount::from_num(100)).unwrap_err();
+        assert!(matches!(
+            post(&mut account, AccountOperation::SetFree, USD, LedgerEventKind::FundsReleased { amount: Amount::from(50) }),
+            Err(AccountError::Overflow)
+        ));
 
-        assert_eq!(account.available, Amount::from_num(50));
-        assert_eq!(account.held, Amount::from_num(50));
+        assert_eq!(account.available(USD), Amount::MAX);
+        assert_eq!(account.held(USD), Amount::from(50));
     }
 
     #[test]
-    fn set_free_on_locked_fails() {
+    fn set_free_on_locked_succeeds() {
         let mut account = Account::new(AccountId(0));
-        account.available = Amount::from_num(50);
-        account.held = Amount::from_num(50);
-        account.locked = true;
+        account.funds.entry(USD).or_default().available = Amount::from(50);
+        account.funds.entry(USD).or_default().held = Amount::from(50);
+        account.status = AccountStatus::Locked;
 
-        assert_eq!(account.available, Amount::from_num(50));
-        assert_eq!(account.held, Amount::from_num(50));
+        assert_eq!(account.available(USD), Amount::from(50));
+        assert_eq!(account.held(USD), Amount::from(50));
 
-        account.set_free(Amount::from_num(50)).unwrap_err();
+        post(&mut account, AccountOperation::SetFree, USD, LedgerEventKind::FundsReleased { amount: Amount::from(50) }).unwrap();
 
-        assert_eq!(account.available, Amount::from_num(50));
-        assert_eq!(account.held, Amount::from_num(50));
+        assert_eq!(account.available(USD), Amount::from(100));
+        assert_eq!(account.held(USD), Amount::ZERO);
+        assert_eq!(account.status, AccountStatus::Locked);
     }
 
     #[test]
     fn charge_back_decreases_available() {
         let mut account = Account::new(AccountId(0));
-        account.available = Amount::from_num(50);
-        account.held = Amount::from_num(50);
+        account.funds.entry(USD).or_default().available = Amount::from(50);
+        account.funds.entry(USD).or_default().held = Amount::from(50);
 
-        assert_eq!(account.available, Amount::from_num(50));
-        assert_eq!(account.held, Amount::from_num(50));
+        assert_eq!(account.available(USD), Amount::from(50));
+        assert_eq!(account.held(USD), Amount::from(50));
 
-        account.charge_back(Amount::from_num(50)).unwrap();
+        post(&mut account, AccountOperation::ChargeBack, USD, LedgerEventKind::FundsChargedBack { amount: Amount::from(50) }).unwrap();
 
-        assert_eq!(account.available, Amount::from_num(50));
-        assert_eq!(account.held, Amount::from_num(0));
-        assert!(account.locked);
+        assert_eq!(account.available(USD), Amount::from(50));
+        assert_eq!(account.held(USD), Amount::ZERO);
+        assert_eq!(account.status, AccountStatus::Locked);
     }
 
     #[test]
     fn charge_back_underflow_fails() {
         let mut account = Account::new(AccountId(0));
-        account.available = Amount::from_num(50);
-        account.held = Amount::from_num(50);
+        account.funds.entry(USD).or_default().available = Amount::from(50);
+        account.funds.entry(USD).or_default().held = Amount::from(50);
+
+        assert_eq!(account.available(USD), Amount::from(50));
+        assert_eq!(account.held(USD), Amount::from(50));
+
+        post(&mut account, AccountOperation::ChargeBack, USD, LedgerEventKind::FundsChargedBack { amount: Amount::from(100) }).unwrap_err();
+
+        assert_eq!(account.available(USD), Amount::from(50));
+        assert_eq!(account.held(USD), Amount::from(50));
+        assert_eq!(account.status, AccountStatus::Active);
+    }
+
+    #[test]
+    fn charge_back_on_locked_succeeds() {
+        let mut account = Account::new(AccountId(0));
+        account.funds.entry(USD).or_default().available = Amount::from(50);
+        account.funds.entry(USD).or_default().held = Amount::from(50);
+        account.status = AccountStatus::Locked;
+
+        assert_eq!(account.available(USD), Amount::from(50));
+        assert_eq!(account.held(USD), Amount::from(50));
+
+        post(&mut account, AccountOperation::ChargeBack, USD, LedgerEventKind::FundsChargedBack { amount: Amount::from(50) }).unwrap();
+
+        assert_eq!(account.available(USD), Amount::from(50));
+        assert_eq!(account.held(USD), Amount::ZERO);
+        assert_eq!(account.status, AccountStatus::Locked);
+    }
+
+    #[test]
+    fn charge_back_on_frozen_keeps_freeze() {
+        let mut account = Account::new(AccountId(0));
+        account.funds.entry(USD).or_default().held = Amount::from(50);
+        account.status = AccountStatus::Frozen { reason: "aml review".to_owned() };
+
+        post(&mut account, AccountOperation::ChargeBack, USD, LedgerEventKind::FundsChargedBack { amount: Amount::from(50) }).unwrap();
+
+        assert_eq!(account.held(USD), Amount::ZERO);
+        assert_eq!(account.status, AccountStatus::Frozen { reason: "aml review".to_owned() });
+    }
+
+    #[test]
+    fn provisionally_credit_on_locked_fails() {
+        let mut account = Account::new(AccountId(0));
+        account.status = AccountStatus::Locked;
 
-        assert_eq!(account.available, Amount::from_num(50));
-        assert_eq!(account.held, Amount::from_num(50));
+        post(&mut account, AccountOperation::ProvisionallyCredit, USD, LedgerEventKind::CreditProvisioned { amount: Amount::from(50) }).unwrap_err();
 
-        account.charge_back(Amount::from_num(100)).unwrap_err();
+        assert_eq!(account.held(USD), Amount::ZERO);
+    }
 
-        assert_eq!(account.available, Amount::from_num(50));
-        assert_eq!(account.held, Amount::from_num(50));
-        assert!(!account.locked);
+    #[test]
+    fn provisionally_credit_increases_held() {
+        let mut account = Account::new(AccountId(0));
+        account.funds.entry(USD).or_default().available = Amount::from(50);
+
+        post(&mut account, AccountOperation::ProvisionallyCredit, USD, LedgerEventKind::CreditProvisioned { amount: Amount::from(20) }).unwrap();
+
+        assert_eq!(account.available(USD), Amount::from(50));
+        assert_eq!(account.held(USD), Amount::from(20));
     }
 
     #[test]
-    fn charge_back_on_locked_fails() {
+    fn provisionally_credit_overflow_fails() {
         let mut account = Account::new(AccountId(0));
-        account.available = Amount::from_num(50);
-        account.held = Amount::from_num(50);
-        account.locked = true;
+        account.funds.entry(USD).or_default().available = Amount::MAX;
 
-        assert_eq!(account.available, Amount::from_num(50));
-        assert_eq!(account.held, Amount::from_num(50));
+        post(&mut account, AccountOperation::ProvisionallyCredit, USD, LedgerEventKind::CreditProvisioned { amount: Amount::from(20) }).unwrap_err();
 
-        account.charge_back(Amount::from_num(50)).unwrap_err();
+        assert_eq!(account.available(USD), Amount::MAX);
+        assert_eq!(account.held(USD), Amount::ZERO);
+    }
+
+    #[test]
+    fn revoke_credit_decreases_held() {
+        let mut account = Account::new(AccountId(0));
+        account.funds.entry(USD).or_default().available = Amount::from(50);
+        account.funds.entry(USD).or_default().held = Amount::from(20);
+
+        post(&mut account, AccountOperation::RevokeCredit, USD, LedgerEventKind::CreditRevoked { amount: Amount::from(20) }).unwrap();
+
+        assert_eq!(account.available(USD), Amount::from(50));
+        assert_eq!(account.held(USD), Amount::ZERO);
+    }
+
+    #[test]
+    fn reverse_withdrawal_increases_available() {
+        let mut account = Account::new(AccountId(0));
+        account.funds.entry(USD).or_default().available = Amount::from(50);
+        account.funds.entry(USD).or_default().held = Amount::from(20);
+
+        post(&mut account, AccountOperation::ReverseWithdrawal, USD, LedgerEventKind::WithdrawalReversed { amount: Amount::from(20) }).unwrap();
+
+        assert_eq!(account.available(USD), Amount::from(70));
+        assert_eq!(account.held(USD), Amount::ZERO);
+        assert_eq!(account.status, AccountStatus::Active);
+    }
+
+    #[test]
+    fn reverse_withdrawal_underflow_fails() {
+        let mut account = Account::new(AccountId(0));
+        account.funds.entry(USD).or_default().available = Amount::from(50);
+        account.funds.entry(USD).or_default().held = Amount::from(20);
+
+        post(&mut account, AccountOperation::ReverseWithdrawal, USD, LedgerEventKind::WithdrawalReversed { amount: Amount::from(30) }).unwrap_err();
+
+        assert_eq!(account.available(USD), Amount::from(50));
+        assert_eq!(account.held(USD), Amount::from(20));
+    }
+
+    #[test]
+    fn unlock_activates_locked() {
+        let mut account = Account::new(AccountId(0));
+        account.status = AccountStatus::Locked;
+
+        account.unlock().unwrap();
+
+        assert_eq!(account.status, AccountStatus::Active);
+    }
+
+    #[test]
+    fn unlock_on_active_fails() {
+        let mut account = Account::new(AccountId(0));
+
+        assert!(matches!(account.unlock(), Err(AccountError::NotLocked)));
+        assert_eq!(account.status, AccountStatus::Active);
+    }
+
+    #[test]
+    fn freeze_blocks_funds() {
+        let mut account = Account::new(AccountId(0));
+        account.funds.entry(USD).or_default().available = Amount::from(50);
+        account.funds.entry(USD).or_default().held = Amount::from(20);
+
+        account.freeze("suspicious activity".to_owned()).unwrap();
+
+        assert!(matches!(post(&mut account, AccountOperation::Deposit, USD, LedgerEventKind::FundsDeposited { amount: Amount::from(10) }), Err(AccountError::Frozen)));
+        assert!(matches!(post(&mut account, AccountOperation::Withdrawal, USD, LedgerEventKind::FundsWithdrawn { amount: Amount::from(10) }), Err(AccountError::Frozen)));
+        post(&mut account, AccountOperation::SetFree, USD, LedgerEventKind::FundsReleased { amount: Amount::from(20) }).unwrap();
+        assert_eq!(account.available(USD), Amount::from(70));
+
+        account.unlock().unwrap();
+        post(&mut account, AccountOperation::Withdrawal, USD, LedgerEventKind::FundsWithdrawn { amount: Amount::from(10) }).unwrap();
+        assert_eq!(account.available(USD), Amount::from(60));
+    }
+
+    #[test]
+    fn freeze_on_locked_fails() {
+        let mut account = Account::new(AccountId(0));
+        account.status = AccountStatus::Locked;
+
+        assert!(matches!(account.freeze(String::new()), Err(AccountError::Locked)));
+        assert_eq!(account.status, AccountStatus::Locked);
+    }
+
+    #[test]
+    fn close_pays_out_available() {
+        let mut account = Account::new(AccountId(0));
+        account.funds.entry(USD).or_default().available = Amount::from(50);
+
+        assert_eq!(account.close().unwrap(), vec![(USD, Amount::from(50))]);
+
+        assert_eq!(account.available(USD), Amount::ZERO);
+        assert_eq!(account.status, AccountStatus::Closed);
+        assert!(matches!(post(&mut account, AccountOperation::Deposit, USD, LedgerEventKind::FundsDeposited { amount: Amount::from(10) }), Err(AccountError::Closed)));
+        assert!(matches!(account.unlock(), Err(AccountError::Closed)));
+    }
+
+    #[test]
+    fn close_with_held_funds_fails() {
+        let mut account = Account::new(AccountId(0));
+        account.funds.entry(USD).or_default().available = Amount::from(50);
+        account.funds.entry(USD).or_default().held = Amount::from(20);
+
+        assert!(matches!(account.close(), Err(AccountError::FundsHeld)));
+
+        assert_eq!(account.available(USD), Amount::from(50));
+        assert_eq!(account.status, AccountStatus::Active);
+    }
+
+    #[test]
+    fn currencies_are_separate() {
+        let mut account = Account::new(AccountId(0));
+        post(&mut account, AccountOperation::Deposit, Currency::EUR, LedgerEventKind::FundsDeposited { amount: Amount::from(50) }).unwrap();
+        post(&mut account, AccountOperation::Deposit, USD, LedgerEventKind::FundsDeposited { amount: Amount::from(20) }).unwrap();
+
+        assert!(matches!(post(&mut account, AccountOperation::Withdrawal, Currency::GBP, LedgerEventKind::FundsWithdrawn { amount: Amount::from(10) }), Err(AccountError::InsufficientFunds)));
+        post(&mut account, AccountOperation::HoldBack, Currency::EUR, LedgerEventKind::FundsHeld { amount: Amount::from(30) }).unwrap();
+
+        assert_eq!(account.balances(Currency::EUR), Balances { available: Amount::from(20), held: Amount::from(30) });
+        assert_eq!(account.balances(USD), Balances { available: Amount::from(20), held: Amount::ZERO });
+        assert_eq!(account.currencies().collect::<Vec<_>>(), vec![Currency::EUR, USD]);
+    }
+
+    #[test]
+    fn convert_moves_funds_between_currencies() {
+        let mut account = Account::new(AccountId(0));
+        post(&mut account, AccountOperation::Deposit, Currency::EUR, LedgerEventKind::FundsDeposited { amount: Amount::from(50) }).unwrap();
+        let convert = |amount, converted| [
+            LedgerEventKind::FundsConvertedOut { amount: Amount::from(amount), to: USD }.postings(AccountId(0), Currency::EUR),
+            LedgerEventKind::FundsConvertedIn { amount: Amount::from(converted), from: Currency::EUR, rate: "1.1".parse().unwrap(), fx_gain: LedgerBalance::ZERO }.postings(AccountId(0), USD),
+        ].concat();
+
+        account.post(AccountOperation::Convert, &convert(20, 22)).unwrap();
+        assert!(matches!(
+            account.post(AccountOperation::Convert, &convert(40, 44)),
+            Err(AccountError::InsufficientFunds)
+        ));
+
+        assert_eq!(account.available(Currency::EUR), Amount::from(30));
+        assert_eq!(account.available(USD), Amount::from(22));
+    }
+
+    #[test]
+    fn postings_of_other_accounts_are_ignored() {
+        let mut account = Account::new(AccountId(0));
+        let postings = LedgerEventKind::FundsDeposited { amount: Amount::from(50) }.postings(AccountId(1), USD);
+
+        account.post(AccountOperation::Deposit, &postings).unwrap();
+
+        assert_eq!(account.currencies().count(), 0);
+    }
+
+    #[test]
+    fn reversed_transfer_keeps_accounts_unlocked() {
+        let mut sender = Account::new(AccountId(0));
+        sender.status = AccountStatus::Frozen { reason: "fraud".to_owned() };
+        let mut receiver = Account::new(AccountId(1));
+        receiver.funds.entry(USD).or_default().held = Amount::from(10);
+
+        post(&mut receiver, AccountOperation::ReverseTransfer, USD, LedgerEventKind::TransferReversed { amount: Amount::from(10) }).unwrap();
+        post(&mut sender, AccountOperation::RefundTransfer, USD, LedgerEventKind::TransferRefunded { amount: Amount::from(10) }).unwrap();
+
+        assert_eq!(receiver.total(USD), Amount::ZERO);
+        assert_eq!(receiver.status, AccountStatus::Active);
+        assert_eq!(sender.available(USD), Amount::from(10));
+    }
+
+    #[test]
+    fn legacy_row_is_in_default_currency() {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader("client,available,held,total,locked
+1,1.5,2,3.5,true".as_bytes());
+        let account = reader.deserialize::<Account>().next().unwrap().unwrap();
+
+        assert_eq!(account.balances(USD), Balances { available: "1.5".parse().unwrap(), held: Amount::from(2) });
+        assert_eq!(account.status, AccountStatus::Locked);
+        assert_eq!(account.rows().len(), 1);
+    }
+
+    #[test]
+    fn unknown_status_fails() {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader("client,available,held,total,locked,status
+1,1.5,2,3.5,true,suspended".as_bytes());
+
+        assert!(reader.deserialize::<Account>().next().unwrap().is_err());
+    }
+
+    #[test]
+    fn overflowing_total_fails() {
+        let json = format!(r#"{{"client":1,"funds":{{"USD":{{"available":"{}","held":"1"}}}},"status":"active"}}"#, Amount::MAX);
 
-        assert_eq!(account.available, Amount::from_num(50));
-        assert_eq!(account.held, Amount::from_num(50));
-        assert!(account.locked);
+        assert!(serde_json::from_str::<Account>(&json).is_err());
     }
 }
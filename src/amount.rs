@@ -0,0 +1,243 @@
+use std::fmt;
+use std::ops::{Add, AddAssign, Sub, SubAssign};
+use std::str::FromStr;
+
+/// Possible errors to occur while parsing an amount
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum AmountError {
+    #[error("The amount is not a valid non-negative decimal number")]
+    Invalid,
+    #[error("The amount has more than four decimal places")]
+    TooManyDecimals,
+    #[error("The amount exceeds the maximum representable amount")]
+    Overflow,
+}
+
+/// An exact decimal amount of money with four decimal places
+///
+/// Internally, the amount is stored as an integer number of ten-thousandths,
+/// so decimal inputs like `0.1` are represented without any rounding errors.
+///
+/// The maximum amount that can be represented is [`Amount::MAX`].
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(u64);
+
+impl Amount {
+    /// The number of decimal places of an amount
+    pub const DECIMALS: u32 = 4;
+    /// The number of raw units in one whole unit of money
+    pub const SCALE: u64 = 10u64.pow(Self::DECIMALS);
+    /// The amount zero
+    pub const ZERO: Self = Self(0);
+    /// The largest representable amount
+    pub const MAX: Self = Self(u64::MAX);
+
+    /// Creates an amount from a raw number of ten-thousandths
+    pub const fn from_raw(raw: u64) -> Self {
+        Self(raw)
+    }
+
+    /// The raw number of ten-thousandths this amount consists of
+    pub const fn raw(self) -> u64 {
+        self.0
+    }
+
+    /// Whether the amount is zero
+    pub const fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Adds two amounts, returning `None` on overflow
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        self.0.checked_add(rhs.0).map(Self)
+    }
+
+    /// Subtracts two amounts, returning `None` if the result would be negative
+    pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+        self.0.checked_sub(rhs.0).map(Self)
+    }
+}
+
+impl From<u32> for Amount {
+    /// Creates an amount of whole units of money
+    ///
+    /// Every `u32` fits into an amount, so this conversion cannot fail.
+    fn from(whole: u32) -> Self {
+        Self(u64::from(whole) * Self::SCALE)
+    }
+}
+
+impl FromStr for Amount {
+    type Err = AmountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (whole, fraction) = match s.split_once('.') {
+            Some((whole, fraction)) => (whole, Some(fraction)),
+            None => (s, None),
+        };
+
+        let is_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+        if !is_digits(whole) || !fraction.is_none_or(is_digits) {
+            return Err(AmountError::Invalid);
+        }
+
+        let fraction = fraction.unwrap_or("");
+        if fraction.len() > Self::DECIMALS as usize {
+            return Err(AmountError::TooManyDecimals);
+        }
+
+        let whole = whole
+            .parse::<u64>()
+            .map_err(|_| AmountError::Overflow)?;
+        // pad the fraction to exactly four digits, i.e. `.5` becomes `5000`
+        let fraction = format!("{:0<width$}", fraction, width = Self::DECIMALS as usize)
+            .parse::<u64>()
+            .map_err(|_| AmountError::Invalid)?;
+
+        whole
+            .checked_mul(Self::SCALE)
+            .and_then(|raw| raw.checked_add(fraction))
+            .map(Self)
+            .ok_or(AmountError::Overflow)
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let whole = self.0 / Self::SCALE;
+        let fraction = self.0 % Self::SCALE;
+
+        match fraction {
+            0 => write!(f, "{}", whole),
+            _ => {
+                let fraction = format!("{:0width$}", fraction, width = Self::DECIMALS as usize);
+                write!(f, "{}.{}", whole, fraction.trim_end_matches('0'))
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl Add for Amount {
+    type Output = Self;
+
+    /// ### Panics
+    /// Panics on overflow. Use [`Amount::checked_add`] to handle overflows.
+    fn add(self, rhs: Self) -> Self::Output {
+        self.checked_add(rhs).expect("attempt to add with overflow")
+    }
+}
+
+impl AddAssign for Amount {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub for Amount {
+    type Output = Self;
+
+    /// ### Panics
+    /// Panics if the result would be negative. Use [`Amount::checked_sub`] to handle underflows.
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.checked_sub(rhs).expect("attempt to subtract with overflow")
+    }
+}
+
+impl SubAssign for Amount {
+    fn sub_assign(&mut self, rhs: Self) {
+        *self = *self - rhs;
+    }
+}
+
+impl serde::Serialize for Amount {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Amount {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: serde::Deserializer<'de>
+    {
+        struct AmountVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for AmountVisitor {
+            type Value = Amount;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a non-negative decimal number with at most four decimal places")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+                where E: serde::de::Error
+            {
+                v.parse().map_err(E::custom)
+            }
+        }
+
+        // amounts are always deserialized from strings, since going through
+        // floating point numbers would defeat the purpose of an exact type
+        deserializer.deserialize_str(AmountVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_is_exact() {
+        assert_eq!("0.1".parse::<Amount>().unwrap(), Amount::from_raw(1_000));
+        assert_eq!("0.0001".parse::<Amount>().unwrap(), Amount::from_raw(1));
+        assert_eq!("1.5".parse::<Amount>().unwrap(), Amount::from_raw(15_000));
+        assert_eq!("42".parse::<Amount>().unwrap(), Amount::from(42));
+        assert_eq!("007.10".parse::<Amount>().unwrap(), Amount::from_raw(71_000));
+        assert_eq!(
+            "0.1".parse::<Amount>().unwrap() + "0.2".parse::<Amount>().unwrap(),
+            "0.3".parse::<Amount>().unwrap(),
+        );
+    }
+
+    #[test]
+    fn parse_too_many_decimals_fails() {
+        assert_eq!("0.00001".parse::<Amount>(), Err(AmountError::TooManyDecimals));
+        assert_eq!("1.50000".parse::<Amount>(), Err(AmountError::TooManyDecimals));
+    }
+
+    #[test]
+    fn parse_invalid_fails() {
+        for input in ["", ".", "1.", ".5", "-1", "+1", "1e3", "1.2.3", "one", " 1"] {
+            assert_eq!(input.parse::<Amount>(), Err(AmountError::Invalid), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn parse_overflow_fails() {
+        assert_eq!(Amount::MAX.to_string().parse::<Amount>(), Ok(Amount::MAX));
+        assert_eq!("1844674407370955.1616".parse::<Amount>(), Err(AmountError::Overflow));
+        assert_eq!("99999999999999999999".parse::<Amount>(), Err(AmountError::Overflow));
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for input in ["0", "1", "1.5", "0.0001", "123.4567", "1844674407370955.1615"] {
+            assert_eq!(input.parse::<Amount>().unwrap().to_string(), input);
+        }
+        assert_eq!(Amount::from_raw(15_000).to_string(), "1.5");
+    }
+
+    #[test]
+    fn checked_arithmetic() {
+        assert_eq!(Amount::MAX.checked_add(Amount::from_raw(1)), None);
+        assert_eq!(Amount::ZERO.checked_sub(Amount::from_raw(1)), None);
+        assert_eq!(Amount::from(2).checked_sub(Amount::from(1)), Some(Amount::from(1)));
+    }
+}
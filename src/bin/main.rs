@@ -1,34 +1,371 @@
-use clap::Parser;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use transaction_engine::TransactionEngine;
+use clap::{ArgEnum, Parser};
+
+use transaction_engine::{AccountStore, DeadlineAction, DiskAccountStore, DiskTransactionStore, DisputeDeadline, EngineConfig, FxRates, FxRounding, Rejection, RoundingMode, Snapshot, SqliteStore, Timestamp, Transaction, TransactionEngine, TransactionError, TransactionStore};
 
 /// A cli interface to the transaction engine
 #[derive(Debug, Parser)]
 #[clap(version)]
 struct Args {
     /// The path to the transaction CSV file
-    filename: std::path::PathBuf,
+    filename: PathBuf,
+    /// Write all rejected transactions, and the reason they were rejected, to this file
+    #[clap(long, value_name = "PATH")]
+    rejections: Option<PathBuf>,
+    /// The format of the rejections file
+    #[clap(long, arg_enum, default_value = "csv", requires = "rejections")]
+    rejections_format: ReportFormat,
+    /// Write the ledger events of all accepted transactions to this file, as JSON lines
+    #[clap(long, value_name = "PATH")]
+    events: Option<PathBuf>,
+    /// Write all accepted transactions and the final dispute state of saved ones to this file
+    #[clap(long, value_name = "PATH")]
+    transaction_log: Option<PathBuf>,
+    /// The format of the transaction log
+    #[clap(long, arg_enum, default_value = "csv", requires = "transaction-log")]
+    transaction_log_format: ReportFormat,
+    /// Write the trial balance of the general ledger to this file, as CSV
+    #[clap(long, value_name = "PATH")]
+    trial_balance: Option<PathBuf>,
+    /// Verify the invariants of the engine state after processing, and abort if any are violated
+    #[clap(long)]
+    check_invariants: bool,
+    /// How to deal with malformed rows and rejected transactions
+    #[clap(long, arg_enum, default_value = "lenient")]
+    mode: Mode,
+    /// Resume processing from the engine state saved in this snapshot
+    #[clap(long, value_name = "PATH")]
+    load_snapshot: Option<PathBuf>,
+    /// Save the engine state to this snapshot after all transactions were processed
+    #[clap(long, value_name = "PATH")]
+    save_snapshot: Option<PathBuf>,
+    /// Keep accounts and saved transactions in files in this directory instead of memory
+    #[clap(long, value_name = "DIR")]
+    store_dir: Option<PathBuf>,
+    /// Keep accounts and saved transactions in this SQLite database, and resume from its state
+    #[clap(long, value_name = "PATH", conflicts_with = "store-dir")]
+    db: Option<PathBuf>,
+    /// Convert currencies using the exchange rates in this CSV file, with the columns from, to and rate
+    #[clap(long, value_name = "PATH")]
+    rates: Option<PathBuf>,
+    /// How converted amounts are rounded: half-even, half-up, down or up
+    #[clap(long, value_name = "MODE", default_value = "half-even")]
+    fx_rounding: RoundingMode,
+    /// The number of decimal places converted amounts are rounded to
+    #[clap(long, value_name = "DECIMALS", default_value = "2", possible_values = ["0", "1", "2", "3", "4"])]
+    fx_decimals: u32,
+    /// Reject disputes of transactions whose timestamp is older than this, and stop keeping them afterwards
+    #[clap(long, value_name = "SECONDS")]
+    dispute_window: Option<u64>,
+    /// Close disputes that are open for longer than this after all transactions were processed
+    #[clap(long, value_name = "SECONDS")]
+    dispute_deadline: Option<u64>,
+    /// Close disputes opened before the input had any timestamp once this many transactions followed them
+    #[clap(long, value_name = "COUNT", requires = "dispute-deadline")]
+    deadline_transactions: Option<u64>,
+    /// How expired disputes are closed: resolve or chargeback
+    #[clap(long, value_name = "ACTION", default_value = "resolve", requires = "dispute-deadline")]
+    deadline_action: DeadlineAction,
+    /// The time expired disputes are closed at, in seconds since the Unix epoch, defaults to the latest timestamp
+    #[clap(long, value_name = "TIMESTAMP", requires = "dispute-deadline")]
+    now: Option<u64>,
+}
+
+/// The input processing modes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ArgEnum)]
+enum Mode {
+    /// Abort on the first malformed row or rejected transaction
+    Strict,
+    /// Skip malformed rows and rejected transactions, and report them at the end
+    Lenient,
+}
+
+/// The supported file formats of reports
+#[derive(Clone, Copy, Debug, ArgEnum)]
+enum ReportFormat {
+    /// Comma separated values with a header row
+    Csv,
+    /// One JSON object per line
+    Jsonl,
+}
+
+/// A writer for reports in one of the [`ReportFormat`]s
+enum ReportWriter {
+    Csv(Box<csv::Writer<File>>),
+    Jsonl(BufWriter<File>),
+}
+
+impl ReportWriter {
+    fn create(path: &Path, format: ReportFormat) -> anyhow::Result<Self> {
+        let file = File::create(path)?;
+        Ok(match format {
+            ReportFormat::Csv => Self::Csv(Box::new(csv::Writer::from_writer(file))),
+            ReportFormat::Jsonl => Self::Jsonl(BufWriter::new(file)),
+        })
+    }
+
+    fn write(&mut self, record: &impl serde::Serialize) -> anyhow::Result<()> {
+        match self {
+            Self::Csv(writer) => writer.serialize(record)?,
+            Self::Jsonl(writer) => {
+                serde_json::to_writer(&mut *writer, record)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        match self {
+            Self::Csv(writer) => writer.flush()?,
+            Self::Jsonl(writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+/// A summary of all skipped rows, reported at the end of a lenient run
+#[derive(Debug, Default)]
+struct Summary {
+    /// The line numbers and errors of all malformed rows
+    malformed: Vec<(u64, csv::Error)>,
+    /// The number of rejected transactions per reason code
+    rejected: BTreeMap<&'static str, u64>,
+}
+
+impl Summary {
+    fn report(&self) {
+        if !self.malformed.is_empty() {
+            eprintln!("warning: skipped {} malformed rows", self.malformed.len());
+            for (line, error) in &self.malformed {
+                eprintln!("  line {}: {}", line, error);
+            }
+        }
+
+        if !self.rejected.is_empty() {
+            eprintln!("warning: rejected {} transactions", self.rejected.values().sum::<u64>());
+            for (reason, count) in &self.rejected {
+                eprintln!("  {}: {}", reason, count);
+            }
+        }
+    }
 }
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    let config = EngineConfig {
+        rates: args.rates.as_ref().map(FxRates::load).transpose()?.unwrap_or_default(),
+        fx_rounding: FxRounding { mode: args.fx_rounding, decimals: args.fx_decimals },
+        dispute_window: args.dispute_window.map(Duration::from_secs),
+        dispute_deadline: args.dispute_deadline.map(|seconds| DisputeDeadline {
+            after: Duration::from_secs(seconds),
+            after_transactions: args.deadline_transactions,
+            action: args.deadline_action,
+        }),
+        ..EngineConfig::default()
+    };
+
+    if let Some(path) = &args.db {
+        // the whole file is applied in one database transaction, so an aborted run
+        // leaves the database untouched
+        let store = SqliteStore::open(path)?;
+        store.begin()?;
+        let engine = run(&args, TransactionEngine::with_stores(config, store.clone(), store.clone())?)?;
+        store.commit()?;
+        write_accounts(engine.accounts())
+    } else if let Some(dir) = &args.store_dir {
+        let accounts = DiskAccountStore::create(dir.join("accounts.store"))?;
+        let transactions = DiskTransactionStore::create(dir.join("transactions.store"))?;
+        let engine = run(&args, TransactionEngine::with_stores(config, accounts, transactions)?)?;
+        write_accounts(engine.accounts())
+    } else {
+        let engine = run(&args, TransactionEngine::with_config(config))?;
+        write_accounts(engine.accounts())
+    }
+}
+
+/// Processes the input file, and returns the resulting engine
+fn run<A: AccountStore, T: TransactionStore>(args: &Args, mut engine: TransactionEngine<A, T>) -> anyhow::Result<TransactionEngine<A, T>> {
     let mut reader = csv::ReaderBuilder::new()
         .has_headers(true)
         .trim(csv::Trim::All)
-        .from_path(args.filename)?;
-    let mut engine = TransactionEngine::new();
+        .from_path(&args.filename)?;
+    let headers = reader.headers()?.clone();
+    let mut rejections = args.rejections
+        .as_ref()
+        .map(|path| ReportWriter::create(path, args.rejections_format))
+        .transpose()?;
+    let mut events = args.events
+        .as_ref()
+        .map(|path| ReportWriter::create(path, ReportFormat::Jsonl))
+        .transpose()?;
+    let mut accepted_transactions = args.transaction_log
+        .as_deref()
+        .map(|path| ReportWriter::create(&pending_path(path), ReportFormat::Jsonl))
+        .transpose()?;
+    if let Some(path) = &args.load_snapshot {
+        engine.restore(Snapshot::load(path)?)?;
+    }
+    let mut summary = Summary::default();
+
+    for record in reader.records() {
+        let transaction = record.and_then(|record| {
+            let line = record.position().map_or(0, csv::Position::line);
+            let transaction: Transaction = record.deserialize(Some(&headers))?;
+            Ok((line, transaction))
+        });
+        let (line, transaction) = match transaction {
+            Ok(transaction) => transaction,
+            // io errors are never caused by a single row, so they always abort
+            Err(error) if error.is_io_error() => return Err(error.into()),
+            Err(error) => {
+                let line = error.position().map_or(0, csv::Position::line);
+                match args.mode {
+                    Mode::Strict => anyhow::bail!("line {}: malformed row: {}", line, error),
+                    Mode::Lenient => summary.malformed.push((line, error)),
+                }
+                continue;
+            }
+        };
 
-    for transaction in reader.deserialize() {
-        // failed transactions are just ignored
-        let _ = engine.handle_transaction(transaction?);
+        let logged = accepted_transactions.is_some().then(|| transaction.clone());
+        let rejection = match engine.process(transaction) {
+            Ok(accepted) => {
+                if let Some(events) = &mut events {
+                    for event in &accepted {
+                        events.write(event)?;
+                    }
+                }
+                if let (Some(writer), Some(transaction)) = (&mut accepted_transactions, logged) {
+                    writer.write(&transaction)?;
+                }
+                continue;
+            }
+            Err(rejection) => rejection,
+        };
+        reject(args, rejection.at_line(line), &mut rejections, &mut summary)?;
     }
 
+    if args.dispute_deadline.is_some() {
+        let closed = match args.now {
+            Some(now) => engine.advance_time(Timestamp::new(now))?,
+            None => engine.close_expired_disputes()?,
+        };
+        for closed in closed {
+            match closed.outcome {
+                Ok(accepted) => {
+                    if let Some(events) = &mut events {
+                        for event in &accepted {
+                            events.write(event)?;
+                        }
+                    }
+                    if let Some(writer) = &mut accepted_transactions {
+                        writer.write(&closed.transaction)?;
+                    }
+                }
+                Err(rejection) => reject(args, rejection, &mut rejections, &mut summary)?,
+            }
+        }
+    }
+
+    if let Some(rejections) = &mut rejections {
+        rejections.flush()?;
+    }
+    if let Some(events) = &mut events {
+        events.flush()?;
+    }
+    if let Some(writer) = &mut accepted_transactions {
+        writer.flush()?;
+    }
+    summary.report();
+
+    if args.check_invariants {
+        let violations = engine.verify_invariants()?;
+        for violation in &violations {
+            eprintln!("invariant violated: {}", violation);
+        }
+        if !violations.is_empty() {
+            anyhow::bail!("{} invariants were violated", violations.len());
+        }
+    }
+    if let Some(path) = &args.trial_balance {
+        let mut writer = ReportWriter::create(path, ReportFormat::Csv)?;
+        for line in engine.ledger().trial_balance().lines {
+            writer.write(&line)?;
+        }
+        writer.flush()?;
+    }
+    if let Some(path) = &args.transaction_log {
+        write_transaction_log(path, args.transaction_log_format, &engine)?;
+    }
+    if let Some(path) = &args.save_snapshot {
+        engine.snapshot()?.save(path)?;
+    }
+
+    Ok(engine)
+}
+
+/// The temporary file that keeps the accepted transactions until the transaction log is written
+fn pending_path(path: &Path) -> PathBuf {
+    let mut pending = path.as_os_str().to_owned();
+    pending.push(".pending");
+    pending.into()
+}
+
+/// Writes the accepted transactions in processing order, with the final dispute state of saved ones
+///
+/// The final state is only known once all transactions are processed, so the accepted transactions
+/// are kept in a temporary file until then, instead of in memory.
+fn write_transaction_log<A: AccountStore, T: TransactionStore>(path: &Path, format: ReportFormat, engine: &TransactionEngine<A, T>) -> anyhow::Result<()> {
+    let pending = pending_path(path);
+    let mut writer = ReportWriter::create(path, format)?;
+    let reader = BufReader::new(File::open(&pending)?);
+    for transaction in serde_json::Deserializer::from_reader(reader).into_iter::<Transaction>() {
+        writer.write(&engine.transaction_row(&transaction?)?)?;
+    }
+    writer.flush()?;
+    std::fs::remove_file(pending)?;
+    Ok(())
+}
+
+/// Reports a rejected transaction, and aborts in strict mode
+fn reject(args: &Args, rejection: Rejection, rejections: &mut Option<ReportWriter>, summary: &mut Summary) -> anyhow::Result<()> {
+    // storage errors are never caused by the transaction itself, so they always abort
+    if let TransactionError::Storage(_) = rejection.error() {
+        return Err(rejection.into_error().into());
+    }
+    if let Some(rejections) = rejections {
+        rejections.write(&rejection)?;
+    }
+
+    match args.mode {
+        Mode::Strict => {
+            if let Some(rejections) = rejections {
+                rejections.flush()?;
+            }
+            anyhow::bail!("{}", rejection);
+        }
+        Mode::Lenient => *summary.rejected.entry(rejection.error().code()).or_default() += 1,
+    }
+
+    Ok(())
+}
+
+/// Writes all accounts as CSV to stdout, one row per client and currency
+fn write_accounts(accounts: &impl AccountStore) -> anyhow::Result<()> {
     let mut writer = csv::WriterBuilder::new()
         .has_headers(true)
         .from_writer(std::io::stdout());
 
-    for account in engine.accounts().values() {
-        writer.serialize(account)?;
+    for account in accounts.accounts()? {
+        for row in account.rows() {
+            writer.serialize(row)?;
+        }
     }
 
     Ok(())
@@ -0,0 +1,103 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// Possible errors to occur while parsing a currency
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum CurrencyError {
+    #[error("The currency is not a three letter code")]
+    Invalid,
+}
+
+/// A currency, identified by its three letter ISO 4217 code, i.e. `EUR`
+///
+/// Codes are parsed case-insensitively, and always written in uppercase. The default
+/// currency [`Currency::USD`] is used for transactions that don't specify one.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    /// Euro
+    pub const EUR: Self = Self(*b"EUR");
+    /// Pound sterling
+    pub const GBP: Self = Self(*b"GBP");
+    /// United States dollar
+    pub const USD: Self = Self(*b"USD");
+
+    /// The smallest and largest possible currencies, used to look up ranges of ledger accounts
+    pub(crate) const MIN: Self = Self([0; 3]);
+    pub(crate) const MAX: Self = Self([u8::MAX; 3]);
+
+    /// The three letter code of the currency
+    pub fn code(&self) -> &str {
+        // currencies are only ever created from ASCII letters
+        std::str::from_utf8(&self.0).unwrap_or_default()
+    }
+}
+
+impl Default for Currency {
+    fn default() -> Self {
+        Self::USD
+    }
+}
+
+impl FromStr for Currency {
+    type Err = CurrencyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let code = <[u8; 3]>::try_from(s.as_bytes()).map_err(|_| CurrencyError::Invalid)?;
+        if !code.iter().all(u8::is_ascii_alphabetic) {
+            return Err(CurrencyError::Invalid);
+        }
+
+        Ok(Self(code.map(|b| b.to_ascii_uppercase())))
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+impl fmt::Debug for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl serde::Serialize for Currency {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer
+    {
+        serializer.serialize_str(self.code())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Currency {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: serde::Deserializer<'de>
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_currency() {
+        assert_eq!("EUR".parse(), Ok(Currency::EUR));
+        assert_eq!("gbp".parse(), Ok(Currency::GBP));
+        assert_eq!(Currency::USD.to_string(), "USD");
+    }
+
+    #[test]
+    fn parse_invalid_currency() {
+        for currency in ["", "EU", "EURO", "E1R", "€UR"] {
+            assert_eq!(currency.parse::<Currency>(), Err(CurrencyError::Invalid), "{}", currency);
+        }
+    }
+}
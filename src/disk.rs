@@ -0,0 +1,604 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{Account, AccountId, AccountStore, Currency, Evicted, EvictedFunds, IdRange, LedgerAccount, LedgerBalance, LedgerLine, StoreError, StoredTransaction, Timestamp, TransactionId, TransactionStore};
+
+/// A file of fixed-size slots, where each slot holds one JSON encoded value
+///
+/// Every slot starts with the length of the value as a little endian `u16`, and a length
+/// of zero marks an empty slot. Since values are addressed by their slot, there's no
+/// index of the values that has to be kept in memory. Slots that were never written don't
+/// take up any disk space on file systems supporting sparse files.
+///
+/// The file starts with a header, that contains a bitmap of the blocks of slots that were
+/// ever written. So iterating the values only reads the used blocks, no matter how sparse
+/// the slots are.
+#[derive(Debug)]
+struct SlotFile {
+    file: File,
+    /// One bit per block of [`SlotFile::BLOCK_SLOTS`] slots, in the order of the blocks
+    blocks: Vec<u8>,
+    /// The size of the header in bytes, where the first slot starts
+    header: u64,
+}
+
+impl SlotFile {
+    /// The size of a single slot in bytes
+    const SLOT_SIZE: u64 = 512;
+    /// The size of the length prefix of a slot in bytes
+    const LENGTH_SIZE: usize = 2;
+    /// The number of slots per block of the bitmap
+    const BLOCK_SLOTS: u64 = 64;
+    /// The first bytes of the header, that identify the format of the file
+    const MAGIC: &'static [u8; 8] = b"SLOTS\0\0\x01";
+
+    /// Opens the file, which provides the specified number of slots
+    fn open(path: &Path, truncate: bool, slots: u64) -> Result<Self, StoreError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(truncate)
+            .open(path)?;
+        let mut blocks = vec![0; slots.div_ceil(Self::BLOCK_SLOTS).div_ceil(8) as usize];
+        // slots are aligned, so the header takes up whole slots
+        let header = (Self::MAGIC.len() + blocks.len()) as u64;
+        let header = header.div_ceil(Self::SLOT_SIZE) * Self::SLOT_SIZE;
+
+        if file.metadata()?.len() == 0 {
+            file.set_len(header)?;
+            file.write_all(Self::MAGIC)?;
+        } else {
+            let mut magic = [0; 8];
+            file.read_exact(&mut magic)?;
+            if &magic != Self::MAGIC {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "The file is not a store").into());
+            }
+            file.read_exact(&mut blocks)?;
+        }
+
+        Ok(Self { file, blocks, header })
+    }
+
+    fn read<V: DeserializeOwned>(&self, slot: u64) -> Result<Option<V>, StoreError> {
+        let mut file = &self.file;
+        let mut bytes = Vec::with_capacity(Self::SLOT_SIZE as usize);
+        file.seek(SeekFrom::Start(self.offset(slot)))?;
+        file.take(Self::SLOT_SIZE).read_to_end(&mut bytes)?;
+
+        Self::decode(&bytes)
+    }
+
+    /// Decodes the value of a slot
+    fn decode<V: DeserializeOwned>(bytes: &[u8]) -> Result<Option<V>, StoreError> {
+        // slots behind the end of the file were never written
+        if bytes.len() < Self::LENGTH_SIZE {
+            return Ok(None);
+        }
+        let len = u16::from_le_bytes([bytes[0], bytes[1]]) as usize;
+        match len {
+            0 => Ok(None),
+            len => {
+                let value = bytes
+                    .get(Self::LENGTH_SIZE..Self::LENGTH_SIZE + len)
+                    .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+                Ok(Some(serde_json::from_slice(value)?))
+            }
+        }
+    }
+
+    fn write(&mut self, slot: u64, value: &impl Serialize) -> Result<(), StoreError> {
+        let json = serde_json::to_vec(value)?;
+        let max = Self::SLOT_SIZE as usize - Self::LENGTH_SIZE;
+        if json.len() > max {
+            return Err(StoreError::RecordTooLarge { size: json.len(), max });
+        }
+
+        // the block is marked first, so a written slot is never skipped
+        let block = slot / Self::BLOCK_SLOTS;
+        let (index, bit) = ((block / 8) as usize, 1 << (block % 8));
+        if self.blocks[index] & bit == 0 {
+            self.blocks[index] |= bit;
+            self.file.seek(SeekFrom::Start((Self::MAGIC.len() + index) as u64))?;
+            self.file.write_all(&[self.blocks[index]])?;
+        }
+
+        let mut bytes = Vec::with_capacity(Self::LENGTH_SIZE + json.len());
+        bytes.extend_from_slice(&(json.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&json);
+        self.file.seek(SeekFrom::Start(self.offset(slot)))?;
+        self.file.write_all(&bytes)?;
+
+        Ok(())
+    }
+
+    /// Marks a slot as empty
+    ///
+    /// The block of the slot stays marked as used.
+    fn clear(&mut self, slot: u64) -> Result<(), StoreError> {
+        self.file.seek(SeekFrom::Start(self.offset(slot)))?;
+        self.file.write_all(&[0; Self::LENGTH_SIZE])?;
+
+        Ok(())
+    }
+
+    /// All values in a range of slots, in the order of their slots
+    ///
+    /// The range has to start and end at the bounds of a block.
+    fn values<V: DeserializeOwned + 'static>(&self, slots: Range<u64>) -> Box<dyn Iterator<Item = Result<V, StoreError>> + '_> {
+        let blocks = slots.start / Self::BLOCK_SLOTS..slots.end.div_ceil(Self::BLOCK_SLOTS);
+        let blocks = self.blocks
+            .iter()
+            .enumerate()
+            .filter(|&(_, &bits)| bits != 0)
+            .flat_map(|(index, &bits)| (0..8)
+                .filter(move |bit| bits & (1 << bit) != 0)
+                .map(move |bit| index as u64 * 8 + bit))
+            .filter(move |block| blocks.contains(block));
+
+        Box::new(blocks.flat_map(move |block| match self.read_block(block) {
+            Ok(values) => values,
+            Err(error) => vec![Err(error)],
+        }))
+    }
+
+    /// The values of all slots in a block, with a single read
+    fn read_block<V: DeserializeOwned>(&self, block: u64) -> Result<Vec<Result<V, StoreError>>, StoreError> {
+        let mut file = &self.file;
+        let size = Self::BLOCK_SLOTS * Self::SLOT_SIZE;
+        let mut bytes = Vec::with_capacity(size as usize);
+        file.seek(SeekFrom::Start(self.offset(block * Self::BLOCK_SLOTS)))?;
+        file.take(size).read_to_end(&mut bytes)?;
+
+        Ok(bytes
+            .chunks(Self::SLOT_SIZE as usize)
+            .filter_map(|slot| Self::decode(slot).transpose())
+            .collect())
+    }
+
+    /// The position of a slot in the file
+    fn offset(&self, slot: u64) -> u64 {
+        self.header + slot * Self::SLOT_SIZE
+    }
+}
+
+/// The number of slots for the accounts of an account store, one per possible account id
+const ACCOUNT_SLOTS: u64 = u16::MAX as u64 + 1;
+/// The number of slots for the ledger of an account store, one per possible ledger account
+const LEDGER_SLOTS: u64 = 7 + 2 * ACCOUNT_SLOTS;
+/// The number of slots for the records of a transaction store, one per possible transaction id
+const TRANSACTION_SLOTS: u64 = u32::MAX as u64 + 1;
+/// The first slot of a transaction store that holds the evicted funds of a client, one per possible account id
+const EVICTED_FUNDS_SLOTS: u64 = TRANSACTION_SLOTS;
+/// The slot of a transaction store that holds the clock, behind the evicted funds
+const CLOCK_SLOT: u64 = EVICTED_FUNDS_SLOTS + ACCOUNT_SLOTS;
+
+/// The balances of a ledger account in every currency, as kept in its slot
+type LedgerSlot = (LedgerAccount, BTreeMap<Currency, LedgerBalance>);
+
+/// An [`AccountStore`] that keeps all accounts in a file instead of memory
+///
+/// The slots of the accounts are followed by one slot per ledger account, that holds its
+/// balances in every currency.
+#[derive(Debug)]
+pub struct DiskAccountStore {
+    slots: SlotFile,
+}
+
+impl DiskAccountStore {
+    /// Creates a new, empty store, and replaces the file if it already exists
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        Ok(Self { slots: SlotFile::open(path.as_ref(), true, ACCOUNT_SLOTS + LEDGER_SLOTS)? })
+    }
+
+    /// Opens an existing store, or creates a new one if the file doesn't exist yet
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        Ok(Self { slots: SlotFile::open(path.as_ref(), false, ACCOUNT_SLOTS + LEDGER_SLOTS)? })
+    }
+
+    /// The slot of a ledger account
+    fn ledger_slot(account: LedgerAccount) -> u64 {
+        ACCOUNT_SLOTS + match account {
+            LedgerAccount::Cash => 0,
+            LedgerAccount::ProvisionalCredits => 1,
+            LedgerAccount::ChargebackLosses => 2,
+            LedgerAccount::FxPositions => 3,
+            LedgerAccount::FxGainsLosses => 4,
+            LedgerAccount::Transfers => 5,
+            LedgerAccount::OpeningBalances => 6,
+            LedgerAccount::Available(client) => 7 + 2 * client.value() as u64,
+            LedgerAccount::Held(client) => 8 + 2 * client.value() as u64,
+        }
+    }
+
+    /// Changes the balances of a ledger account, and clears its slot once it has none left
+    fn update_ledger(&mut self, account: LedgerAccount, update: impl FnOnce(&mut BTreeMap<Currency, LedgerBalance>)) -> Result<(), StoreError> {
+        let slot = Self::ledger_slot(account);
+        let mut balances = self.slots
+            .read::<LedgerSlot>(slot)?
+            .map(|(_, balances)| balances)
+            .unwrap_or_default();
+        update(&mut balances);
+
+        match balances.is_empty() {
+            true => self.slots.clear(slot),
+            false => self.slots.write(slot, &(account, balances)),
+        }
+    }
+}
+
+impl AccountStore for DiskAccountStore {
+    fn get(&self, id: AccountId) -> Result<Option<Account>, StoreError> {
+        self.slots.read(id.value() as u64)
+    }
+
+    fn insert(&mut self, account: Account) -> Result<(), StoreError> {
+        self.slots.write(account.id().value() as u64, &account)
+    }
+
+    fn remove(&mut self, id: AccountId) -> Result<(), StoreError> {
+        self.slots.clear(id.value() as u64)
+    }
+
+    fn accounts(&self) -> Result<Vec<Account>, StoreError> {
+        self.slots.values(0..ACCOUNT_SLOTS).collect()
+    }
+
+    fn ledger(&self) -> Result<Vec<LedgerLine>, StoreError> {
+        let mut lines = Vec::new();
+        for slot in self.slots.values::<LedgerSlot>(ACCOUNT_SLOTS..ACCOUNT_SLOTS + LEDGER_SLOTS) {
+            let (account, balances) = slot?;
+            lines.extend(balances
+                .into_iter()
+                .map(|(currency, balance)| LedgerLine { account, currency, balance }));
+        }
+
+        Ok(lines)
+    }
+
+    fn insert_ledger(&mut self, lines: &[LedgerLine]) -> Result<(), StoreError> {
+        for line in lines {
+            self.update_ledger(line.account, |balances| {
+                balances.insert(line.currency, line.balance);
+            })?;
+        }
+        Ok(())
+    }
+
+    fn remove_ledger(&mut self, lines: &[(LedgerAccount, Currency)]) -> Result<(), StoreError> {
+        for &(account, currency) in lines {
+            self.update_ledger(account, |balances| {
+                balances.remove(&currency);
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// The content of a slot of a transaction store
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+enum TransactionSlot {
+    Record(StoredTransaction),
+    /// A range of evicted transactions, in the slot of its first id
+    Evicted(IdRange),
+}
+
+/// A [`TransactionStore`] that keeps all records in a file instead of memory
+///
+/// Each record occupies a fixed-size slot addressed by its transaction id, so memory usage
+/// stays constant no matter how many transactions are saved. Records have to fit into a
+/// slot once they are encoded, which limits the length of their reasons. The ranges of
+/// evicted ids are kept in the slots of the evicted records, and the evicted funds and the
+/// clock in slots behind the records.
+#[derive(Debug)]
+pub struct DiskTransactionStore {
+    slots: SlotFile,
+}
+
+impl DiskTransactionStore {
+    /// Creates a new, empty store, and replaces the file if it already exists
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        Ok(Self { slots: SlotFile::open(path.as_ref(), true, CLOCK_SLOT + 1)? })
+    }
+
+    /// Opens an existing store, or creates a new one if the file doesn't exist yet
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        Ok(Self { slots: SlotFile::open(path.as_ref(), false, CLOCK_SLOT + 1)? })
+    }
+
+    /// Changes the evicted funds of a client, and clears its slot once it has none left
+    fn update_evicted_funds(&mut self, client: AccountId, update: impl FnOnce(&mut BTreeMap<Currency, LedgerBalance>)) -> Result<(), StoreError> {
+        let slot = EVICTED_FUNDS_SLOTS + client.value() as u64;
+        let mut totals = self.slots
+            .read::<(AccountId, BTreeMap<Currency, LedgerBalance>)>(slot)?
+            .map(|(_, totals)| totals)
+            .unwrap_or_default();
+        update(&mut totals);
+
+        match totals.is_empty() {
+            true => self.slots.clear(slot),
+            false => self.slots.write(slot, &(client, totals)),
+        }
+    }
+}
+
+impl TransactionStore for DiskTransactionStore {
+    fn get(&self, id: TransactionId) -> Result<Option<StoredTransaction>, StoreError> {
+        match self.slots.read(id.value() as u64)? {
+            Some(TransactionSlot::Record(record)) => Ok(Some(record)),
+            Some(TransactionSlot::Evicted(_)) | None => Ok(None),
+        }
+    }
+
+    fn insert(&mut self, record: StoredTransaction) -> Result<(), StoreError> {
+        self.slots.write(record.transaction().id().value() as u64, &TransactionSlot::Record(record))
+    }
+
+    fn remove(&mut self, id: TransactionId) -> Result<(), StoreError> {
+        self.slots.clear(id.value() as u64)
+    }
+
+    fn records(&self) -> Box<dyn Iterator<Item = Result<StoredTransaction, StoreError>> + '_> {
+        Box::new(self.slots
+            .values(0..TRANSACTION_SLOTS)
+            .filter_map(|slot| match slot {
+                Ok(TransactionSlot::Record(record)) => Some(Ok(record)),
+                Ok(TransactionSlot::Evicted(_)) => None,
+                Err(error) => Some(Err(error)),
+            }))
+    }
+
+    fn evicted(&self) -> Result<Evicted, StoreError> {
+        let mut evicted = Evicted::default();
+        for slot in self.slots.values(0..TRANSACTION_SLOTS) {
+            if let TransactionSlot::Evicted(range) = slot? {
+                evicted.ids.push(range);
+            }
+        }
+        for slot in self.slots.values::<(AccountId, BTreeMap<Currency, LedgerBalance>)>(EVICTED_FUNDS_SLOTS..EVICTED_FUNDS_SLOTS + ACCOUNT_SLOTS) {
+            let (client, totals) = slot?;
+            evicted.funds.extend(totals
+                .into_iter()
+                .map(|(currency, total)| EvictedFunds { client, currency, total }));
+        }
+
+        Ok(evicted)
+    }
+
+    fn insert_evicted(&mut self, evicted: &Evicted) -> Result<(), StoreError> {
+        // ranges that start within a newer range are left in place, since overlapping ranges are merged when they are read
+        for range in &evicted.ids {
+            self.slots.write(range.first.value() as u64, &TransactionSlot::Evicted(*range))?;
+        }
+        for funds in &evicted.funds {
+            self.update_evicted_funds(funds.client, |totals| {
+                totals.insert(funds.currency, funds.total);
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn remove_evicted(&mut self, evicted: &Evicted) -> Result<(), StoreError> {
+        for range in &evicted.ids {
+            if let Some(TransactionSlot::Evicted(_)) = self.slots.read(range.first.value() as u64)? {
+                self.slots.clear(range.first.value() as u64)?;
+            }
+        }
+        for funds in &evicted.funds {
+            self.update_evicted_funds(funds.client, |totals| {
+                totals.remove(&funds.currency);
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn clock(&self) -> Result<Option<Timestamp>, StoreError> {
+        self.slots.read(CLOCK_SLOT)
+    }
+
+    fn set_clock(&mut self, clock: Option<Timestamp>) -> Result<(), StoreError> {
+        match clock {
+            Some(clock) => self.slots.write(CLOCK_SLOT, &clock),
+            None => self.slots.clear(CLOCK_SLOT),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{process, read, TempPath};
+    use crate::{Amount, DisputeOpening, EngineConfig, Transaction, RedisputePolicy, TransactionEngine};
+
+    const TRANSACTIONS: &str = r#"type, client, tx, amount, reason
+                                  deposit,   1,  1,     50,
+                                  deposit,   2,  2,     20,
+                                  deposit,   1, 70000,  30,
+                                  withdrawal,2,  4,      5,
+                                  dispute,   1,  1,       ,
+                                  dispute,   2,  2,       ,
+                                  chargeback,2,  2,       ,
+                                  freeze,    1,  5,       , fraud investigation"#;
+
+    fn transactions() -> Vec<Transaction> {
+        read(TRANSACTIONS)
+    }
+
+    /// A fresh store path, that's unique per test
+    fn store_path(name: &str) -> TempPath {
+        TempPath::new(&format!("{}.store", name))
+    }
+
+    #[test]
+    fn disk_engine_matches_memory_engine() {
+        let mut memory = TransactionEngine::new();
+        let (accounts_path, transactions_path) = (store_path("engine-accounts"), store_path("engine-transactions"));
+        let mut disk = TransactionEngine::with_stores(
+            EngineConfig::default(),
+            DiskAccountStore::create(&accounts_path).unwrap(),
+            DiskTransactionStore::create(&transactions_path).unwrap(),
+        ).unwrap();
+
+        for transaction in transactions() {
+            let expected = memory.handle_transaction(transaction.clone()).map_err(|error| error.code());
+            let result = disk.handle_transaction(transaction).map_err(|error| error.code());
+            assert_eq!(result, expected);
+        }
+
+        assert_eq!(disk.snapshot().unwrap(), memory.snapshot().unwrap());
+    }
+
+    #[test]
+    fn ledger_survives_reopening() {
+        let mut memory = TransactionEngine::new();
+        let (accounts_path, transactions_path) = (store_path("ledger-accounts"), store_path("ledger-transactions"));
+        let mut disk = TransactionEngine::with_stores(
+            EngineConfig::default(),
+            DiskAccountStore::create(&accounts_path).unwrap(),
+            DiskTransactionStore::create(&transactions_path).unwrap(),
+        ).unwrap();
+        process(&mut memory, TRANSACTIONS);
+        process(&mut disk, TRANSACTIONS);
+        drop(disk);
+
+        let disk = TransactionEngine::with_stores(
+            EngineConfig::default(),
+            DiskAccountStore::open(&accounts_path).unwrap(),
+            DiskTransactionStore::open(&transactions_path).unwrap(),
+        ).unwrap();
+        assert_eq!(disk.ledger(), memory.ledger());
+        assert_eq!(disk.accounts().accounts().unwrap(), memory.accounts().accounts().unwrap());
+    }
+
+    #[test]
+    fn eviction_survives_reopening() {
+        let path = store_path("eviction");
+        let mut store = DiskTransactionStore::create(&path).unwrap();
+        let record = StoredTransaction::new(Transaction::deposit(TransactionId::new(2), AccountId::new(1), Amount::from(10)));
+        store.insert(record.clone()).unwrap();
+        let range = |first, last| IdRange { first: TransactionId::new(first), last: TransactionId::new(last) };
+        let funds = EvictedFunds { client: AccountId::new(1), currency: Currency::EUR, total: "12.5".parse().unwrap() };
+        store.insert_evicted(&Evicted { ids: vec![range(3, 7), range(9, 9)], funds: vec![funds] }).unwrap();
+        store.set_clock(Some(Timestamp::new(150))).unwrap();
+        drop(store);
+
+        let store = DiskTransactionStore::open(&path).unwrap();
+        assert_eq!(store.evicted().unwrap(), Evicted { ids: vec![range(3, 7), range(9, 9)], funds: vec![funds] });
+        assert_eq!(store.clock().unwrap(), Some(Timestamp::new(150)));
+        assert_eq!(store.get(TransactionId::new(3)).unwrap(), None);
+        assert_eq!(store.records().collect::<Result<Vec<_>, _>>().unwrap(), vec![record]);
+    }
+
+    #[test]
+    fn evictions_can_be_removed() {
+        let path = store_path("remove-eviction");
+        let mut store = DiskTransactionStore::create(&path).unwrap();
+        let range = |first, last| IdRange { first: TransactionId::new(first), last: TransactionId::new(last) };
+        let funds = |total: &str| EvictedFunds { client: AccountId::new(1), currency: Currency::EUR, total: total.parse().unwrap() };
+        let previous = Evicted { ids: vec![range(3, 7)], funds: vec![funds("12.5")] };
+        store.insert_evicted(&previous).unwrap();
+        let evicted = Evicted { ids: vec![range(3, 8)], funds: vec![funds("15")] };
+        store.insert_evicted(&evicted).unwrap();
+
+        store.remove_evicted(&evicted).unwrap();
+        assert_eq!(store.evicted().unwrap(), Evicted::default());
+        store.insert_evicted(&previous).unwrap();
+        assert_eq!(store.evicted().unwrap(), previous);
+    }
+
+    #[test]
+    fn records_survive_reopening() {
+        let path = store_path("reopen");
+        let mut store = DiskTransactionStore::create(&path).unwrap();
+        let records = transactions()
+            .into_iter()
+            .take(3)
+            .map(StoredTransaction::new)
+            .collect::<Vec<_>>();
+        for record in records.iter().rev() {
+            store.insert(record.clone()).unwrap();
+        }
+        let mut disputed = records[0].clone();
+        disputed.dispute(None, RedisputePolicy::Never, DisputeOpening { timestamp: None, sequence: 5 }).unwrap();
+        store.insert(disputed.clone()).unwrap();
+        drop(store);
+
+        let store = DiskTransactionStore::open(&path).unwrap();
+        assert_eq!(store.get(records[0].transaction().id()).unwrap(), Some(disputed.clone()));
+        assert_eq!(store.get(transactions()[3].id()).unwrap(), None);
+        assert_eq!(
+            store.records().collect::<Result<Vec<_>, _>>().unwrap(),
+            vec![disputed, records[1].clone(), records[2].clone()],
+        );
+    }
+
+    #[test]
+    fn removed_records_are_gone() {
+        let path = store_path("remove");
+        let mut store = DiskTransactionStore::create(&path).unwrap();
+        let records = transactions()
+            .into_iter()
+            .take(2)
+            .map(StoredTransaction::new)
+            .collect::<Vec<_>>();
+        for record in &records {
+            store.insert(record.clone()).unwrap();
+        }
+        store.remove(records[0].transaction().id()).unwrap();
+
+        assert_eq!(store.get(records[0].transaction().id()).unwrap(), None);
+        assert_eq!(store.records().collect::<Result<Vec<_>, _>>().unwrap(), vec![records[1].clone()]);
+    }
+
+    #[test]
+    fn create_replaces_existing_store() {
+        let path = store_path("replace");
+        let mut store = DiskAccountStore::create(&path).unwrap();
+        store.insert(Account::new(transactions()[0].client())).unwrap();
+        drop(store);
+
+        let store = DiskAccountStore::create(&path).unwrap();
+        assert_eq!(store.accounts().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn sparse_records_are_found_after_reopening() {
+        let path = store_path("sparse");
+        let mut store = DiskTransactionStore::create(&path).unwrap();
+        let records = [1, u32::MAX]
+            .map(|id| StoredTransaction::new(Transaction::deposit(TransactionId::new(id), AccountId::new(1), Amount::from(10))));
+        for record in &records {
+            store.insert(record.clone()).unwrap();
+        }
+        drop(store);
+
+        let store = DiskTransactionStore::open(&path).unwrap();
+        assert_eq!(store.records().collect::<Result<Vec<_>, _>>().unwrap(), records);
+    }
+
+    #[test]
+    fn opening_other_file_fails() {
+        let path = store_path("other");
+        std::fs::write(&path, "not a store").unwrap();
+
+        assert!(matches!(DiskTransactionStore::open(&path), Err(StoreError::Io(_))));
+    }
+
+    #[test]
+    fn too_large_record_fails() {
+        let path = store_path("too-large");
+        let mut store = DiskAccountStore::create(&path).unwrap();
+        let mut account = Account::new(transactions()[0].client());
+        account.freeze("x".repeat(1_000)).unwrap();
+
+        assert!(matches!(store.insert(account), Err(StoreError::RecordTooLarge { max: 510, .. })));
+    }
+}
@@ -0,0 +1,253 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::{LedgerEvent, Rejection, Timestamp, Transaction, TransactionError, TransactionType};
+
+/// The lifecycle state of a saved transaction
+///
+/// The legal transitions are:
+/// ```text
+/// Settled ──dispute──▶ Disputed ──chargeback──▶ ChargedBack
+///                       ▲    │
+///               dispute │    │ resolve
+///                       │    ▼
+///                      Resolved
+/// ```
+/// Disputing a resolved transaction again is only possible if the [`RedisputePolicy`] allows it.
+/// [`TransactionState::ChargedBack`] is final, i.e. a transaction that was charged
+/// back cannot be disputed again.
+#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionState {
+    /// The transaction was processed, and was never disputed
+    Settled,
+    /// There's an open dispute for the transaction
+    Disputed,
+    /// The last dispute of the transaction was resolved
+    Resolved,
+    /// The transaction was reversed after a dispute
+    ChargedBack,
+}
+
+/// Rules on whether a transaction can be disputed again after its dispute was resolved
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RedisputePolicy {
+    /// A transaction can only be disputed once
+    #[default]
+    Never,
+    /// A transaction can be disputed up to the specified number of times in total
+    Limited(u32),
+    /// A resolved transaction can be disputed again any number of times
+    Unlimited,
+}
+
+/// The types of transactions that can be disputed
+///
+/// Deposits and transfers can always be disputed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DisputableTransactions {
+    /// Only deposits can be disputed
+    #[default]
+    Deposits,
+    /// Both deposits and withdrawals can be disputed
+    ///
+    /// Disputing a withdrawal provisionally credits the disputed amount to the client's
+    /// held funds. See [`LedgerEventKind::CreditProvisioned`](crate::LedgerEventKind::CreditProvisioned).
+    DepositsAndWithdrawals,
+}
+
+/// When an open dispute was opened
+///
+/// See [`StoredTransaction::opened`](crate::StoredTransaction::opened).
+#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DisputeOpening {
+    /// The time of the dispute, or the clock of the engine if the dispute had no timestamp
+    pub timestamp: Option<Timestamp>,
+    /// The sequence number of the dispute, see [`TransactionEngine::sequence`](crate::TransactionEngine::sequence)
+    pub sequence: u64,
+}
+
+/// How an open dispute is closed once its deadline passed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DeadlineAction {
+    /// The disputed funds are released again, like by a resolve
+    #[default]
+    Resolve,
+    /// The disputed funds are reversed, like by a chargeback
+    Chargeback,
+}
+
+impl DeadlineAction {
+    /// The type of the transaction that closes the dispute
+    pub fn transaction_type(self) -> TransactionType {
+        match self {
+            Self::Resolve => TransactionType::Resolve,
+            Self::Chargeback => TransactionType::Chargeback,
+        }
+    }
+}
+
+impl FromStr for DeadlineAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "resolve" => Ok(Self::Resolve),
+            "chargeback" => Ok(Self::Chargeback),
+            _ => Err(format!("unknown deadline action `{}`, expected resolve or chargeback", s)),
+        }
+    }
+}
+
+/// How long disputes can stay open before they are closed automatically
+///
+/// Deadlines are applied by [`TransactionEngine::advance_time`](crate::TransactionEngine::advance_time)
+/// and [`TransactionEngine::close_expired_disputes`](crate::TransactionEngine::close_expired_disputes).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DisputeDeadline {
+    /// The time after which an open dispute expires
+    pub after: Duration,
+    /// The number of accepted transactions after which a dispute that was opened at an unknown
+    /// time expires
+    ///
+    /// Without it, disputes are rejected while the engine doesn't know the time.
+    pub after_transactions: Option<u64>,
+    /// How expired disputes are closed
+    pub action: DeadlineAction,
+}
+
+impl DisputeDeadline {
+    /// Whether a dispute that was opened at `opened` has expired at the time `now`, once
+    /// `sequence` transactions were accepted
+    ///
+    /// Disputes that were opened at an unknown time expire by the number of transactions
+    /// since, or right away if there's no such limit.
+    pub fn expired(self, opened: DisputeOpening, now: Option<Timestamp>, sequence: u64) -> bool {
+        match (opened.timestamp, self.after_transactions) {
+            (Some(timestamp), _) => now.is_some_and(|now| now.elapsed_since(timestamp) >= self.after),
+            (None, Some(transactions)) => sequence.saturating_sub(opened.sequence) >= transactions,
+            (None, None) => true,
+        }
+    }
+}
+
+/// A dispute that was closed because its deadline passed, see [`TransactionEngine::advance_time`](crate::TransactionEngine::advance_time)
+#[derive(Debug)]
+pub struct ClosedDispute {
+    /// The resolve or chargeback that closed the dispute
+    pub transaction: Transaction,
+    /// When the dispute was opened
+    pub opened: DisputeOpening,
+    /// The changes the closing transaction caused, or why it was rejected
+    pub outcome: Result<Vec<LedgerEvent>, Rejection>,
+}
+
+impl DisputableTransactions {
+    /// Whether transactions of the specified type can be disputed
+    pub fn contains(self, transaction_type: TransactionType) -> bool {
+        match transaction_type {
+            TransactionType::Deposit | TransactionType::Transfer => true,
+            TransactionType::Withdrawal => self == Self::DepositsAndWithdrawals,
+            _ => false,
+        }
+    }
+}
+
+impl RedisputePolicy {
+    /// Whether a transaction that was already disputed `disputes` times may be disputed again
+    fn allows(self, disputes: u32) -> bool {
+        match self {
+            Self::Never => disputes == 0,
+            Self::Limited(limit) => disputes < limit,
+            Self::Unlimited => true,
+        }
+    }
+}
+
+impl TransactionState {
+    /// Whether there's currently an open dispute for the transaction
+    pub fn is_disputed(self) -> bool {
+        self == Self::Disputed
+    }
+
+    /// The state after opening a new dispute
+    ///
+    /// `disputes` is the number of disputes that were opened for the transaction so far.
+    pub(crate) fn dispute(self, disputes: u32, policy: RedisputePolicy) -> Result<Self, TransactionError> {
+        match self {
+            Self::Settled | Self::Resolved if policy.allows(disputes) => Ok(Self::Disputed),
+            Self::Settled | Self::Resolved => Err(TransactionError::RedisputeNotAllowed),
+            Self::Disputed => Err(TransactionError::DuplicateDispute),
+            Self::ChargedBack => Err(TransactionError::ChargedBack),
+        }
+    }
+
+    /// The state after resolving the open dispute
+    pub(crate) fn resolve(self) -> Result<Self, TransactionError> {
+        match self {
+            Self::Disputed => Ok(Self::Resolved),
+            _ => Err(TransactionError::UnknownDispute),
+        }
+    }
+
+    /// The state after charging back the open dispute
+    pub(crate) fn charge_back(self) -> Result<Self, TransactionError> {
+        match self {
+            Self::Disputed => Ok(Self::ChargedBack),
+            _ => Err(TransactionError::UnknownDispute),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use TransactionState::*;
+
+    #[test]
+    fn dispute_transitions() {
+        assert_eq!(Settled.dispute(0, RedisputePolicy::Never).unwrap(), Disputed);
+        assert!(matches!(Disputed.dispute(1, RedisputePolicy::Unlimited), Err(TransactionError::DuplicateDispute)));
+        assert!(matches!(ChargedBack.dispute(1, RedisputePolicy::Unlimited), Err(TransactionError::ChargedBack)));
+    }
+
+    #[test]
+    fn redispute_follows_policy() {
+        assert!(matches!(Resolved.dispute(1, RedisputePolicy::Never), Err(TransactionError::RedisputeNotAllowed)));
+        assert_eq!(Resolved.dispute(1, RedisputePolicy::Limited(2)).unwrap(), Disputed);
+        assert!(matches!(Resolved.dispute(2, RedisputePolicy::Limited(2)), Err(TransactionError::RedisputeNotAllowed)));
+        assert_eq!(Resolved.dispute(1_000, RedisputePolicy::Unlimited).unwrap(), Disputed);
+    }
+
+    #[test]
+    fn resolve_transitions() {
+        assert_eq!(Disputed.resolve().unwrap(), Resolved);
+        for state in [Settled, Resolved, ChargedBack] {
+            assert!(matches!(state.resolve(), Err(TransactionError::UnknownDispute)));
+        }
+    }
+
+    #[test]
+    fn deadline_expiry() {
+        let deadline = DisputeDeadline { after: Duration::from_secs(60), after_transactions: Some(3), action: DeadlineAction::Resolve };
+        let opened = DisputeOpening { timestamp: Some(Timestamp::new(100)), sequence: 1 };
+
+        assert!(!deadline.expired(opened, Some(Timestamp::new(159)), 100));
+        assert!(deadline.expired(opened, Some(Timestamp::new(160)), 1));
+
+        // disputes opened at an unknown time expire by the number of transactions since
+        let untimed = DisputeOpening { timestamp: None, ..opened };
+        assert!(!deadline.expired(untimed, Some(Timestamp::new(1_000)), 3));
+        assert!(deadline.expired(untimed, None, 4));
+        assert!(DisputeDeadline { after_transactions: None, ..deadline }.expired(untimed, None, 1));
+    }
+
+    #[test]
+    fn charge_back_transitions() {
+        assert_eq!(Disputed.charge_back().unwrap(), ChargedBack);
+        for state in [Settled, Resolved, ChargedBack] {
+            assert!(matches!(state.charge_back(), Err(TransactionError::UnknownDispute)));
+        }
+    }
+}
@@ -1,7 +1,10 @@
-use std::collections::{HashMap, HashSet};
-use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+use std::time::Duration;
 
-use crate::{Account, AccountError, AccountId, Transaction, TransactionId, TransactionType};
+use crate::invariants;
+use crate::eviction::EvictedIds;
+use crate::{Account, AccountError, AccountId, AccountOperation, AccountStatus, AccountStore, Amount, ClosedDispute, Currency, DisputableTransactions, DisputeDeadline, DisputeOpening, Evicted, EvictedFunds, FxRates, FxRounding, InvariantViolation, Journal, JournalError, Ledger, LedgerAccount, LedgerBalance, LedgerEvent, LedgerLine, LedgerEventKind, Posting, RedisputePolicy, Recovery, Rejection, Snapshot, SnapshotError, StoreError, StoredTransaction, SyncPolicy, Timestamp, Transaction, TransactionId, TransactionRow, TransactionState, TransactionStore, TransactionType};
 
 /// Possible errors to occur during the processing of a transaction
 #[derive(Debug, thiserror::Error)]
@@ -18,300 +21,2737 @@ pub enum TransactionError {
     UnknownDispute,
     #[error("There's already a transaction with the same id")]
     DuplicateTransaction,
-    #[error("The transaction is not of type deposit and cannot be disputed")]
+    #[error("Transactions of this type cannot be disputed")]
     ImpossibleDispute,
+    #[error("The referenced transaction belongs to a different client")]
+    ClientMismatch,
+    #[error("The transaction was already disputed and cannot be disputed again")]
+    RedisputeNotAllowed,
+    #[error("The transaction was charged back and cannot be disputed again")]
+    ChargedBack,
+    #[error("The amount exceeds the disputable or disputed portion of the transaction")]
+    ExcessiveAmount,
+    #[error("The disputed, resolved or charged back portion is zero")]
+    ZeroAmount,
+    #[error("The referenced account was not found")]
+    AccountNotFound,
+    #[error("The transaction is missing a reason")]
+    TransactionReasonNotSpecified,
+    #[error("The conversion is missing a target currency")]
+    TargetCurrencyNotSpecified,
+    #[error("The conversion converts to the same currency")]
+    SameCurrency,
+    #[error("There's no exchange rate for the conversion")]
+    RateNotFound,
+    #[error("The transfer is missing a destination")]
+    DestinationNotSpecified,
+    #[error("The transfer sends funds to the same client")]
+    SelfTransfer,
+    #[error("The dispute window of the referenced transaction has passed")]
+    DisputeWindowExpired,
+    #[error("The dispute has a deadline, but its time is unknown")]
+    MissingTimestamp,
+    #[error(transparent)]
+    Storage(#[from] StoreError),
+}
+
+impl TransactionError {
+    /// A stable, machine-readable code identifying the error, i.e. `insufficient_funds`
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Account(error) => error.code(),
+            Self::TransactionNotFound => "transaction_not_found",
+            Self::TransactionAmountNotSpecified => "amount_not_specified",
+            Self::DuplicateDispute => "duplicate_dispute",
+            Self::UnknownDispute => "unknown_dispute",
+            Self::DuplicateTransaction => "duplicate_transaction",
+            Self::ImpossibleDispute => "impossible_dispute",
+            Self::ClientMismatch => "client_mismatch",
+            Self::RedisputeNotAllowed => "redispute_not_allowed",
+            Self::ChargedBack => "charged_back",
+            Self::ExcessiveAmount => "excessive_amount",
+            Self::ZeroAmount => "zero_amount",
+            Self::AccountNotFound => "account_not_found",
+            Self::TransactionReasonNotSpecified => "reason_not_specified",
+            Self::TargetCurrencyNotSpecified => "target_currency_not_specified",
+            Self::SameCurrency => "same_currency",
+            Self::RateNotFound => "rate_not_found",
+            Self::DestinationNotSpecified => "destination_not_specified",
+            Self::SelfTransfer => "self_transfer",
+            Self::DisputeWindowExpired => "dispute_window_expired",
+            Self::MissingTimestamp => "missing_timestamp",
+            Self::Storage(_) => "storage_error",
+        }
+    }
+}
+
+/// The configuration of a [`TransactionEngine`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EngineConfig {
+    /// Whether a transaction can be disputed again after its dispute was resolved
+    pub redispute_policy: RedisputePolicy,
+    /// Which types of transactions can be disputed
+    pub disputable: DisputableTransactions,
+    /// The exchange rates used by conversions
+    ///
+    /// Journaled conversions are replayed with these rates, so recovering an engine
+    /// requires the rates it used before.
+    pub rates: FxRates,
+    /// How converted amounts are rounded
+    pub fx_rounding: FxRounding,
+    /// How long transactions with a timestamp can be disputed
+    ///
+    /// Disputes after the window are rejected. Transactions whose window has passed are
+    /// evicted from the transaction store as soon as they aren't disputed anymore. Their
+    /// ids are still remembered, so they cannot be used again, and disputes of them are
+    /// rejected like late ones. Transactions without a timestamp can always be disputed.
+    /// See [`TransactionEngine::clock`].
+    pub dispute_window: Option<Duration>,
+    /// How long disputes can stay open before they are closed automatically
+    ///
+    /// See [`TransactionEngine::advance_time`] and [`TransactionEngine::close_expired_disputes`].
+    pub dispute_deadline: Option<DisputeDeadline>,
+}
+
+/// The validated effects of a single transaction
+///
+/// See [`TransactionEngine::prepare`] and [`TransactionEngine::commit`].
+#[derive(Debug)]
+struct Changes {
+    /// The new states of the affected accounts, i.e. both clients of a transfer
+    accounts: Vec<Account>,
+    /// The new record of the saved or referenced transaction, if any
+    record: Option<StoredTransaction>,
+    /// The changes to the affected account
+    events: Vec<LedgerEvent>,
+    /// The time of the transaction, which advances the clock of the engine
+    timestamp: Option<Timestamp>,
+}
+
+/// A record that was evicted while committing a transaction, and what it replaced
+///
+/// See [`TransactionEngine::evict_record`] and [`TransactionEngine::undo_eviction`].
+#[derive(Debug)]
+struct Eviction {
+    record: StoredTransaction,
+    /// The range containing the id of the record, and the new funds of the evicted transactions
+    evicted: Evicted,
+    /// The ranges the new range replaced, and the previous funds of the evicted transactions
+    replaced: Evicted,
+    /// Whether the record was waiting for its dispute window to pass
+    tracked: bool,
+}
+
+/// The kinds of payments, see [`TransactionEngine::prepare_payment`]
+#[derive(Clone, Copy, Debug)]
+enum Payment {
+    Deposit,
+    Withdrawal,
+}
+
+/// The steps of a dispute, see [`TransactionEngine::prepare_dispute`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DisputeStep {
+    Dispute,
+    Resolve,
+    Chargeback,
+}
+
+/// The transactions of an operator, see [`TransactionEngine::prepare_administrative`]
+#[derive(Clone, Copy, Debug)]
+enum Administration {
+    Unlock,
+    Freeze,
+    Close,
 }
 
 /// The central transaction engine used for processing all transactions
 ///
 /// This will automatically create use accounts on the fly, in case transactions
 /// reference new or unknown user accounts.
-#[derive(Debug, Default)]
-pub struct TransactionEngine {
-    /// A map of all user accounts
-    accounts: HashMap<AccountId, Account>,
-    /// A map of all deposit and withdrawal transactions
+///
+/// By default, accounts and transactions are kept in memory. Other storage can be used
+/// through [`TransactionEngine::with_stores`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TransactionEngine<A = HashMap<AccountId, Account>, T = HashMap<TransactionId, StoredTransaction>> {
+    config: EngineConfig,
+    /// The store of all user accounts
+    accounts: A,
+    /// The store of all deposit and withdrawal transactions, together with their dispute state
     /// Other types of transactions cannot be referenced, and therefore don't have to be saved
-    transactions: HashMap<TransactionId, Transaction>,
-    /// A set of all currently disputed transactions
-    disputes: HashSet<TransactionId>,
+    transactions: T,
+    /// The number of accepted transactions
+    sequence: u64,
+    /// The double-entry record of all funds
+    ledger: Ledger,
+    /// The latest timestamp of all accepted transactions
+    clock: Option<Timestamp>,
+    /// The saved transactions with a timestamp, ordered by the time their dispute window passes
+    ///
+    /// Only used if there's a dispute window.
+    expiries: BTreeSet<(Timestamp, TransactionId)>,
+    /// The total funds per client and currency of the transactions that were evicted
+    evicted: BTreeMap<(AccountId, Currency), LedgerBalance>,
+    /// The ids of the transactions that were evicted
+    evicted_ids: EvictedIds,
 }
 
 impl TransactionEngine {
     /// Creates a new, empty transaction engine
     pub fn new() -> Self {
+        Self::with_config(EngineConfig::default())
+    }
+
+    /// Creates a new, empty transaction engine with a custom configuration
+    pub fn with_config(config: EngineConfig) -> Self {
+        Self::empty(config, HashMap::new(), HashMap::new())
+    }
+
+    /// Restores an engine from a snapshot
+    ///
+    /// See [`TransactionEngine::snapshot`].
+    pub fn from_snapshot(config: EngineConfig, snapshot: Snapshot) -> Result<Self, SnapshotError> {
+        let mut engine = Self::with_config(config);
+        engine.restore(snapshot)?;
+
+        Ok(engine)
+    }
+
+    /// Rebuilds an engine after a crash from an optional snapshot and a journal
+    ///
+    /// All journal entries that are already part of the snapshot are skipped, and the
+    /// remaining ones are replayed. A torn entry at the end of the journal is removed,
+    /// so the returned journal can be used to continue processing right away.
+    pub fn recover(
+        config: EngineConfig,
+        snapshot: Option<Snapshot>,
+        path: impl AsRef<Path>,
+        sync_policy: SyncPolicy,
+    ) -> Result<(Self, Journal, Recovery), JournalError> {
+        let path = path.as_ref();
+        let mut engine = match snapshot {
+            Some(snapshot) => Self::from_snapshot(config, snapshot)?,
+            None => Self::with_config(config),
+        };
+        let contents = Journal::read(path)?;
+        let mut recovery = Recovery {
+            replayed: 0,
+            skipped: 0,
+            torn_len: contents.torn_len,
+        };
+
+        for entry in contents.entries {
+            if entry.sequence <= engine.sequence {
+                recovery.skipped += 1;
+                continue;
+            }
+            if entry.sequence != engine.sequence + 1 {
+                return Err(JournalError::Gap { expected: engine.sequence + 1, found: entry.sequence });
+            }
+
+            let changes = engine
+                .prepare(&entry.transaction)
+                .map_err(|error| JournalError::Replay { sequence: entry.sequence, error })?;
+            engine.commit(changes)?;
+            recovery.replayed += 1;
+        }
+
+        if contents.torn_len > 0 {
+            let file = std::fs::OpenOptions::new().write(true).open(path)?;
+            file.set_len(contents.valid_len)?;
+            file.sync_all()?;
+        }
+        let journal = Journal::open(path, sync_policy)?;
+
+        Ok((engine, journal, recovery))
+    }
+
+}
+
+impl<A: AccountStore, T: TransactionStore> TransactionEngine<A, T> {
+    /// Creates a new transaction engine on top of the specified stores
+    ///
+    /// The stores may already contain accounts and transactions, i.e. when they are
+    /// persisted across runs. The ledger is loaded from the account store, and the clock
+    /// and the evicted transactions from the transaction store.
+    pub fn with_stores(config: EngineConfig, accounts: A, transactions: T) -> Result<Self, StoreError> {
+        let ledger = Ledger::from_lines(accounts.ledger()?);
+        let evicted = transactions.evicted()?;
+        let clock = transactions.clock()?;
+        // when the saved transactions can be evicted is only tracked in memory
+        let mut expiries = BTreeSet::new();
+        if config.dispute_window.is_some() {
+            for record in transactions.records() {
+                let transaction = record?.transaction().clone();
+                if let Some(timestamp) = transaction.timestamp() {
+                    expiries.insert((timestamp, transaction.id()));
+                }
+            }
+        }
+
+        Ok(Self {
+            ledger,
+            clock,
+            expiries,
+            evicted: evicted.funds
+                .into_iter()
+                .map(|funds| ((funds.client, funds.currency), funds.total))
+                .collect(),
+            evicted_ids: EvictedIds::from_ranges(evicted.ids),
+            ..Self::empty(config, accounts, transactions)
+        })
+    }
+
+    /// Creates a new transaction engine on top of the specified stores, without loading anything from them
+    fn empty(config: EngineConfig, accounts: A, transactions: T) -> Self {
         Self {
-            accounts: HashMap::new(),
-            transactions: HashMap::new(),
-            disputes: HashSet::new(),
+            config,
+            accounts,
+            transactions,
+            sequence: 0,
+            ledger: Ledger::new(),
+            clock: None,
+            expiries: BTreeSet::new(),
+            evicted: BTreeMap::new(),
+            evicted_ids: EvictedIds::default(),
         }
     }
 
-    /// The map of all current accounts
-    pub fn accounts(&self) -> &HashMap<AccountId, Account> {
+    /// The configuration of the engine
+    pub fn config(&self) -> &EngineConfig {
+        &self.config
+    }
+
+    /// The store of all current accounts
+    pub fn accounts(&self) -> &A {
         &self.accounts
     }
 
+    /// The store of all saved deposits and withdrawals
+    pub fn transactions(&self) -> &T {
+        &self.transactions
+    }
+
+    /// A saved deposit or withdrawal, together with its dispute state
+    pub fn transaction(&self, id: TransactionId) -> Result<Option<StoredTransaction>, StoreError> {
+        self.transactions.get(id)
+    }
+
+    /// A processed transaction as a row of a transaction log, see [`TransactionRow`]
+    ///
+    /// If the transaction is saved, the row contains the current dispute state of its record.
+    /// Rows of other transactions, i.e. disputes or evicted deposits, leave these columns empty.
+    pub fn transaction_row(&self, transaction: &Transaction) -> Result<TransactionRow, StoreError> {
+        Ok(match self.transactions.get(transaction.id())? {
+            Some(record) if record.transaction() == transaction => record.row(),
+            _ => transaction.row(),
+        })
+    }
+
+    /// The current state of a saved deposit or withdrawal
+    pub fn transaction_state(&self, id: TransactionId) -> Result<Option<TransactionState>, StoreError> {
+        Ok(self.transaction(id)?.map(|record| record.state()))
+    }
+
+    /// The general ledger, that records all movements of funds
+    ///
+    /// The ledger is written to the account store together with the accounts. Clients whose
+    /// accounts were created before the ledger recorded them, i.e. in a store without a
+    /// ledger, are added with their funds as opening balances the next time they are
+    /// involved in a transaction.
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    /// The number of transactions the engine accepted so far
+    ///
+    /// The sequence number is part of snapshots, and is used to match snapshots with
+    /// the entries of a [`Journal`].
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// The latest timestamp of all transactions the engine accepted so far
+    ///
+    /// Disputes without a timestamp are checked against the dispute window at this time,
+    /// see [`EngineConfig::dispute_window`].
+    pub fn clock(&self) -> Option<Timestamp> {
+        self.clock
+    }
+
+    /// Loads the state of a snapshot into the stores of the engine
+    ///
+    /// The stores are expected to be empty. See [`TransactionEngine::from_snapshot`].
+    pub fn restore(&mut self, snapshot: Snapshot) -> Result<(), SnapshotError> {
+        self.sequence = snapshot.sequence();
+        self.clock = snapshot.clock();
+        self.transactions.set_clock(self.clock)?;
+        self.accounts.insert_ledger(&snapshot.ledger)?;
+        self.ledger = Ledger::from_lines(snapshot.ledger);
+        self.evicted_ids = EvictedIds::from_ranges(snapshot.evicted_ids);
+        let evicted = Evicted { ids: self.evicted_ids.ranges().collect(), funds: snapshot.evicted };
+        self.transactions.insert_evicted(&evicted)?;
+        self.evicted = evicted.funds
+            .into_iter()
+            .map(|funds| ((funds.client, funds.currency), funds.total))
+            .collect();
+
+        for account in snapshot.accounts {
+            if self.accounts.get(account.id())?.is_some() {
+                return Err(SnapshotError::DuplicateAccount(account.id()));
+            }
+            self.accounts.insert(account)?;
+        }
+        for record in snapshot.transactions {
+            if self.transactions.contains(record.transaction().id())? {
+                return Err(SnapshotError::DuplicateTransaction(record.transaction().id()));
+            }
+            self.track_expiry(&record);
+            self.transactions.insert(record)?;
+        }
+
+        Ok(())
+    }
+
+    /// Creates a snapshot of the full engine state
+    ///
+    /// Accounts and transactions are ordered by their ids, so equal engines always
+    /// produce equal snapshots.
+    pub fn snapshot(&self) -> Result<Snapshot, StoreError> {
+        let accounts = self.accounts.accounts()?;
+        let transactions = self.transactions
+            .records()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let evicted = self.evicted
+            .iter()
+            .map(|(&(client, currency), &total)| EvictedFunds { client, currency, total })
+            .collect();
+
+        let evicted_ids = self.evicted_ids.ranges().collect();
+
+        Ok(Snapshot::new(self.sequence, self.clock, accounts, transactions, self.ledger.trial_balance().lines, evicted, evicted_ids))
+    }
+
+    /// Checks the accounts, saved transactions, and ledger of the engine against each other
+    ///
+    /// For every client, the total funds have to match the saved deposits minus withdrawals,
+    /// without charged back transactions, and the held funds have to match the open
+    /// disputes. Every saved transaction has to belong to an existing account, and the
+    /// ledger has to be balanced. Closed accounts only have to be empty, since their payouts
+    /// are not saved. Evicted transactions are accounted for by their total funds. An empty
+    /// list means that all invariants hold.
+    pub fn verify_invariants(&self) -> Result<Vec<InvariantViolation>, StoreError> {
+        invariants::verify(self.accounts.accounts()?, self.transactions.records(), &self.evicted, &self.ledger)
+    }
+
     /// Processes one transaction and applies possible effects to user accounts
-    pub fn handle_transaction(&mut self, transaction: Transaction) -> Result<(), TransactionError> {
-        let transaction_id = transaction.id();
-        let transaction_type = transaction.transaction_type();
-        self.save_transaction(transaction)?;
-
-        let transaction = self.transactions
-            .get(&transaction_id)
-            .ok_or(TransactionError::TransactionNotFound)?;
+    ///
+    /// Transactions are processed atomically: if the transaction is rejected, the
+    /// engine is left exactly as it was before, i.e. no accounts are created and
+    /// the transaction is not saved.
+    ///
+    /// Returns the [`LedgerEvent`]s describing how the transaction changed the account.
+    pub fn handle_transaction(&mut self, transaction: Transaction) -> Result<Vec<LedgerEvent>, TransactionError> {
+        let changes = self.prepare(&transaction)?;
+
+        Ok(self.commit(changes)?)
+    }
+
+    /// Processes one transaction like [`TransactionEngine::handle_transaction`], but
+    /// returns the context of the transaction alongside the error if it's rejected
+    pub fn process(&mut self, transaction: Transaction) -> Result<Vec<LedgerEvent>, Rejection> {
+        let changes = self
+            .prepare(&transaction)
+            .map_err(|error| Rejection::new(&transaction, error))?;
+
+        self.commit(changes)
+            .map_err(|error| Rejection::new(&transaction, error.into()))
+    }
+
+    /// Processes one transaction like [`TransactionEngine::process`], but writes it to
+    /// the journal before applying it
+    ///
+    /// Rejected transactions are not journaled. If the journal cannot be written, the
+    /// transaction is not applied, and the outer error is returned. If the transaction
+    /// was journaled, but cannot be written to the stores, it's rejected with a
+    /// [`TransactionError::Storage`] error, and will be applied again on recovery. Until
+    /// then, the journal refuses all further transactions with [`JournalError::Unapplied`].
+    pub fn process_journaled(&mut self, transaction: Transaction, journal: &mut Journal) -> Result<Result<Vec<LedgerEvent>, Rejection>, JournalError> {
+        let changes = match self.prepare(&transaction) {
+            Ok(changes) => changes,
+            Err(error) => return Ok(Err(Rejection::new(&transaction, error))),
+        };
+        let sequence = self.sequence + 1;
+        journal.append(sequence, &transaction)?;
+
+        let committed = self.commit(changes);
+        if committed.is_err() {
+            journal.mark_unapplied(sequence);
+        }
+
+        Ok(committed.map_err(|error| Rejection::new(&transaction, error.into())))
+    }
+
+    /// Advances the clock to the specified time, and closes all open disputes whose deadline passed
+    ///
+    /// Every expired dispute is closed by a resolve or chargeback of its whole disputed
+    /// portion, see [`EngineConfig::dispute_deadline`]. The closing transactions carry the id
+    /// and client of the disputed transaction and the timestamp `now`, and are processed like
+    /// any other transaction, in the order the disputes were opened. Returns the closed
+    /// disputes, together with the events of the closing transactions or why they were rejected.
+    ///
+    /// All saved transactions are checked, so this is meant to be called periodically, i.e.
+    /// after every input file, rather than after every transaction.
+    pub fn advance_time(&mut self, now: Timestamp) -> Result<Vec<ClosedDispute>, StoreError> {
+        self.close_disputes(Some(now), |engine, transaction| Ok(engine.process(transaction)))
+    }
+
+    /// Closes all open disputes whose deadline passed, without advancing the clock
+    ///
+    /// Like [`TransactionEngine::advance_time`], but for engines that may not know the time,
+    /// i.e. when the input has no timestamps. The closing transactions carry the current clock.
+    pub fn close_expired_disputes(&mut self) -> Result<Vec<ClosedDispute>, StoreError> {
+        self.close_disputes(None, |engine, transaction| Ok(engine.process(transaction)))
+    }
+
+    /// Advances the clock like [`TransactionEngine::advance_time`], but writes the closing
+    /// transactions to the journal before applying them
+    ///
+    /// The clock itself is not journaled, so a recovered engine only knows the timestamps of
+    /// the journaled transactions.
+    pub fn advance_time_journaled(&mut self, now: Timestamp, journal: &mut Journal) -> Result<Vec<ClosedDispute>, JournalError> {
+        self.close_disputes(Some(now), |engine, transaction| engine.process_journaled(transaction, journal))
+    }
+
+    /// Closes expired disputes for [`TransactionEngine::advance_time`] and its variants
+    fn close_disputes<E: From<StoreError>>(
+        &mut self,
+        now: Option<Timestamp>,
+        mut process: impl FnMut(&mut Self, Transaction) -> Result<Result<Vec<LedgerEvent>, Rejection>, E>,
+    ) -> Result<Vec<ClosedDispute>, E> {
+        let clock = self.clock.max(now);
+        if clock != self.clock {
+            self.transactions.set_clock(clock)?;
+            self.clock = clock;
+        }
+        // without a new time, the disputes are closed at the current clock
+        let now = now.or(self.clock);
+        let mut expired = Vec::new();
+        if let Some(deadline) = self.config.dispute_deadline {
+            for record in self.transactions.records() {
+                let record = record?;
+                let transaction = record.transaction();
+                match record.opened() {
+                    Some(opened) if record.state().is_disputed() && deadline.expired(opened, now, self.sequence) => {
+                        let closing = Transaction::new(transaction.id(), deadline.action.transaction_type(), transaction.client(), None, Currency::default(), None);
+                        expired.push((opened, match now {
+                            Some(now) => closing.at(now),
+                            None => closing,
+                        }));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        expired.sort_by_key(|&(opened, _)| opened);
+
+        let mut closed = Vec::new();
+        for (opened, transaction) in expired {
+            let outcome = process(self, transaction.clone())?;
+            closed.push(ClosedDispute { transaction, opened, outcome });
+        }
+        // the clock may have passed dispute windows without any transaction being processed
+        self.evict(self.clock, &mut Vec::new())?;
+
+        Ok(closed)
+    }
+
+    /// Checks whether a transaction can be applied, and calculates the resulting state of
+    /// the affected account and transaction record
+    ///
+    /// This does not modify the engine in any way. See [`TransactionEngine::commit`].
+    fn prepare(&self, transaction: &Transaction) -> Result<Changes, TransactionError> {
+        match transaction.transaction_type() {
+            TransactionType::Deposit => self.prepare_payment(transaction, Payment::Deposit),
+            TransactionType::Withdrawal => self.prepare_payment(transaction, Payment::Withdrawal),
+            TransactionType::Dispute => self.prepare_dispute(transaction, DisputeStep::Dispute),
+            TransactionType::Resolve => self.prepare_dispute(transaction, DisputeStep::Resolve),
+            TransactionType::Chargeback => self.prepare_dispute(transaction, DisputeStep::Chargeback),
+            TransactionType::Unlock => self.prepare_administrative(transaction, Administration::Unlock),
+            TransactionType::Freeze => self.prepare_administrative(transaction, Administration::Freeze),
+            TransactionType::Close => self.prepare_administrative(transaction, Administration::Close),
+            TransactionType::Convert => self.prepare_conversion(transaction),
+            TransactionType::Transfer => self.prepare_transfer(transaction),
+        }
+    }
+
+    /// Checks that no other transaction used the id of a new transaction, including evicted ones
+    fn check_unused(&self, id: TransactionId) -> Result<(), TransactionError> {
+        if self.transactions.contains(id)? || self.evicted_ids.contains(id) {
+            return Err(TransactionError::DuplicateTransaction);
+        }
+
+        Ok(())
+    }
+
+    /// Prepares a deposit or withdrawal
+    fn prepare_payment(&self, transaction: &Transaction, payment: Payment) -> Result<Changes, TransactionError> {
+        self.check_unused(transaction.id())?;
+
         let amount = transaction
             .amount()
             .ok_or(TransactionError::TransactionAmountNotSpecified)?;
-        let account = self.accounts
-            .entry(transaction.client())
-            .or_insert_with(|| Account::new(transaction.client()));
+        let currency = transaction.currency();
+        let mut account = self.account(transaction.client())?;
+
+        let events = match payment {
+            Payment::Deposit => self.apply(transaction, currency, &mut account, AccountOperation::Deposit, LedgerEventKind::FundsDeposited { amount })?,
+            Payment::Withdrawal => self.apply(transaction, currency, &mut account, AccountOperation::Withdrawal, LedgerEventKind::FundsWithdrawn { amount })?,
+        };
+
+        Ok(Changes {
+            accounts: vec![account],
+            record: Some(StoredTransaction::new(transaction.clone())),
+            events,
+            timestamp: transaction.timestamp(),
+        })
+    }
+
+    /// Prepares a transfer of available funds to another client
+    ///
+    /// Both accounts are changed together, so a transfer is either applied to both sides or
+    /// rejected. Unlike deposits, transfers never create the destination account.
+    fn prepare_transfer(&self, transaction: &Transaction) -> Result<Changes, TransactionError> {
+        self.check_unused(transaction.id())?;
+
+        let amount = transaction
+            .amount()
+            .ok_or(TransactionError::TransactionAmountNotSpecified)?;
+        let destination = transaction
+            .destination()
+            .ok_or(TransactionError::DestinationNotSpecified)?;
+        if destination == transaction.client() {
+            return Err(TransactionError::SelfTransfer);
+        }
+        let currency = transaction.currency();
+        let mut sender = self.account(transaction.client())?;
+        let mut receiver = self
+            .stored_account(destination)?
+            .ok_or(TransactionError::AccountNotFound)?;
+
+        let mut events = self.apply(transaction, currency, &mut sender, AccountOperation::Withdrawal, LedgerEventKind::FundsTransferredOut { amount, to: destination })?;
+        events.extend(self.apply(transaction, currency, &mut receiver, AccountOperation::Deposit, LedgerEventKind::FundsTransferredIn { amount, from: transaction.client() })?);
 
-        match transaction_type {
-            TransactionType::Deposit => account.deposit(amount)?,
-            TransactionType::Withdrawal => account.withdrawal(amount)?,
+        Ok(Changes {
+            accounts: vec![sender, receiver],
+            record: Some(StoredTransaction::new(transaction.clone())),
+            events,
+            timestamp: transaction.timestamp(),
+        })
+    }
+
+    /// Prepares the conversion of available funds to another currency
+    ///
+    /// Conversions are saved like deposits and withdrawals, together with the converted
+    /// amount, but cannot be disputed.
+    fn prepare_conversion(&self, transaction: &Transaction) -> Result<Changes, TransactionError> {
+        self.check_unused(transaction.id())?;
+
+        let amount = transaction
+            .amount()
+            .ok_or(TransactionError::TransactionAmountNotSpecified)?;
+        let (from, to) = match transaction.target_currency() {
+            None => return Err(TransactionError::TargetCurrencyNotSpecified),
+            Some(to) if to == transaction.currency() => return Err(TransactionError::SameCurrency),
+            Some(to) => (transaction.currency(), to),
+        };
+        let rate = self.config.rates
+            .get(from, to)
+            .ok_or(TransactionError::RateNotFound)?;
+        let conversion = rate
+            .convert(amount, self.config.fx_rounding)
+            .ok_or(AccountError::Overflow)?;
+
+        let sequence = self.sequence + 1;
+        let mut account = self.account(transaction.client())?;
+        let mut events = vec![
+            LedgerEvent::new(
+                sequence,
+                transaction,
+                account.id(),
+                from,
+                LedgerEventKind::FundsConvertedOut { amount, to },
+                account.balances(from),
+                account.balances(from),
+            ),
+            LedgerEvent::new(
+                sequence,
+                transaction,
+                account.id(),
+                to,
+                LedgerEventKind::FundsConvertedIn { amount: conversion.amount, from, rate, fx_gain: conversion.fx_gain },
+                account.balances(to),
+                account.balances(to),
+            ),
+        ];
+        let postings = events
+            .iter()
+            .flat_map(|event| event.postings.iter().copied())
+            .collect::<Vec<_>>();
+        account.post(AccountOperation::Convert, &postings)?;
+        for event in &mut events {
+            event.after = account.balances(event.currency);
+        }
+
+        Ok(Changes {
+            accounts: vec![account],
+            record: Some(StoredTransaction::conversion(transaction.clone(), conversion.amount)),
+            events,
+            timestamp: transaction.timestamp(),
+        })
+    }
+
+    /// Prepares a dispute, resolve, or chargeback
+    ///
+    /// These transactions reference the deposit or withdrawal they are about. Their own
+    /// amount is optional, and limits them to a portion of the referenced amount.
+    fn prepare_dispute(&self, transaction: &Transaction, step: DisputeStep) -> Result<Changes, TransactionError> {
+        let mut record = match self.transactions.get(transaction.id())? {
+            Some(record) => record,
+            // evicted transactions weren't disputed when their dispute window passed
+            None if self.evicted_ids.contains(transaction.id()) => {
+                return Err(match step {
+                    DisputeStep::Dispute => TransactionError::DisputeWindowExpired,
+                    DisputeStep::Resolve | DisputeStep::Chargeback => TransactionError::UnknownDispute,
+                });
+            }
+            None => return Err(TransactionError::TransactionNotFound),
+        };
+        let referenced = record.transaction();
+        // clients may only dispute their own transactions
+        if referenced.client() != transaction.client() {
+            return Err(TransactionError::ClientMismatch);
+        }
+        // disputes without a timestamp happen at the current time of the engine
+        if step == DisputeStep::Dispute && self.window_passed(&record, transaction.timestamp().or(self.clock)) {
+            return Err(TransactionError::DisputeWindowExpired);
+        }
+        if referenced.transaction_type() == TransactionType::Transfer {
+            return self.prepare_transfer_dispute(transaction, step, record);
+        }
+        if referenced.amount().is_none() {
+            return Err(TransactionError::TransactionAmountNotSpecified);
+        }
+        // disputes always hold funds in the currency of the referenced transaction
+        let currency = referenced.currency();
+        let is_withdrawal = referenced.transaction_type() == TransactionType::Withdrawal;
+        let mut account = self.account(referenced.client())?;
+
+        let events = match step {
             // the specs state
             // > A dispute represents a client's claim that a transaction was erroneous and should be reversed.
             // [...]. This means that the clients available funds should decrease by the amount disputed, their
             // held funds should increase by the amount disputed, while their total funds should remain the same.
             //
-            // Since the specs don't say anything about disputing withdrawals / increasing funds, disputes
-            // are, for now, only allowed for deposits.
-            TransactionType::Dispute if transaction.transaction_type() != TransactionType::Deposit => {
+            // Disputed withdrawals work the other way around: the disputed amount is provisionally credited
+            // to the held funds. Resolving the dispute removes the credit again, while a chargeback returns
+            // the money to the available funds.
+            DisputeStep::Dispute if !self.config.disputable.contains(referenced.transaction_type()) => {
                 return Err(TransactionError::ImpossibleDispute);
             }
-            TransactionType::Dispute => {
-                self.disputes
-                    .insert(transaction.id())
-                    .then(|| ())
-                    .ok_or(TransactionError::DuplicateDispute)?;
-                account.hold_back(amount)?;
-            },
-            TransactionType::Resolve => {
-                self.disputes
-                    .remove(&transaction.id())
-                    .then(|| ())
-                    .ok_or(TransactionError::UnknownDispute)?;
-                account.set_free(amount)?;
-            },
-            TransactionType::Chargeback => {
-                self.disputes
-                    .remove(&transaction.id())
-                    .then(|| ())
-                    .ok_or(TransactionError::UnknownDispute)?;
-                account.charge_back(amount)?;
-
-                let id = transaction.id();
-                self.transactions.remove(&id);
-            },
-        }
+            DisputeStep::Dispute => {
+                let amount = record.dispute(transaction.amount(), self.config.redispute_policy, self.opening(transaction)?)?;
+                match is_withdrawal {
+                    false => self.apply(transaction, currency, &mut account, AccountOperation::HoldBack, LedgerEventKind::FundsHeld { amount })?,
+                    true => self.apply(transaction, currency, &mut account, AccountOperation::ProvisionallyCredit, LedgerEventKind::CreditProvisioned { amount })?,
+                }
+            }
+            DisputeStep::Resolve => {
+                let amount = record.resolve(transaction.amount())?;
+                match is_withdrawal {
+                    false => self.apply(transaction, currency, &mut account, AccountOperation::SetFree, LedgerEventKind::FundsReleased { amount })?,
+                    true => self.apply(transaction, currency, &mut account, AccountOperation::RevokeCredit, LedgerEventKind::CreditRevoked { amount })?,
+                }
+            }
+            DisputeStep::Chargeback => {
+                let amount = record.charge_back(transaction.amount())?;
+                match is_withdrawal {
+                    false => self.apply(transaction, currency, &mut account, AccountOperation::ChargeBack, LedgerEventKind::FundsChargedBack { amount })?,
+                    true => self.apply(transaction, currency, &mut account, AccountOperation::ReverseWithdrawal, LedgerEventKind::WithdrawalReversed { amount })?,
+                }
+            }
+        };
 
-        Ok(())
+        Ok(Changes { accounts: vec![account], record: Some(record), events, timestamp: transaction.timestamp() })
     }
 
-    fn save_transaction(&mut self, transaction: Transaction) -> Result<(), TransactionError> {
-        match transaction.transaction_type() {
-            TransactionType::Deposit | TransactionType::Withdrawal => {},
-            // we don't have to save other transaction types here, since they cannot
-            // be referenced later on
-            _ => return Ok(())
+    /// Prepares a dispute, resolve, or chargeback of a transfer
+    ///
+    /// The transfer is disputed as a unit: while the dispute is open, the destination holds
+    /// the transferred funds, and a chargeback returns them to the sender.
+    fn prepare_transfer_dispute(&self, transaction: &Transaction, step: DisputeStep, mut record: StoredTransaction) -> Result<Changes, TransactionError> {
+        let referenced = record.transaction();
+        if referenced.amount().is_none() {
+            return Err(TransactionError::TransactionAmountNotSpecified);
         }
+        let destination = referenced
+            .destination()
+            .ok_or(TransactionError::DestinationNotSpecified)?;
+        let currency = referenced.currency();
+        let mut sender = self.account(referenced.client())?;
+        let mut receiver = self.account(destination)?;
 
-        match self.transactions.entry(transaction.id()) {
-            Entry::Vacant(v) => {
-                v.insert(transaction);
-                Ok(())
+        let (accounts, events) = match step {
+            DisputeStep::Dispute => {
+                let amount = record.dispute(transaction.amount(), self.config.redispute_policy, self.opening(transaction)?)?;
+                let events = self.apply(transaction, currency, &mut receiver, AccountOperation::HoldBack, LedgerEventKind::FundsHeld { amount })?;
+                (vec![receiver], events)
+            }
+            DisputeStep::Resolve => {
+                let amount = record.resolve(transaction.amount())?;
+                let events = self.apply(transaction, currency, &mut receiver, AccountOperation::SetFree, LedgerEventKind::FundsReleased { amount })?;
+                (vec![receiver], events)
+            }
+            DisputeStep::Chargeback => {
+                let amount = record.charge_back(transaction.amount())?;
+                let mut events = self.apply(transaction, currency, &mut receiver, AccountOperation::ReverseTransfer, LedgerEventKind::TransferReversed { amount })?;
+                events.extend(self.apply(transaction, currency, &mut sender, AccountOperation::RefundTransfer, LedgerEventKind::TransferRefunded { amount })?);
+                (vec![receiver, sender], events)
             }
-            Entry::Occupied(_) => Err(TransactionError::DuplicateTransaction),
+        };
+
+        Ok(Changes { accounts, record: Some(record), events, timestamp: transaction.timestamp() })
+    }
+
+    /// Prepares an operator-issued unlock, freeze, or close
+    ///
+    /// Unlike other transactions, administrative transactions never create new accounts.
+    fn prepare_administrative(&self, transaction: &Transaction, administration: Administration) -> Result<Changes, TransactionError> {
+        let mut account = self
+            .stored_account(transaction.client())?
+            .ok_or(TransactionError::AccountNotFound)?;
+
+        let kind = match administration {
+            Administration::Unlock => {
+                account.unlock()?;
+                LedgerEventKind::AccountUnlocked
+            }
+            Administration::Freeze => {
+                let reason = transaction
+                    .reason()
+                    .ok_or(TransactionError::TransactionReasonNotSpecified)?;
+                account.freeze(reason.to_owned())?;
+                LedgerEventKind::AccountFrozen { reason: reason.to_owned() }
+            }
+            Administration::Close => return self.prepare_close(transaction, account),
+        };
+        // the status changes don't move any funds
+        let currency = transaction.currency();
+        let balances = account.balances(currency);
+        let events = vec![LedgerEvent::new(self.sequence + 1, transaction, account.id(), currency, kind, balances, balances)];
+
+        Ok(Changes { accounts: vec![account], record: None, events, timestamp: transaction.timestamp() })
+    }
+
+    /// Prepares the close of an account, which pays out the funds in every currency
+    ///
+    /// Every payout is described by its own [`LedgerEventKind::AccountClosed`] event. An
+    /// account without any funds is closed with a single event in the currency of the
+    /// transaction.
+    fn prepare_close(&self, transaction: &Transaction, mut account: Account) -> Result<Changes, TransactionError> {
+        let sequence = self.sequence + 1;
+        let before = account.clone();
+        let mut payouts = account.close()?;
+        if payouts.is_empty() {
+            payouts.push((transaction.currency(), Amount::ZERO));
         }
+
+        let events = payouts
+            .into_iter()
+            .map(|(currency, payout)| LedgerEvent::new(
+                sequence,
+                transaction,
+                account.id(),
+                currency,
+                LedgerEventKind::AccountClosed { payout },
+                before.balances(currency),
+                account.balances(currency),
+            ))
+            .collect();
+
+        Ok(Changes { accounts: vec![account], record: None, events, timestamp: transaction.timestamp() })
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::collections::HashMap;
+    /// Performs an operation on an account by posting the event it causes, and describes its
+    /// effects as ledger events
+    ///
+    /// The new funds of the account are derived from the postings of the event, see
+    /// [`Account::post`]. If the operation locks the account, an additional
+    /// [`LedgerEventKind::AccountLocked`] event is emitted.
+    fn apply(
+        &self,
+        transaction: &Transaction,
+        currency: Currency,
+        account: &mut Account,
+        operation: AccountOperation,
+        kind: LedgerEventKind,
+    ) -> Result<Vec<LedgerEvent>, TransactionError> {
+        let sequence = self.sequence + 1;
+        let before = account.balances(currency);
+        let was_locked = *account.status() == AccountStatus::Locked;
 
-    use super::*;
+        let mut event = LedgerEvent::new(sequence, transaction, account.id(), currency, kind, before, before);
+        account.post(operation, &event.postings)?;
+        let after = account.balances(currency);
+        event.after = after;
+        let mut events = vec![event];
+        if !was_locked && *account.status() == AccountStatus::Locked {
+            events.push(LedgerEvent::new(sequence, transaction, account.id(), currency, LedgerEventKind::AccountLocked, after, after));
+        }
 
-    macro_rules! engine_test {
-        (
-            $name:ident
-            $transactions:literal
-            $solution:literal
-        ) => {
-            #[test]
-            fn $name() {
-                let mut reader = csv::ReaderBuilder::new()
-                    .has_headers(true)
-                    .trim(csv::Trim::All)
-                    .from_reader($transactions.as_bytes());
-                let mut engine = TransactionEngine::new();
+        Ok(events)
+    }
 
-                for transaction in reader.deserialize() {
-                    let _ = engine.handle_transaction(transaction.unwrap());
-                }
+    /// The current state of an account, or a new empty account if it doesn't exist yet
+    fn account(&self, id: AccountId) -> Result<Account, TransactionError> {
+        let account = self.accounts
+            .get(id)?
+            .unwrap_or_else(|| Account::new(id));
 
-                let mut accounts = csv::ReaderBuilder::new()
-                    .has_headers(true)
-                    .trim(csv::Trim::All)
-                    .from_reader($solution.as_bytes());
-                let accounts = accounts
-                    .deserialize::<Account>()
-                    .map(Result::unwrap)
-                    .map(|account| (account.id(), account))
-                    .collect::<HashMap<_, _>>();
-                assert_eq!(
-                    engine.accounts(),
-                    &accounts,
-                );
+        self.with_ledger_funds(account)
+    }
+
+    /// The current state of an account, if it exists
+    fn stored_account(&self, id: AccountId) -> Result<Option<Account>, TransactionError> {
+        self.accounts
+            .get(id)?
+            .map(|account| self.with_ledger_funds(account))
+            .transpose()
+    }
+
+    /// The account with the funds the ledger recorded for the client
+    ///
+    /// The funds of an account are derived from its ledger accounts, so the copy in the
+    /// account store never decides about a transaction. Clients the ledger didn't record
+    /// yet keep their stored funds, which are opened in the ledger by their next transaction.
+    fn with_ledger_funds(&self, account: Account) -> Result<Account, TransactionError> {
+        let id = account.id();
+        if !self.ledger.contains_client(id) {
+            return Ok(account);
+        }
+
+        let funds = self.ledger
+            .client_currencies(id)
+            .into_iter()
+            // only a corrupted ledger holds client balances that aren't amounts
+            .map(|currency| Ok((currency, self.ledger.client_balances(id, currency).ok_or(AccountError::Overflow)?)))
+            .collect::<Result<BTreeMap<_, _>, AccountError>>()?;
+
+        Ok(Account::restore(id, funds, account.status().clone())?)
+    }
+
+    /// Applies a transaction that was accepted by [`TransactionEngine::prepare`]
+    ///
+    /// All checks already happened while preparing the transaction, so this can only fail
+    /// if the stores cannot be written. In that case, the writes that already happened are
+    /// undone, so the transaction is either applied completely or not at all. Only if the
+    /// stores cannot be written to undo them either, they are left partially written.
+    ///
+    /// The changed ledger balances are written to the account store together with the accounts.
+    fn commit(&mut self, Changes { accounts, record, events, timestamp }: Changes) -> Result<Vec<LedgerEvent>, StoreError> {
+        let clock = self.clock.max(timestamp);
+        // the record itself is evicted right away, once it's not disputed anymore
+        let expired = record.as_ref().is_some_and(|record| self.expired(record, clock));
+
+        let mut previous_accounts = Vec::new();
+        for account in &accounts {
+            previous_accounts.push((account.id(), self.accounts.get(account.id())?));
+        }
+        let previous_record = match &record {
+            Some(record) => Some((record.transaction().id(), self.transactions.get(record.transaction().id())?)),
+            None => None,
+        };
+        // clients the ledger didn't record yet are opened with the funds they had so far
+        let postings = previous_accounts
+            .iter()
+            .filter_map(|(_, existing)| existing.as_ref())
+            .filter(|existing| !self.ledger.contains_client(existing.id()))
+            .flat_map(Self::opening_postings)
+            .chain(events.iter().flat_map(|event| event.postings.iter().copied()))
+            .collect::<Vec<_>>();
+        let previous_lines = self.ledger.lines(&postings);
+        let mut changed = Ledger::from_lines(previous_lines.clone());
+        for posting in &postings {
+            changed.post(posting);
+        }
+        let lines = changed.trial_balance().lines;
+
+        // records whose dispute window passed are evicted together with the transaction
+        let mut evictions = Vec::new();
+        let written = self
+            .evict(clock, &mut evictions)
+            .and_then(|()| self.write(&accounts, record.as_ref().filter(|_| !expired), &lines, clock))
+            .and_then(|()| {
+                if let Some(record) = record.as_ref().filter(|_| expired) {
+                    evictions.push(self.evict_record(record)?);
+                }
+                Ok(())
+            });
+        if let Err(error) = written {
+            for eviction in evictions.into_iter().rev() {
+                self.undo_eviction(eviction);
             }
+            self.undo(previous_accounts, previous_record, &lines, previous_lines);
+            return Err(error);
+        }
+
+        if let Some(record) = record.as_ref().filter(|_| !expired) {
+            self.track_expiry(record);
+        }
+        self.sequence += 1;
+        self.clock = clock;
+
+        for posting in &postings {
+            self.ledger.post(posting);
+        }
+
+        Ok(events)
+    }
+
+    /// Writes the changed accounts, ledger balances, and clock of a transaction, and saves its record
+    fn write(&mut self, accounts: &[Account], record: Option<&StoredTransaction>, lines: &[LedgerLine], clock: Option<Timestamp>) -> Result<(), StoreError> {
+        if let Some(record) = record {
+            self.transactions.insert(record.clone())?;
+        }
+        for account in accounts {
+            self.accounts.insert(account.clone())?;
+        }
+        self.accounts.insert_ledger(lines)?;
+        if clock != self.clock {
+            self.transactions.set_clock(clock)?;
+        }
+
+        Ok(())
+    }
+
+    /// Restores the accounts, ledger balances, and the record of a transaction whose writes failed
+    ///
+    /// Failed writes might have been applied partially, so all of them are undone. Errors
+    /// are ignored, since the error of the failed write is the one that's reported.
+    fn undo(
+        &mut self,
+        accounts: Vec<(AccountId, Option<Account>)>,
+        record: Option<(TransactionId, Option<StoredTransaction>)>,
+        lines: &[LedgerLine],
+        previous_lines: Vec<LedgerLine>,
+    ) {
+        let keys = lines
+            .iter()
+            .map(|line| (line.account, line.currency))
+            .collect::<Vec<_>>();
+        let _ = self.accounts.remove_ledger(&keys);
+        let _ = self.accounts.insert_ledger(&previous_lines);
+        let _ = self.transactions.set_clock(self.clock);
+        for (id, account) in accounts {
+            let _ = match account {
+                Some(account) => self.accounts.insert(account),
+                None => self.accounts.remove(id),
+            };
+        }
+        if let Some((id, record)) = record {
+            let _ = match record {
+                Some(record) => self.transactions.insert(record),
+                None => self.transactions.remove(id),
+            };
+        }
+    }
+
+    /// When a dispute is opened by the transaction
+    ///
+    /// Disputes whose deadline can only pass by time are rejected while the time is unknown.
+    fn opening(&self, transaction: &Transaction) -> Result<DisputeOpening, TransactionError> {
+        let opening = DisputeOpening {
+            timestamp: transaction.timestamp().or(self.clock),
+            sequence: self.sequence + 1,
         };
+        match self.config.dispute_deadline {
+            Some(DisputeDeadline { after_transactions: None, .. }) if opening.timestamp.is_none() => {
+                Err(TransactionError::MissingTimestamp)
+            }
+            _ => Ok(opening),
+        }
     }
 
-    engine_test!(pdf_example
-        r#"type, client, tx, amount
-           deposit,   1,  1,    1.0
-           deposit,   2,  2,    2.0
-           deposit,   1,  3,    2.0
-           withdrawal,1,  4,    1.5
-           withdrawal,2,  5,    3.0"#
-        r#"client,available,held,total,locked
-                1,      1.5,   0,  1.5, false
-                2,        2,   0,    2, false"#
-    );
+    /// Whether the dispute window of a saved transaction has passed at the specified time
+    fn window_passed(&self, record: &StoredTransaction, now: Option<Timestamp>) -> bool {
+        match (self.config.dispute_window, record.transaction().timestamp(), now) {
+            (Some(window), Some(timestamp), Some(now)) => now.elapsed_since(timestamp) > window,
+            _ => false,
+        }
+    }
 
-    engine_test!(deposit
+    /// Whether a saved transaction can be evicted at the specified time
+    fn expired(&self, record: &StoredTransaction, now: Option<Timestamp>) -> bool {
+        !record.state().is_disputed() && self.window_passed(record, now)
+    }
+
+    /// Remembers when a saved transaction can be evicted, if there's a dispute window
+    fn track_expiry(&mut self, record: &StoredTransaction) {
+        if let (Some(_), Some(timestamp)) = (self.config.dispute_window, record.transaction().timestamp()) {
+            self.expiries.insert((timestamp, record.transaction().id()));
+        }
+    }
+
+    /// Removes the saved transactions whose dispute window has passed at the specified time
+    ///
+    /// Disputed transactions are kept until their dispute is resolved or charged back. The
+    /// funds of evicted transactions are kept as totals, so the invariants can still be
+    /// verified.
+    fn evict(&mut self, clock: Option<Timestamp>, evictions: &mut Vec<Eviction>) -> Result<(), StoreError> {
+        let (window, clock) = match (self.config.dispute_window, clock) {
+            (Some(window), Some(clock)) => (window, clock),
+            _ => return Ok(()),
+        };
+        let expired = self.expiries
+            .iter()
+            .take_while(|&&(timestamp, _)| clock.elapsed_since(timestamp) > window)
+            .copied()
+            .collect::<Vec<_>>();
+
+        for (timestamp, id) in expired {
+            match self.transactions.get(id)? {
+                Some(record) if record.state().is_disputed() => {}
+                Some(record) => evictions.push(self.evict_record(&record)?),
+                None => {
+                    self.expiries.remove(&(timestamp, id));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes the record of a transaction from the store, and adds its id and funds to the
+    /// evicted transactions
+    ///
+    /// If the eviction cannot be recorded, the record is saved again. Returns the eviction, so
+    /// it can be undone if the transaction that caused it fails.
+    fn evict_record(&mut self, record: &StoredTransaction) -> Result<Eviction, StoreError> {
+        let transaction = record.transaction();
+        let mut funds = BTreeMap::new();
+        for (key, effect) in invariants::effects(record) {
+            let total = funds.entry(key).or_insert_with(|| self.evicted.get(&key).copied().unwrap_or_default());
+            *total = LedgerBalance::from_raw(total.raw() + effect.total);
+        }
+        let range = self.evicted_ids.range_with(transaction.id());
+        let evicted = Evicted {
+            ids: vec![range],
+            funds: funds
+                .into_iter()
+                .map(|((client, currency), total)| EvictedFunds { client, currency, total })
+                .collect(),
+        };
+        let replaced = Evicted {
+            ids: self.evicted_ids.within(range),
+            funds: evicted.funds
+                .iter()
+                .filter_map(|funds| self.evicted
+                    .get(&(funds.client, funds.currency))
+                    .map(|&total| EvictedFunds { total, ..*funds }))
+                .collect(),
+        };
+
+        self.transactions.remove(transaction.id())?;
+        if let Err(error) = self.transactions.insert_evicted(&evicted) {
+            let _ = self.transactions.insert(record.clone());
+            return Err(error);
+        }
+
+        self.evicted_ids.insert(transaction.id());
+        for funds in &evicted.funds {
+            self.evicted.insert((funds.client, funds.currency), funds.total);
+        }
+        let tracked = transaction
+            .timestamp()
+            .is_some_and(|timestamp| self.expiries.remove(&(timestamp, transaction.id())));
+
+        Ok(Eviction { record: record.clone(), evicted, replaced, tracked })
+    }
+
+    /// Saves the record of an eviction again, and restores the evicted transactions it replaced
+    ///
+    /// Like [`TransactionEngine::undo`], errors are ignored.
+    fn undo_eviction(&mut self, Eviction { record, evicted, replaced, tracked }: Eviction) {
+        let _ = self.transactions.remove_evicted(&evicted);
+        let _ = self.transactions.insert_evicted(&replaced);
+        let _ = self.transactions.insert(record.clone());
+
+        for &range in &evicted.ids {
+            self.evicted_ids.restore(range, &replaced.ids);
+        }
+        for funds in &evicted.funds {
+            self.evicted.remove(&(funds.client, funds.currency));
+        }
+        for funds in &replaced.funds {
+            self.evicted.insert((funds.client, funds.currency), funds.total);
+        }
+        if let Some(timestamp) = record.transaction().timestamp().filter(|_| tracked) {
+            self.expiries.insert((timestamp, record.transaction().id()));
+        }
+    }
+
+    /// The postings that add a client with existing funds to the ledger
+    fn opening_postings(account: &Account) -> Vec<Posting> {
+        let client = account.id();
+        let mut postings = Vec::new();
+        for currency in account.currencies() {
+            let balances = account.balances(currency);
+            for (ledger_account, amount) in [(LedgerAccount::Available(client), balances.available), (LedgerAccount::Held(client), balances.held)] {
+                if !amount.is_zero() {
+                    postings.push(Posting::new(LedgerAccount::OpeningBalances, ledger_account, currency, amount));
+                }
+            }
+        }
+        postings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::testing::FailingAccounts;
+    use crate::{Balances, DeadlineAction};
+
+    macro_rules! config {
+        () => { EngineConfig::default() };
+        ($config:expr) => { $config };
+    }
+
+    macro_rules! engine_test {
+        (
+            $name:ident
+            $(with $config:expr;)?
+            $transactions:literal
+            $solution:literal
+        ) => {
+            #[test]
+            fn $name() {
+                let mut reader = csv::ReaderBuilder::new()
+                    .has_headers(true)
+                    .trim(csv::Trim::All)
+                    .from_reader($transactions.as_bytes());
+                let mut engine = TransactionEngine::with_config(config!($($config)?));
+
+                for transaction in reader.deserialize() {
+                    let _ = engine.handle_transaction(transaction.unwrap());
+                }
+
+                let mut accounts = csv::ReaderBuilder::new()
+                    .has_headers(true)
+                    .trim(csv::Trim::All)
+                    .from_reader($solution.as_bytes());
+                // every row holds the funds of a client in one currency
+                let mut expected = HashMap::<_, Account>::new();
+                for account in accounts.deserialize::<Account>().map(Result::unwrap) {
+                    match expected.get_mut(&account.id()) {
+                        Some(existing) => existing.merge(account),
+                        None => {
+                            expected.insert(account.id(), account);
+                        }
+                    }
+                }
+                assert_eq!(
+                    engine.accounts(),
+                    &expected,
+                );
+            }
+        };
+    }
+
+    /// Checks that the last transaction is rejected with the expected error, and
+    /// leaves the engine untouched
+    ///
+    /// All transactions before the last one have to be accepted.
+    macro_rules! rejection_test {
+        (
+            $name:ident
+            $(with $config:expr;)?
+            $transactions:literal
+            $error:pat
+        ) => {
+            #[test]
+            fn $name() {
+                let mut reader = csv::ReaderBuilder::new()
+                    .has_headers(true)
+                    .trim(csv::Trim::All)
+                    .from_reader($transactions.as_bytes());
+                let mut transactions = reader
+                    .deserialize::<Transaction>()
+                    .map(Result::unwrap)
+                    .collect::<Vec<_>>();
+                let rejected = transactions.pop().unwrap();
+                let mut engine = TransactionEngine::with_config(config!($($config)?));
+
+                for transaction in transactions {
+                    engine.handle_transaction(transaction).unwrap();
+                }
+
+                let before = engine.clone();
+                let result = engine.handle_transaction(rejected);
+                assert!(matches!(result, Err($error)), "{:?}", result);
+                assert_eq!(engine, before);
+            }
+        };
+    }
+
+    engine_test!(pdf_example
+        r#"type, client, tx, amount
+           deposit,   1,  1,    1.0
+           deposit,   2,  2,    2.0
+           deposit,   1,  3,    2.0
+           withdrawal,1,  4,    1.5
+           withdrawal,2,  5,    3.0"#
+        r#"client,available,held,total,locked
+                1,      1.5,   0,  1.5, false
+                2,        2,   0,    2, false"#
+    );
+
+    engine_test!(deposit
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   1,  2,     20"#
+        r#"client,available,held,total,locked
+                1,       70,   0,   70, false"#
+    );
+    engine_test!(duplicate_transaction
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   1,  1,     20"#
+        r#"client,available,held,total,locked
+                1,       50,   0,   50, false"#
+    );
+    engine_test!(withdrawal
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   1,  2,     20
+           withdrawal,1,  3,     30
+           withdrawal,1,  4,     10"#
+        r#"client,available,held,total,locked
+                1,       30,   0,   30, false"#
+    );
+    engine_test!(withdrawal_underflow
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   1,  2,     20
+           withdrawal,1,  3,     80"#
+        r#"client,available,held,total,locked
+                1,       70,   0,   70, false"#
+    );
+    engine_test!(dispute
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   1,  2,     20
+           dispute,   1,  1,     "#
+        r#"client,available,held,total,locked
+                1,       20,  50,   70, false"#
+    );
+    engine_test!(duplicate_dispute
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   1,  2,     20
+           dispute,   1,  1,
+           dispute,   1,  1,       "#
+        r#"client,available,held,total,locked
+                1,       20,  50,   70, false"#
+    );
+    engine_test!(dispute_underflow
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   1,  2,     20
+           withdrawal,1,  3,     60
+           dispute,   1,  1,       "#
+        r#"client,available,held,total,locked
+                1,       10,   0,   10, false"#
+    );
+    engine_test!(dispute_unknown
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   1,  2,     20
+           dispute,   1, 42,       "#
+        r#"client,available,held,total,locked
+                1,       70,   0,   70, false"#
+    );
+    engine_test!(resolve
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   1,  2,     20
+           dispute,   1,  1,
+           resolve,   1,  1,       "#
+        r#"client,available,held,total,locked
+                1,       70,   0,   70, false"#
+    );
+    engine_test!(resolve_unknown
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   1,  2,     20
+           dispute,   1,  1,
+           resolve,   1, 42,       "#
+        r#"client,available,held,total,locked
+                1,       20,  50,   70, false"#
+    );
+    engine_test!(duplicate_resolve
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   1,  2,     20
+           dispute,   1,  1,
+           resolve,   1,  1,
+           resolve,   1,  1,       "#
+        r#"client,available,held,total,locked
+                1,       70,  0,   70, false"#
+    );
+    engine_test!(chargeback
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   1,  2,     20
+           dispute,   1,  1,
+           chargeback,1,  1,       "#
+        r#"client,available,held,total,locked
+                1,       20,  0,   20, true"#
+    );
+    engine_test!(duplicate_chargeback
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   1,  2,     20
+           dispute,   1,  1,
+           chargeback,1,  1,
+           dispute,   1,  1,
+           chargeback,1,  1,       "#
+        r#"client,available,held,total,locked
+                1,       20,  0,   20, true"#
+    );
+    engine_test!(deposit_after_chargeback
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   1,  2,     20
+           dispute,   1,  1,
+           chargeback,1,  1,
+           deposit,   1,  3,     10"#
+        r#"client,available,held,total,locked
+                1,       20,  0,   20, true"#
+    );
+    engine_test!(withdrawal_after_chargeback
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   1,  2,     20
+           dispute,   1,  1,
+           chargeback,1,  1,
+           withdrawal,1,  3,     10"#
+        r#"client,available,held,total,locked
+                1,       20,  0,   20, true"#
+    );
+
+    engine_test!(deposit_overflow
+        r#"type, client, tx, amount
+           deposit,   1,  1, 1844674407370955.1615
+           deposit,   1,  2,    1
+           dispute,   1,  2,       "#
+        r#"client,available,held,total,locked
+                1, 1844674407370955.1615,   0, 1844674407370955.1615, false"#
+    );
+    engine_test!(overflow_is_not_saved
+        r#"type, client, tx, amount
+           deposit,   1,  1,    1
+           deposit,   1,  2, 1844674407370955.1615
+           deposit,   1,  2,    5"#
+        r#"client,available,held,total,locked
+                1,        6,   0,    6, false"#
+    );
+
+    rejection_test!(reject_deposit_on_locked
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           dispute,   1,  1,
+           chargeback,1,  1,
+           deposit,   1,  2,     10"#
+        TransactionError::Account(AccountError::Locked)
+    );
+    rejection_test!(reject_withdrawal_on_locked
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   1,  2,     50
+           dispute,   1,  1,
+           chargeback,1,  1,
+           withdrawal,1,  3,     10"#
+        TransactionError::Account(AccountError::Locked)
+    );
+    rejection_test!(reject_withdrawal_insufficient_funds
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           withdrawal,1,  2,     60"#
+        TransactionError::Account(AccountError::InsufficientFunds)
+    );
+    rejection_test!(reject_withdrawal_on_new_account
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           withdrawal,2,  2,     10"#
+        TransactionError::Account(AccountError::InsufficientFunds)
+    );
+    rejection_test!(reject_dispute_insufficient_funds
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           withdrawal,1,  2,     20
+           dispute,   1,  1,       "#
+        TransactionError::Account(AccountError::InsufficientFunds)
+    );
+    rejection_test!(reject_deposit_overflow
+        r#"type, client, tx, amount
+           deposit,   1,  1, 1844674407370955.1615
+           deposit,   1,  2,    1"#
+        TransactionError::Account(AccountError::Overflow)
+    );
+    rejection_test!(reject_dispute_unknown_transaction
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           dispute,   1,  2,       "#
+        TransactionError::TransactionNotFound
+    );
+    rejection_test!(reject_resolve_unknown_transaction
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           resolve,   1,  2,       "#
+        TransactionError::TransactionNotFound
+    );
+    rejection_test!(reject_chargeback_unknown_transaction
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           chargeback,1,  2,       "#
+        TransactionError::TransactionNotFound
+    );
+    rejection_test!(reject_deposit_without_amount
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   2,  2,       "#
+        TransactionError::TransactionAmountNotSpecified
+    );
+    rejection_test!(reject_withdrawal_without_amount
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           withdrawal,1,  2,       "#
+        TransactionError::TransactionAmountNotSpecified
+    );
+    rejection_test!(reject_duplicate_dispute
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           dispute,   1,  1,
+           dispute,   1,  1,       "#
+        TransactionError::DuplicateDispute
+    );
+    rejection_test!(reject_resolve_without_dispute
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           resolve,   1,  1,       "#
+        TransactionError::UnknownDispute
+    );
+    rejection_test!(reject_chargeback_without_dispute
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           chargeback,1,  1,       "#
+        TransactionError::UnknownDispute
+    );
+    rejection_test!(reject_duplicate_deposit
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   2,  1,     50"#
+        TransactionError::DuplicateTransaction
+    );
+    rejection_test!(reject_duplicate_withdrawal
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           withdrawal,1,  1,     10"#
+        TransactionError::DuplicateTransaction
+    );
+    rejection_test!(reject_dispute_of_withdrawal
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           withdrawal,1,  2,     10
+           dispute,   1,  2,       "#
+        TransactionError::ImpossibleDispute
+    );
+    rejection_test!(reject_dispute_of_other_client
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   2,  2,     50
+           dispute,   2,  1,       "#
+        TransactionError::ClientMismatch
+    );
+    rejection_test!(reject_resolve_of_other_client
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   2,  2,     50
+           dispute,   1,  1,
+           resolve,   2,  1,       "#
+        TransactionError::ClientMismatch
+    );
+    rejection_test!(reject_chargeback_of_other_client
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   2,  2,     50
+           dispute,   1,  1,
+           chargeback,2,  1,       "#
+        TransactionError::ClientMismatch
+    );
+    rejection_test!(reject_dispute_of_unknown_client
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           dispute,   2,  1,       "#
+        TransactionError::ClientMismatch
+    );
+
+    engine_test!(cross_client_dispute
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   2,  2,     20
+           dispute,   2,  1,
+           chargeback,2,  1,
+           dispute,   1,  2,       "#
+        r#"client,available,held,total,locked
+                1,       50,   0,   50, false
+                2,       20,   0,   20, false"#
+    );
+
+    rejection_test!(reject_redispute_after_resolve
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           dispute,   1,  1,
+           resolve,   1,  1,
+           dispute,   1,  1,       "#
+        TransactionError::RedisputeNotAllowed
+    );
+    rejection_test!(reject_redispute_over_limit
+        with EngineConfig { redispute_policy: RedisputePolicy::Limited(2), ..EngineConfig::default() };
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           dispute,   1,  1,
+           resolve,   1,  1,
+           dispute,   1,  1,
+           resolve,   1,  1,
+           dispute,   1,  1,       "#
+        TransactionError::RedisputeNotAllowed
+    );
+    rejection_test!(reject_dispute_after_chargeback
+        with EngineConfig { redispute_policy: RedisputePolicy::Unlimited, ..EngineConfig::default() };
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   1,  2,     50
+           dispute,   1,  1,
+           chargeback,1,  1,
+           dispute,   1,  1,       "#
+        TransactionError::ChargedBack
+    );
+    rejection_test!(reject_resolve_after_resolve
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           dispute,   1,  1,
+           resolve,   1,  1,
+           resolve,   1,  1,       "#
+        TransactionError::UnknownDispute
+    );
+
+    engine_test!(redispute_unlimited
+        with EngineConfig { redispute_policy: RedisputePolicy::Unlimited, ..EngineConfig::default() };
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   1,  2,     20
+           dispute,   1,  1,
+           resolve,   1,  1,
+           dispute,   1,  1,
+           resolve,   1,  1,
+           dispute,   1,  1,       "#
+        r#"client,available,held,total,locked
+                1,       20,  50,   70, false"#
+    );
+
+    #[test]
+    fn transaction_state_lifecycle() {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(r#"type, client, tx, amount
+                            deposit,   1,  1,     50
+                            deposit,   1,  2,     50
+                            dispute,   1,  1,
+                            resolve,   1,  1,
+                            dispute,   1,  2,
+                            chargeback,1,  2,       "#.as_bytes());
+        let transactions = reader
+            .deserialize::<Transaction>()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        let expected_states = [
+            TransactionState::Settled,
+            TransactionState::Settled,
+            TransactionState::Disputed,
+            TransactionState::Resolved,
+            TransactionState::Disputed,
+            TransactionState::ChargedBack,
+        ];
+        let first = transactions[0].id();
+        let mut engine = TransactionEngine::new();
+
+        for (transaction, state) in transactions.into_iter().zip(expected_states) {
+            let id = transaction.id();
+            engine.handle_transaction(transaction).unwrap();
+            assert_eq!(engine.transaction_state(id).unwrap(), Some(state));
+        }
+
+        assert_eq!(engine.transaction(first).unwrap().unwrap().disputes(), 1);
+    }
+
+    engine_test!(withdrawal_dispute
+        with EngineConfig { disputable: DisputableTransactions::DepositsAndWithdrawals, ..EngineConfig::default() };
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           withdrawal,1,  2,     20
+           dispute,   1,  2,       "#
+        r#"client,available,held,total,locked
+                1,       30,  20,   50, false"#
+    );
+    engine_test!(withdrawal_dispute_resolve
+        with EngineConfig { disputable: DisputableTransactions::DepositsAndWithdrawals, ..EngineConfig::default() };
         r#"type, client, tx, amount
            deposit,   1,  1,     50
-           deposit,   1,  2,     20"#
+           withdrawal,1,  2,     20
+           dispute,   1,  2,
+           resolve,   1,  2,       "#
         r#"client,available,held,total,locked
-                1,       70,   0,   70, false"#
+                1,       30,   0,   30, false"#
     );
-    engine_test!(duplicate_transaction
+    engine_test!(withdrawal_dispute_chargeback
+        with EngineConfig { disputable: DisputableTransactions::DepositsAndWithdrawals, ..EngineConfig::default() };
         r#"type, client, tx, amount
            deposit,   1,  1,     50
-           deposit,   1,  1,     20"#
+           withdrawal,1,  2,     20
+           dispute,   1,  2,
+           chargeback,1,  2,
+           withdrawal,1,  3,     50"#
         r#"client,available,held,total,locked
-                1,       50,   0,   50, false"#
+                1,        0,   0,    0, false"#
     );
-    engine_test!(withdrawal
+    engine_test!(withdrawal_dispute_with_deposit_dispute
+        with EngineConfig { disputable: DisputableTransactions::DepositsAndWithdrawals, ..EngineConfig::default() };
         r#"type, client, tx, amount
            deposit,   1,  1,     50
-           deposit,   1,  2,     20
-           withdrawal,1,  3,     30
-           withdrawal,1,  4,     10"#
+           deposit,   1,  3,     50
+           withdrawal,1,  2,     20
+           dispute,   1,  2,
+           dispute,   1,  1,
+           chargeback,1,  1,       "#
         r#"client,available,held,total,locked
-                1,       30,   0,   30, false"#
+                1,       30,  20,   50, true"#
     );
-    engine_test!(withdrawal_underflow
+
+    engine_test!(resolve_on_locked
         r#"type, client, tx, amount
            deposit,   1,  1,     50
            deposit,   1,  2,     20
-           withdrawal,1,  3,     80"#
+           dispute,   1,  1,
+           dispute,   1,  2,
+           chargeback,1,  1,
+           resolve,   1,  2,       "#
         r#"client,available,held,total,locked
-                1,       70,   0,   70, false"#
+                1,       20,   0,   20, true"#
     );
-    engine_test!(dispute
+    engine_test!(chargeback_on_locked
         r#"type, client, tx, amount
            deposit,   1,  1,     50
            deposit,   1,  2,     20
-           dispute,   1,  1,     "#
+           dispute,   1,  1,
+           dispute,   1,  2,
+           chargeback,1,  1,
+           chargeback,1,  2,       "#
         r#"client,available,held,total,locked
-                1,       20,  50,   70, false"#
+                1,        0,   0,    0, true"#
     );
-    engine_test!(duplicate_dispute
+    rejection_test!(reject_dispute_on_locked
         r#"type, client, tx, amount
            deposit,   1,  1,     50
            deposit,   1,  2,     20
            dispute,   1,  1,
-           dispute,   1,  1,       "#
-        r#"client,available,held,total,locked
-                1,       20,  50,   70, false"#
+           chargeback,1,  1,
+           dispute,   1,  2,       "#
+        TransactionError::Account(AccountError::Locked)
     );
-    engine_test!(dispute_underflow
+
+    engine_test!(unlock_after_chargeback
         r#"type, client, tx, amount
            deposit,   1,  1,     50
            deposit,   1,  2,     20
-           withdrawal,1,  3,     60
+           dispute,   1,  1,
+           chargeback,1,  1,
+           unlock,    1,  3,
+           deposit,   1,  4,     10"#
+        r#"client,available,held,total,locked,status
+                1,       30,   0,   30, false, active"#
+    );
+    engine_test!(freeze
+        r#"type, client, tx, amount, reason
+           deposit,   1,  1,     50,
+           deposit,   1,  2,     20,
+           dispute,   1,  1,       ,
+           freeze,    1,  3,       , fraud investigation
+           deposit,   1,  4,     10,
+           resolve,   1,  1,       ,"#
+        r#"client,available,held,total,locked,status,reason
+                1,       70,   0,   70, true, frozen, fraud investigation"#
+    );
+    engine_test!(chargeback_on_frozen
+        r#"type, client, tx, amount, reason
+           deposit,   1,  1,     50,
+           deposit,   1,  2,     20,
+           dispute,   1,  1,       ,
+           freeze,    1,  3,       , aml review
+           chargeback,1,  1,       ,"#
+        r#"client,available,held,total,locked,status,reason
+                1,       20,   0,   20, true, frozen, aml review"#
+    );
+    engine_test!(unfreeze
+        r#"type, client, tx, amount, reason
+           deposit,   1,  1,     50,
+           freeze,    1,  2,       , fraud investigation
+           unlock,    1,  3,       ,
+           withdrawal,1,  4,     10,"#
+        r#"client,available,held,total,locked,status
+                1,       40,   0,   40, false, active"#
+    );
+    engine_test!(close
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           deposit,   2,  2,     20
+           close,     1,  3,
+           deposit,   1,  4,     10"#
+        r#"client,available,held,total,locked,status
+                1,        0,   0,    0, true, closed
+                2,       20,   0,   20, false, active"#
+    );
+    rejection_test!(reject_freeze_without_reason
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           freeze,    1,  2,       "#
+        TransactionError::TransactionReasonNotSpecified
+    );
+    rejection_test!(reject_unlock_of_active
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           unlock,    1,  2,       "#
+        TransactionError::Account(AccountError::NotLocked)
+    );
+    rejection_test!(reject_unlock_of_unknown_account
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           unlock,    2,  2,       "#
+        TransactionError::AccountNotFound
+    );
+    rejection_test!(reject_close_with_open_dispute
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           dispute,   1,  1,
+           close,     1,  2,       "#
+        TransactionError::Account(AccountError::FundsHeld)
+    );
+    rejection_test!(reject_dispute_on_closed
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           close,     1,  2,
            dispute,   1,  1,       "#
+        TransactionError::Account(AccountError::Closed)
+    );
+    engine_test!(currencies
+        r#"type, client, tx, amount, currency
+           deposit,   1,  1,     50, EUR
+           deposit,   1,  2,     20, gbp
+           deposit,   1,  3,     10,
+           withdrawal,1,  4,      5, EUR
+           deposit,   2,  5,     30, GBP"#
+        r#"client,currency,available,held,total,locked
+                1,     EUR,       45,   0,   45, false
+                1,     GBP,       20,   0,   20, false
+                1,     USD,       10,   0,   10, false
+                2,     GBP,       30,   0,   30, false"#
+    );
+    engine_test!(dispute_holds_original_currency
+        r#"type, client, tx, amount, currency
+           deposit,   1,  1,     50, EUR
+           deposit,   1,  2,     20, GBP
+           dispute,   1,  1,       ,
+           dispute,   1,  2,       , EUR
+           resolve,   1,  2,       ,"#
+        r#"client,currency,available,held,total,locked
+                1,     EUR,        0,  50,   50, false
+                1,     GBP,       20,   0,   20, false"#
+    );
+    engine_test!(close_pays_out_all_currencies
+        r#"type, client, tx, amount, currency
+           deposit,   1,  1,     50, EUR
+           deposit,   1,  2,     20, GBP
+           close,     1,  3,       ,"#
+        r#"client,currency,available,held,total,locked,status
+                1,     EUR,        0,   0,    0, true, closed
+                1,     GBP,        0,   0,    0, true, closed"#
+    );
+    rejection_test!(reject_withdrawal_in_other_currency
+        r#"type, client, tx, amount, currency
+           deposit,   1,  1,     50, EUR
+           withdrawal,1,  2,     10, GBP"#
+        TransactionError::Account(AccountError::InsufficientFunds)
+    );
+    rejection_test!(reject_close_with_dispute_in_other_currency
+        r#"type, client, tx, amount, currency
+           deposit,   1,  1,     50, EUR
+           deposit,   1,  2,     20, GBP
+           dispute,   1,  2,       ,
+           close,     1,  3,       ,"#
+        TransactionError::Account(AccountError::FundsHeld)
+    );
+
+    engine_test!(conversion
+        with fx_config();
+        r#"type, client, tx, amount, currency, target_currency
+           deposit,   1,  1,    100,      EUR,
+           convert,   1,  2,     40,      EUR, USD
+           convert,   1,  3,     10,      USD, eur"#
+        r#"client,currency,available,held,total,locked
+                1,     EUR,    69.22,   0,69.22, false
+                1,     USD,     33.4,   0, 33.4, false"#
+    );
+    rejection_test!(reject_conversion_without_rate
+        with fx_config();
+        r#"type, client, tx, amount, currency, target_currency
+           deposit,   1,  1,    100,      EUR,
+           convert,   1,  2,     40,      EUR, GBP"#
+        TransactionError::RateNotFound
+    );
+    rejection_test!(reject_conversion_without_target
+        with fx_config();
+        r#"type, client, tx, amount, currency, target_currency
+           deposit,   1,  1,    100,      EUR,
+           convert,   1,  2,     40,      EUR,"#
+        TransactionError::TargetCurrencyNotSpecified
+    );
+    rejection_test!(reject_conversion_to_same_currency
+        with fx_config();
+        r#"type, client, tx, amount, currency, target_currency
+           deposit,   1,  1,    100,      EUR,
+           convert,   1,  2,     40,      EUR, EUR"#
+        TransactionError::SameCurrency
+    );
+    rejection_test!(reject_conversion_insufficient_funds
+        with fx_config();
+        r#"type, client, tx, amount, currency, target_currency
+           deposit,   1,  1,    100,      EUR,
+           convert,   1,  2,    150,      EUR, USD"#
+        TransactionError::Account(AccountError::InsufficientFunds)
+    );
+    rejection_test!(reject_dispute_of_conversion
+        with fx_config();
+        r#"type, client, tx, amount, currency, target_currency
+           deposit,   1,  1,    100,      EUR,
+           convert,   1,  2,     40,      EUR, USD
+           dispute,   1,  2,       ,         ,"#
+        TransactionError::ImpossibleDispute
+    );
+
+    #[test]
+    fn conversion_records_fx_gains_and_losses() {
+        let engine = engine_with(fx_config(), r#"type, client, tx, amount, currency, target_currency
+                                                 deposit,   1,  1,     20,      USD,
+                                                 convert,   1,  2,     10,      USD, EUR
+                                                 convert,   1,  3,  1.005,      EUR, USD"#);
+
+        // 10 USD are 9.2166 EUR, rounded up to 9.22 EUR, and 1.005 EUR are 1.0904 USD,
+        // rounded down to 1.09 USD
+        let balance = |account, currency| engine.ledger().balance(account, currency).to_string();
+        assert!(engine.ledger().trial_balance().is_balanced());
+        assert_eq!(balance(LedgerAccount::FxGainsLosses, Currency::EUR), "-0.0034");
+        assert_eq!(balance(LedgerAccount::FxGainsLosses, Currency::USD), "0.0004");
+        assert_eq!(balance(LedgerAccount::FxPositions, Currency::EUR), "-8.2116");
+        assert_eq!(balance(LedgerAccount::FxPositions, Currency::USD), "8.9096");
+        assert_eq!(engine.transaction(TransactionId::new(2)).unwrap().unwrap().converted(), Some("9.22".parse().unwrap()));
+        assert_eq!(engine.verify_invariants().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn conversion_events() {
+        let events = last_events(fx_config(), r#"type, client, tx, amount, currency, target_currency
+                                                 deposit,   1,  1,     50,      EUR,
+                                                 convert,   1,  2,     10,      EUR, USD"#);
+        let rate = "1.085".parse().unwrap();
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].currency, Currency::EUR);
+        assert_eq!(events[0].kind, LedgerEventKind::FundsConvertedOut { amount: Amount::from(10), to: Currency::USD });
+        assert_eq!(events[0].after, balances(40, 0));
+        assert_eq!(events[1].currency, Currency::USD);
+        assert_eq!(
+            events[1].kind,
+            LedgerEventKind::FundsConvertedIn { amount: "10.85".parse().unwrap(), from: Currency::EUR, rate, fx_gain: LedgerBalance::ZERO },
+        );
+        assert_eq!(events[1].before, balances(0, 0));
+    }
+
+    engine_test!(transfer
+        r#"type, client, tx, amount, destination
+           deposit,   1,  1,     50,
+           deposit,   2,  2,     10,
+           transfer,  1,  3,     30, 2"#
         r#"client,available,held,total,locked
-                1,       10,   0,   10, false"#
+                1,       20,   0,   20, false
+                2,       40,   0,   40, false"#
     );
-    engine_test!(dispute_unknown
+    engine_test!(transfer_dispute
+        r#"type, client, tx, amount, destination
+           deposit,   1,  1,     50,
+           deposit,   2,  2,     10,
+           transfer,  1,  3,     30, 2
+           dispute,   1,  3,       ,"#
+        r#"client,available,held,total,locked
+                1,       20,   0,   20, false
+                2,       10,  30,   40, false"#
+    );
+    engine_test!(transfer_resolve
+        r#"type, client, tx, amount, destination
+           deposit,   1,  1,     50,
+           deposit,   2,  2,     10,
+           transfer,  1,  3,     30, 2
+           dispute,   1,  3,       ,
+           resolve,   1,  3,       ,"#
+        r#"client,available,held,total,locked
+                1,       20,   0,   20, false
+                2,       40,   0,   40, false"#
+    );
+    engine_test!(transfer_chargeback
+        r#"type, client, tx, amount, destination
+           deposit,   2,  1,     10,
+           deposit,   1,  2,     50,
+           transfer,  1,  3,     30, 2
+           dispute,   1,  3,       ,
+           freeze,    1,  4,       ,
+           chargeback,1,  3,       ,"#
+        r#"client,available,held,total,locked
+                1,       50,   0,   50, false
+                2,       10,   0,   10, false"#
+    );
+    rejection_test!(reject_transfer_to_unknown_client
+        r#"type, client, tx, amount, destination
+           deposit,   1,  1,     50,
+           transfer,  1,  2,     30, 2"#
+        TransactionError::AccountNotFound
+    );
+    rejection_test!(reject_transfer_to_self
+        r#"type, client, tx, amount, destination
+           deposit,   1,  1,     50,
+           transfer,  1,  2,     30, 1"#
+        TransactionError::SelfTransfer
+    );
+    rejection_test!(reject_transfer_without_destination
+        r#"type, client, tx, amount, destination
+           deposit,   1,  1,     50,
+           transfer,  1,  2,     30,"#
+        TransactionError::DestinationNotSpecified
+    );
+    rejection_test!(reject_transfer_insufficient_funds
+        r#"type, client, tx, amount, destination
+           deposit,   1,  1,     50,
+           deposit,   2,  2,     10,
+           transfer,  1,  3,     60, 2"#
+        TransactionError::Account(AccountError::InsufficientFunds)
+    );
+    rejection_test!(reject_transfer_from_locked
+        r#"type, client, tx, amount, destination
+           deposit,   1,  1,     50,
+           deposit,   1,  2,     10,
+           deposit,   2,  3,     10,
+           dispute,   1,  2,       ,
+           chargeback,1,  2,       ,
+           transfer,  1,  4,     30, 2"#
+        TransactionError::Account(AccountError::Locked)
+    );
+    rejection_test!(reject_transfer_to_frozen
+        r#"type, client, tx, amount, destination, reason
+           deposit,   1,  1,     50,            ,
+           deposit,   2,  2,     10,            ,
+           freeze,    2,  3,       ,            , fraud
+           transfer,  1,  4,     30,           2,"#
+        TransactionError::Account(AccountError::Frozen)
+    );
+    rejection_test!(reject_transfer_dispute_by_destination
+        r#"type, client, tx, amount, destination
+           deposit,   1,  1,     50,
+           deposit,   2,  2,     10,
+           transfer,  1,  3,     30, 2
+           dispute,   2,  3,       ,"#
+        TransactionError::ClientMismatch
+    );
+    rejection_test!(reject_transfer_dispute_of_spent_funds
+        r#"type, client, tx, amount, destination
+           deposit,   1,  1,     50,
+           deposit,   2,  2,     10,
+           transfer,  1,  3,     30, 2
+           withdrawal,2,  4,     35,
+           dispute,   1,  3,       ,"#
+        TransactionError::Account(AccountError::InsufficientFunds)
+    );
+
+    #[test]
+    fn transfer_chargeback_events() {
+        let mut engine = TransactionEngine::new();
+        let events = process_all(&mut engine, r#"type, client, tx, amount, destination
+                                                 deposit,   1,  1,     50,
+                                                 deposit,   2,  2,     10,
+                                                 transfer,  1,  3,     30, 2
+                                                 dispute,   1,  3,       ,
+                                                 chargeback,1,  3,       ,"#);
+        let summary = |events: &[LedgerEvent]| events
+            .iter()
+            .map(|event| (event.client, event.kind.clone(), event.after))
+            .collect::<Vec<_>>();
+        let (sender, receiver) = (AccountId::new(1), AccountId::new(2));
+        let amount = Amount::from(30);
+
+        assert_eq!(summary(&events[2]), [
+            (sender, LedgerEventKind::FundsTransferredOut { amount, to: receiver }, balances(20, 0)),
+            (receiver, LedgerEventKind::FundsTransferredIn { amount, from: sender }, balances(40, 0)),
+        ]);
+        assert_eq!(summary(&events[4]), [
+            (receiver, LedgerEventKind::TransferReversed { amount }, balances(10, 0)),
+            (sender, LedgerEventKind::TransferRefunded { amount }, balances(50, 0)),
+        ]);
+        assert_eq!(engine.ledger().balance(LedgerAccount::Transfers, Currency::USD), LedgerBalance::ZERO);
+        assert_eq!(engine.ledger().balance(LedgerAccount::Cash, Currency::USD).to_string(), "-60");
+        assert_eq!(engine.verify_invariants().unwrap(), vec![]);
+    }
+
+    engine_test!(partial_dispute
         r#"type, client, tx, amount
            deposit,   1,  1,     50
-           deposit,   1,  2,     20
-           dispute,   1, 42,       "#
+           dispute,   1,  1,     20"#
         r#"client,available,held,total,locked
-                1,       70,   0,   70, false"#
+                1,       30,  20,   50, false"#
     );
-    engine_test!(resolve
+    engine_test!(multiple_partial_disputes
         r#"type, client, tx, amount
            deposit,   1,  1,     50
-           deposit,   1,  2,     20
-           dispute,   1,  1,
-           resolve,   1,  1,       "#
+           dispute,   1,  1,     20
+           dispute,   1,  1,     10
+           resolve,   1,  1,      5"#
         r#"client,available,held,total,locked
-                1,       70,   0,   70, false"#
+                1,       25,  25,   50, false"#
     );
-    engine_test!(resolve_unknown
+    engine_test!(partial_dispute_of_rest
         r#"type, client, tx, amount
            deposit,   1,  1,     50
-           deposit,   1,  2,     20
-           dispute,   1,  1,
-           resolve,   1, 42,       "#
+           dispute,   1,  1,     20
+           dispute,   1,  1,       "#
         r#"client,available,held,total,locked
-                1,       20,  50,   70, false"#
+                1,        0,  50,   50, false"#
     );
-    engine_test!(duplicate_resolve
+    engine_test!(partial_chargeback
         r#"type, client, tx, amount
            deposit,   1,  1,     50
-           deposit,   1,  2,     20
-           dispute,   1,  1,
-           resolve,   1,  1,
+           dispute,   1,  1,     20
+           chargeback,1,  1,     15
            resolve,   1,  1,       "#
         r#"client,available,held,total,locked
-                1,       70,  0,   70, false"#
+                1,       35,   0,   35, true"#
     );
-    engine_test!(chargeback
+    engine_test!(partial_withdrawal_dispute
+        with EngineConfig { disputable: DisputableTransactions::DepositsAndWithdrawals, ..EngineConfig::default() };
         r#"type, client, tx, amount
            deposit,   1,  1,     50
-           deposit,   1,  2,     20
-           dispute,   1,  1,
-           chargeback,1,  1,       "#
+           withdrawal,1,  2,     30
+           dispute,   1,  2,     10
+           chargeback,1,  2,      4"#
         r#"client,available,held,total,locked
-                1,       20,  0,   20, true"#
+                1,       24,   6,   30, false"#
     );
-    engine_test!(duplicate_chargeback
+    engine_test!(partial_transfer_dispute
+        r#"type, client, tx, amount, destination
+           deposit,   1,  1,     50,
+           deposit,   2,  2,     10,
+           transfer,  1,  3,     30, 2
+           dispute,   1,  3,     10,
+           chargeback,1,  3,      5,"#
+        r#"client,available,held,total,locked
+                1,       25,   0,   25, false
+                2,       30,   5,   35, false"#
+    );
+    rejection_test!(reject_dispute_exceeding_undisputed
         r#"type, client, tx, amount
            deposit,   1,  1,     50
-           deposit,   1,  2,     20
-           dispute,   1,  1,
-           chargeback,1,  1,
-           dispute,   1,  1,
-           chargeback,1,  1,       "#
-        r#"client,available,held,total,locked
-                1,       20,  0,   20, true"#
+           dispute,   1,  1,     30
+           dispute,   1,  1,     30"#
+        TransactionError::ExcessiveAmount
     );
-    engine_test!(deposit_after_chargeback
+    rejection_test!(reject_zero_dispute
         r#"type, client, tx, amount
            deposit,   1,  1,     50
-           deposit,   1,  2,     20
-           dispute,   1,  1,
-           chargeback,1,  1,
-           deposit,   1,  3,     10"#
-        r#"client,available,held,total,locked
-                1,       20,  0,   20, true"#
+           dispute,   1,  1,      0"#
+        TransactionError::ZeroAmount
     );
-    engine_test!(withdrawal_after_chargeback
+    rejection_test!(reject_dispute_of_zero_deposit
+        r#"type, client, tx, amount
+           deposit,   1,  1,      0
+           dispute,   1,  1,       "#
+        TransactionError::ZeroAmount
+    );
+    rejection_test!(reject_zero_resolve
         r#"type, client, tx, amount
            deposit,   1,  1,     50
-           deposit,   1,  2,     20
-           dispute,   1,  1,
-           chargeback,1,  1,
-           withdrawal,1,  3,     10"#
+           dispute,   1,  1,     20
+           resolve,   1,  1,      0"#
+        TransactionError::ZeroAmount
+    );
+    rejection_test!(reject_zero_chargeback
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           dispute,   1,  1,     20
+           chargeback,1,  1,      0"#
+        TransactionError::ZeroAmount
+    );
+    rejection_test!(reject_resolve_exceeding_disputed
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           dispute,   1,  1,     20
+           resolve,   1,  1,     30"#
+        TransactionError::ExcessiveAmount
+    );
+    rejection_test!(reject_dispute_after_partial_chargeback
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           dispute,   1,  1,     20
+           chargeback,1,  1,     20
+           dispute,   1,  1,     10"#
+        TransactionError::ChargedBack
+    );
+
+    #[test]
+    fn partial_disputes_keep_invariants() {
+        let mut engine = TransactionEngine::new();
+        let events = process_all(&mut engine, r#"type, client, tx, amount, destination
+                                                 deposit,   1,  1,     50,
+                                                 deposit,   2,  2,     10,
+                                                 transfer,  1,  3,     30, 2
+                                                 dispute,   1,  1,     10,
+                                                 dispute,   1,  3,     10,
+                                                 resolve,   1,  1,      4,
+                                                 chargeback,1,  3,      5,"#);
+
+        assert_eq!(events[5][0].kind, LedgerEventKind::FundsReleased { amount: Amount::from(4) });
+        assert_eq!(events[6][0].kind, LedgerEventKind::TransferReversed { amount: Amount::from(5) });
+        let record = engine.transaction(TransactionId::new(1)).unwrap().unwrap();
+        assert_eq!((record.state(), record.disputed(), record.disputes()), (TransactionState::Disputed, Amount::from(6), 1));
+        assert_eq!(engine.verify_invariants().unwrap(), vec![]);
+    }
+
+    engine_test!(dispute_within_window
+        with window_config();
+        r#"type, client, tx, amount, timestamp
+           deposit,   1,  1,     50,         0
+           dispute,   1,  1,       ,       100"#
         r#"client,available,held,total,locked
-                1,       20,  0,   20, true"#
+                1,        0,  50,   50, false"#
+    );
+    engine_test!(dispute_without_timestamps
+        with window_config();
+        r#"type, client, tx, amount, timestamp
+           deposit,   1,  1,     50,
+           deposit,   1,  2,     10,      1000
+           dispute,   1,  1,       ,      2000"#
+        r#"client,available,held,total,locked
+                1,       10,  50,   60, false"#
+    );
+    rejection_test!(reject_dispute_after_window
+        with window_config();
+        r#"type, client, tx, amount, timestamp
+           deposit,   1,  1,     50,        0
+           dispute,   1,  1,       ,      101"#
+        TransactionError::DisputeWindowExpired
+    );
+    rejection_test!(reject_further_dispute_after_clock_passed_window
+        with window_config();
+        r#"type, client, tx, amount, timestamp
+           deposit,   1,  1,     50,         0
+           dispute,   1,  1,     20,        10
+           deposit,   1,  2,     10,       150
+           dispute,   1,  1,     10,"#
+        TransactionError::DisputeWindowExpired
+    );
+    rejection_test!(reject_reused_id_of_evicted_transaction
+        with window_config();
+        r#"type, client, tx, amount, timestamp
+           deposit,   1,  1,     10,         0
+           deposit,   1,  2,      5,      1000
+           deposit,   1,  1,     10,      1001"#
+        TransactionError::DuplicateTransaction
+    );
+    rejection_test!(reject_transfer_with_id_of_evicted_transaction
+        with window_config();
+        r#"type, client, tx, amount, timestamp, destination
+           deposit,   1,  1,     10,         0,
+           deposit,   2,  2,      5,      1000,
+           transfer,  2,  1,      5,      1001, 1"#
+        TransactionError::DuplicateTransaction
+    );
+    rejection_test!(reject_dispute_of_evicted_transaction
+        with window_config();
+        r#"type, client, tx, amount, timestamp
+           deposit,   1,  1,     10,         0
+           deposit,   1,  2,      5,      1000
+           dispute,   1,  1,       ,      1001"#
+        TransactionError::DisputeWindowExpired
     );
+
+    #[test]
+    fn expired_transactions_are_evicted() {
+        let mut engine = engine_with(window_config(), r#"type, client, tx, amount, timestamp
+                                                         deposit,   1,  1,     50,         0
+                                                         deposit,   1,  2,     20,        10
+                                                         dispute,   1,  2,       ,        20
+                                                         withdrawal,1,  3,      5,
+                                                         deposit,   1,  4,     10,       150"#);
+        let saved = |engine: &TransactionEngine| engine.transactions().keys().map(|id| id.value()).collect::<BTreeSet<_>>();
+
+        // the disputed deposit is kept until its dispute is resolved
+        assert_eq!(engine.clock(), Some(Timestamp::new(150)));
+        assert_eq!(saved(&engine), BTreeSet::from([2, 3, 4]));
+        assert!(matches!(
+            engine.handle_transaction(Transaction::dispute(TransactionId::new(1), AccountId::new(1))),
+            Err(TransactionError::DisputeWindowExpired),
+        ));
+        engine.handle_transaction(Transaction::resolve(TransactionId::new(2), AccountId::new(1))).unwrap();
+        assert_eq!(saved(&engine), BTreeSet::from([3, 4]));
+        assert_eq!(engine.verify_invariants().unwrap(), vec![]);
+
+        let restored = TransactionEngine::from_snapshot(window_config(), engine.snapshot().unwrap()).unwrap();
+        assert_eq!(restored, engine);
+        assert_eq!(restored.verify_invariants().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn expired_disputes_are_resolved() {
+        let config = deadline_config(DeadlineAction::Resolve);
+        let mut engine = engine_with(config, r#"type, client, tx, amount, timestamp
+                                                deposit,   1,  1,     50,         0
+                                                deposit,   1,  2,     20,         0
+                                                dispute,   1,  2,       ,        20
+                                                dispute,   1,  1,     30,        10
+                                                dispute,   1,  1,     10,        30"#);
+
+        assert!(engine.advance_time(Timestamp::new(69)).unwrap().is_empty());
+        let closed = engine.advance_time(Timestamp::new(80)).unwrap();
+        let summary = closed
+            .iter()
+            .map(|closed| (closed.transaction.id().value(), closed.opened.sequence, closed.outcome.as_ref().unwrap()[0].kind.clone()))
+            .collect::<Vec<_>>();
+
+        // further portions don't move the deadline of the open dispute
+        assert_eq!(summary, [
+            (1, 4, LedgerEventKind::FundsReleased { amount: Amount::from(40) }),
+            (2, 3, LedgerEventKind::FundsReleased { amount: Amount::from(20) }),
+        ]);
+        assert_eq!(closed[0].transaction, Transaction::resolve(TransactionId::new(1), AccountId::new(1)).at(Timestamp::new(80)));
+        assert_eq!(engine.clock(), Some(Timestamp::new(80)));
+        assert_eq!(engine.transaction_state(TransactionId::new(1)).unwrap(), Some(TransactionState::Resolved));
+        assert!(engine.advance_time(Timestamp::new(1_000)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn expired_disputes_are_charged_back() {
+        let config = deadline_config(DeadlineAction::Chargeback);
+        let mut engine = engine_with(config, r#"type, client, tx, amount, timestamp
+                                                deposit,   1,  1,     50,
+                                                dispute,   1,  1,       ,
+                                                deposit,   2,  2,     20,       100
+                                                dispute,   2,  2,       ,"#);
+
+        // the first dispute was opened before the engine knew the time, so it expires by the
+        // number of transactions since
+        let closed = engine.advance_time(Timestamp::new(160)).unwrap();
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].opened, DisputeOpening { timestamp: Some(Timestamp::new(100)), sequence: 4 });
+        assert_eq!(closed[0].transaction.transaction_type(), TransactionType::Chargeback);
+
+        let account = engine.accounts()[&AccountId::new(2)].clone();
+        assert_eq!((account.total(Currency::USD), account.status()), (Amount::ZERO, &AccountStatus::Locked));
+        assert!(engine.transaction_state(TransactionId::new(1)).unwrap().unwrap().is_disputed());
+
+        // the chargeback of the second dispute was the third transaction after the first one
+        let closed = engine.close_expired_disputes().unwrap();
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].opened, DisputeOpening { timestamp: None, sequence: 2 });
+        assert_eq!(closed[0].transaction, Transaction::chargeback(TransactionId::new(1), AccountId::new(1)).at(Timestamp::new(160)));
+        assert_eq!(engine.transaction_state(TransactionId::new(1)).unwrap(), Some(TransactionState::ChargedBack));
+        assert_eq!(engine.verify_invariants().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn expired_disputes_are_closed_without_time() {
+        let config = deadline_config(DeadlineAction::Resolve);
+        let mut engine = engine_with(config, r#"type, client, tx, amount
+                                                deposit,   1,  1,     50
+                                                dispute,   1,  1,
+                                                deposit,   1,  2,     10
+                                                deposit,   1,  3,     10"#);
+
+        assert!(engine.close_expired_disputes().unwrap().is_empty());
+        process_all(&mut engine, r#"type, client, tx, amount
+                                    withdrawal, 1,  4,      5"#);
+
+        let closed = engine.close_expired_disputes().unwrap();
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].transaction, Transaction::resolve(TransactionId::new(1), AccountId::new(1)));
+        assert_eq!(engine.clock(), None);
+        assert_eq!(engine.accounts()[&AccountId::new(1)].available(Currency::USD), Amount::from(65));
+    }
+
+    rejection_test! {
+        reject_untimed_dispute_with_timed_deadline
+        with EngineConfig {
+            dispute_deadline: Some(DisputeDeadline { after: Duration::from_secs(60), after_transactions: None, action: DeadlineAction::Resolve }),
+            ..EngineConfig::default()
+        };
+        r#"type, client, tx, amount
+           deposit,   1,  1,     50
+           dispute,   1,  1,"#
+        TransactionError::MissingTimestamp
+    }
+
+    #[test]
+    fn failed_writes_are_undone() {
+        let (sender, receiver, unknown) = (AccountId::new(1), AccountId::new(2), AccountId::new(3));
+        let mut engine = TransactionEngine::with_stores(EngineConfig::default(), FailingAccounts::default(), HashMap::new()).unwrap();
+        engine.handle_transaction(Transaction::deposit(TransactionId::new(1), sender, Amount::from(50))).unwrap();
+        engine.handle_transaction(Transaction::deposit(TransactionId::new(2), receiver, Amount::from(10))).unwrap();
+        engine.accounts.failing = Some(receiver);
+        let before = engine.snapshot().unwrap();
+
+        // the record and the sender are written before the receiver fails
+        let result = engine.handle_transaction(Transaction::transfer(TransactionId::new(3), sender, Amount::from(20), receiver));
+        assert!(matches!(result, Err(TransactionError::Storage(_))), "{:?}", result);
+        assert_eq!(engine.snapshot().unwrap(), before);
+
+        engine.accounts.failing = Some(unknown);
+        let result = engine.handle_transaction(Transaction::deposit(TransactionId::new(4), unknown, Amount::from(5)));
+        assert!(matches!(result, Err(TransactionError::Storage(_))), "{:?}", result);
+        assert_eq!(engine.snapshot().unwrap(), before);
+    }
+
+    #[test]
+    fn failed_writes_undo_evictions() {
+        let (first, second) = (AccountId::new(1), AccountId::new(2));
+        let accounts = FailingAccounts { failing: Some(second), ..FailingAccounts::default() };
+        let mut engine = TransactionEngine::with_stores(window_config(), accounts, HashMap::new()).unwrap();
+        engine.handle_transaction(Transaction::deposit(TransactionId::new(1), first, Amount::from(50)).at(Timestamp::new(100))).unwrap();
+        engine.handle_transaction(Transaction::deposit(TransactionId::new(2), first, Amount::from(20)).at(Timestamp::new(150))).unwrap();
+        let before = engine.snapshot().unwrap();
+
+        // the deposit passes the dispute window of the first one, but cannot be written
+        let deposit = Transaction::deposit(TransactionId::new(3), second, Amount::from(5)).at(Timestamp::new(201));
+        let result = engine.handle_transaction(deposit.clone());
+        assert!(matches!(result, Err(TransactionError::Storage(_))), "{:?}", result);
+        assert_eq!(engine.snapshot().unwrap(), before);
+
+        engine.accounts.failing = None;
+        engine.handle_transaction(deposit).unwrap();
+        assert!(engine.transaction(TransactionId::new(1)).unwrap().is_none());
+        assert!(engine.transaction(TransactionId::new(2)).unwrap().is_some());
+    }
+
+    #[test]
+    fn exported_transactions_can_be_replayed() {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(r#"type,      client, tx, amount, reason
+                            deposit,        1,  1,     10,
+                            deposit,        1,  2,      5,
+                            withdrawal,     1,  3,      2,
+                            dispute,        1,  1,       ,
+                            resolve,        1,  1,       ,
+                            dispute,        1,  2,       ,
+                            chargeback,     1,  2,       ,
+                            unlock,         1,  4,       ,
+                            deposit,        2,  5,      3,
+                            freeze,         2,  6,       , fraud investigation
+                            unlock,         2,  7,       ,
+                            withdrawal,     2,  8,      3,
+                            close,          2,  9,       ,"#.as_bytes());
+        let transactions: Vec<Transaction> = reader.deserialize().map(Result::unwrap).collect();
+        let mut engine = TransactionEngine::new();
+        for transaction in &transactions {
+            engine.process(transaction.clone()).unwrap();
+        }
+        let rows: Vec<TransactionRow> = transactions.iter().map(|transaction| engine.transaction_row(transaction).unwrap()).collect();
+        assert_eq!(rows[1].state, Some(TransactionState::ChargedBack));
+        assert_eq!(rows[3].state, None);
+
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        for row in &rows {
+            writer.serialize(row).unwrap();
+        }
+        let csv = writer.into_inner().unwrap();
+        let from_csv: Vec<Transaction> = csv::Reader::from_reader(csv.as_slice()).deserialize().map(Result::unwrap).collect();
+        let from_jsonl = rows.iter().map(|row| serde_json::from_str(&serde_json::to_string(row).unwrap()).unwrap()).collect();
+
+        for replayed in [from_csv, from_jsonl] {
+            assert_eq!(replayed, transactions);
+            let mut replay = TransactionEngine::new();
+            for transaction in replayed {
+                replay.process(transaction).unwrap();
+            }
+            assert_eq!(replay.snapshot().unwrap(), engine.snapshot().unwrap());
+        }
+    }
+
+    #[test]
+    fn process_returns_rejection_context() {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(r#"type, client, tx, amount
+                            deposit,   1,  1,     50
+                            withdrawal,1,  2,     60"#.as_bytes());
+        let mut transactions = reader
+            .deserialize::<Transaction>()
+            .map(Result::unwrap);
+        let mut engine = TransactionEngine::new();
+
+        engine.process(transactions.next().unwrap()).unwrap();
+        let withdrawal = transactions.next().unwrap();
+        let (id, client) = (withdrawal.id(), withdrawal.client());
+        let rejection = engine.process(withdrawal).unwrap_err().at_line(3);
+
+        assert_eq!(rejection.line(), Some(3));
+        assert_eq!(rejection.id(), id);
+        assert_eq!(rejection.client(), client);
+        assert_eq!(rejection.transaction_type(), TransactionType::Withdrawal);
+        assert_eq!(rejection.error().code(), "insufficient_funds");
+
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.serialize(&rejection).unwrap();
+        assert_eq!(
+            String::from_utf8(writer.into_inner().unwrap()).unwrap(),
+            "line,tx,client,type,reason,message\n\
+             3,2,1,withdrawal,insufficient_funds,The account does not hold enough available funds\n",
+        );
+    }
+
+    /// A configuration with rates between euros and dollars
+    fn fx_config() -> EngineConfig {
+        let rates = FxRates::read("from,to,rate\nEUR,USD,1.085\nUSD,EUR,0.92165899".as_bytes()).unwrap();
+        EngineConfig { rates, ..EngineConfig::default() }
+    }
+
+    /// A configuration with a dispute window of 100 seconds
+    fn window_config() -> EngineConfig {
+        EngineConfig { dispute_window: Some(Duration::from_secs(100)), ..EngineConfig::default() }
+    }
+
+    /// A configuration where disputes are closed by the action 60 seconds after they were opened,
+    /// or 3 transactions after if they were opened at an unknown time
+    fn deadline_config(action: DeadlineAction) -> EngineConfig {
+        EngineConfig {
+            dispute_deadline: Some(DisputeDeadline { after: Duration::from_secs(60), after_transactions: Some(3), action }),
+            ..EngineConfig::default()
+        }
+    }
+
+    /// Processes CSV rows, which all have to be accepted, and returns the events of every row
+    fn process_all(engine: &mut TransactionEngine, transactions: &str) -> Vec<Vec<LedgerEvent>> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(transactions.as_bytes());
+
+        reader
+            .deserialize::<Transaction>()
+            .map(|transaction| engine.handle_transaction(transaction.unwrap()).unwrap())
+            .collect()
+    }
+
+    /// An engine with the configuration, that processed all CSV rows
+    fn engine_with(config: EngineConfig, transactions: &str) -> TransactionEngine {
+        let mut engine = TransactionEngine::with_config(config);
+        process_all(&mut engine, transactions);
+        engine
+    }
+
+    /// Processes all transactions, and returns the events of the last one
+    fn last_events(config: EngineConfig, transactions: &str) -> Vec<LedgerEvent> {
+        process_all(&mut TransactionEngine::with_config(config), transactions).pop().unwrap()
+    }
+
+    fn balances(available: u32, held: u32) -> Balances {
+        Balances { available: Amount::from(available), held: Amount::from(held) }
+    }
+
+    #[test]
+    fn deposit_event() {
+        let events = last_events(EngineConfig::default(), r#"type, client, tx, amount
+                                                            deposit,   1,  1,     50
+                                                            deposit,   1,  2,     20"#);
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].sequence, 2);
+        assert_eq!(events[0].kind, LedgerEventKind::FundsDeposited { amount: Amount::from(20) });
+        assert_eq!(events[0].before, balances(50, 0));
+        assert_eq!(events[0].after, balances(70, 0));
+    }
+
+    #[test]
+    fn chargeback_events() {
+        let events = last_events(EngineConfig::default(), r#"type, client, tx, amount
+                                                            deposit,   1,  1,     50
+                                                            dispute,   1,  1,
+                                                            chargeback,1,  1,       "#);
+        let kinds = events
+            .iter()
+            .map(|event| event.kind.clone())
+            .collect::<Vec<_>>();
+
+        assert_eq!(kinds, [LedgerEventKind::FundsChargedBack { amount: Amount::from(50) }, LedgerEventKind::AccountLocked]);
+        assert_eq!(events[0].before, balances(0, 50));
+        assert_eq!(events[0].after, balances(0, 0));
+        assert_eq!(events[1].before, events[1].after);
+    }
+
+    #[test]
+    fn chargeback_on_frozen_doesnt_lock() {
+        let events = last_events(EngineConfig::default(), r#"type, client, tx, amount, reason
+                                                            deposit,   1,  1,     50,
+                                                            dispute,   1,  1,       ,
+                                                            freeze,    1,  2,       , aml review
+                                                            chargeback,1,  1,       ,"#);
+        let kinds = events
+            .iter()
+            .map(|event| event.kind.clone())
+            .collect::<Vec<_>>();
+
+        assert_eq!(kinds, [LedgerEventKind::FundsChargedBack { amount: Amount::from(50) }]);
+    }
+
+    #[test]
+    fn withdrawal_dispute_events() {
+        let config = EngineConfig { disputable: DisputableTransactions::DepositsAndWithdrawals, ..EngineConfig::default() };
+        let events = last_events(config.clone(), r#"type, client, tx, amount
+                                            deposit,   1,  1,     50
+                                            withdrawal,1,  2,     20
+                                            dispute,   1,  2,       "#);
+        assert_eq!(events[0].kind, LedgerEventKind::CreditProvisioned { amount: Amount::from(20) });
+        assert_eq!(events[0].after, balances(30, 20));
+
+        let events = last_events(config, r#"type, client, tx, amount
+                                            deposit,   1,  1,     50
+                                            withdrawal,1,  2,     20
+                                            dispute,   1,  2,
+                                            chargeback,1,  2,       "#);
+        // reversing a withdrawal doesn't lock the account
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, LedgerEventKind::WithdrawalReversed { amount: Amount::from(20) });
+        assert_eq!(events[0].after, balances(50, 0));
+    }
+
+    #[test]
+    fn close_event() {
+        let events = last_events(EngineConfig::default(), r#"type, client, tx, amount
+                                                            deposit,   1,  1,     50
+                                                            close,     1,  2,       "#);
+
+        assert_eq!(events[0].kind, LedgerEventKind::AccountClosed { payout: Amount::from(50) });
+        assert_eq!(events[0].after, balances(0, 0));
+    }
+
+    #[test]
+    fn close_events_per_currency() {
+        let events = last_events(EngineConfig::default(), r#"type, client, tx, amount, currency
+                                                            deposit,   1,  1,     50, EUR
+                                                            deposit,   1,  2,     20, GBP
+                                                            close,     1,  3,       ,"#);
+        let payouts = events
+            .iter()
+            .map(|event| (event.currency, event.kind.clone(), event.after))
+            .collect::<Vec<_>>();
+
+        assert_eq!(payouts, [
+            (Currency::EUR, LedgerEventKind::AccountClosed { payout: Amount::from(50) }, balances(0, 0)),
+            (Currency::GBP, LedgerEventKind::AccountClosed { payout: Amount::from(20) }, balances(0, 0)),
+        ]);
+    }
+
+    #[test]
+    fn event_serializes_flat() {
+        let events = last_events(EngineConfig::default(), r#"type, client, tx, amount
+                                                            deposit,   1,  1,    1.5"#);
+
+        assert_eq!(
+            serde_json::to_string(&events[0]).unwrap(),
+            r#"{"sequence":1,"tx":1,"client":1,"currency":"USD","event":"funds_deposited","amount":"1.5","before":{"available":"0","held":"0"},"after":{"available":"1.5","held":"0"},"postings":[{"debit":"cash","credit":"available:1","currency":"USD","amount":"1.5"}]}"#,
+        );
+    }
+
+    #[test]
+    fn ledger_is_balanced() {
+        let config = EngineConfig { disputable: DisputableTransactions::DepositsAndWithdrawals, ..EngineConfig::default() };
+        let engine = engine_with(config, r#"type, client, tx, amount
+                                            deposit,   1,  1,     50
+                                            deposit,   2,  2,     30
+                                            withdrawal,1,  3,     20
+                                            dispute,   1,  3,
+                                            chargeback,1,  3,
+                                            dispute,   2,  2,
+                                            chargeback,2,  2,
+                                            deposit,   3,  4,     10
+                                            close,     3,  5,       "#);
+
+        let trial_balance = engine.ledger().trial_balance();
+        let balance = |account| engine.ledger().balance(account, Currency::USD).to_string();
+        assert!(trial_balance.is_balanced());
+        assert_eq!(balance(LedgerAccount::Cash), "-30");
+        assert_eq!(balance(LedgerAccount::ChargebackLosses), "-20");
+        assert_eq!(balance(LedgerAccount::ProvisionalCredits), "0");
+        for account in engine.accounts().values() {
+            assert_eq!(engine.ledger().client_balances(account.id(), Currency::USD), Some(account.balances(Currency::USD)));
+        }
+    }
+
+    #[test]
+    fn ledger_opens_existing_accounts() {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(r#"type, client, tx, amount
+                            deposit,   1,  1,     50
+                            dispute,   1,  1,
+                            deposit,   1,  2,     20"#.as_bytes());
+        let mut transactions = reader
+            .deserialize::<Transaction>()
+            .map(Result::unwrap);
+        let mut engine = TransactionEngine::new();
+        engine.handle_transaction(transactions.next().unwrap()).unwrap();
+        engine.handle_transaction(transactions.next().unwrap()).unwrap();
+
+        // a snapshot without a ledger, like the ones written before the ledger existed
+        let mut snapshot = engine.snapshot().unwrap();
+        snapshot.ledger.clear();
+        let mut engine = TransactionEngine::from_snapshot(EngineConfig::default(), snapshot).unwrap();
+        engine.handle_transaction(transactions.next().unwrap()).unwrap();
+
+        let client = engine.accounts().values().next().unwrap().id();
+        assert!(engine.ledger().trial_balance().is_balanced());
+        assert_eq!(engine.ledger().balance(LedgerAccount::OpeningBalances, Currency::USD).to_string(), "-50");
+        assert_eq!(engine.ledger().balance(LedgerAccount::Cash, Currency::USD).to_string(), "-20");
+        assert_eq!(engine.ledger().balance(LedgerAccount::Held(client), Currency::USD).to_string(), "50");
+    }
 }
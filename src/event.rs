@@ -0,0 +1,150 @@
+use crate::{AccountId, Amount, Currency, FxRate, LedgerAccount, LedgerBalance, Posting, Transaction, TransactionId};
+
+/// The funds of an account in one currency at one point in time
+#[derive(Clone, Copy, Debug, Default, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct Balances {
+    /// See [`Account::available`](crate::Account::available)
+    pub available: Amount,
+    /// See [`Account::held`](crate::Account::held)
+    pub held: Amount,
+}
+
+impl Balances {
+    /// The sum of available and held funds
+    pub fn total(&self) -> Amount {
+        self.available + self.held
+    }
+}
+
+/// What happened to an account, see [`LedgerEvent`]
+#[derive(Clone, Debug, serde::Serialize, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum LedgerEventKind {
+    /// Funds were added to the available funds by a deposit
+    FundsDeposited { amount: Amount },
+    /// Funds were removed from the available funds by a withdrawal
+    FundsWithdrawn { amount: Amount },
+    /// Funds of a disputed deposit were moved from the available to the held funds
+    FundsHeld { amount: Amount },
+    /// Funds of a resolved deposit dispute were moved back to the available funds
+    FundsReleased { amount: Amount },
+    /// Held funds of a disputed deposit were removed by a chargeback
+    FundsChargedBack { amount: Amount },
+    /// The amount of a disputed withdrawal was provisionally credited to the held funds
+    CreditProvisioned { amount: Amount },
+    /// The provisional credit of a resolved withdrawal dispute was removed again
+    CreditRevoked { amount: Amount },
+    /// The provisional credit of a disputed withdrawal was made available by a chargeback
+    WithdrawalReversed { amount: Amount },
+    /// Available funds were removed to be converted to another currency
+    FundsConvertedOut { amount: Amount, to: Currency },
+    /// The converted funds were added to the available funds
+    ///
+    /// `fx_gain` is what rounding the converted amount gained, and is negative for losses.
+    FundsConvertedIn { amount: Amount, from: Currency, rate: FxRate, fx_gain: LedgerBalance },
+    /// Available funds were sent to another client by a transfer
+    FundsTransferredOut { amount: Amount, to: AccountId },
+    /// Funds sent by another client were added to the available funds
+    FundsTransferredIn { amount: Amount, from: AccountId },
+    /// Held funds of a disputed transfer were removed by a chargeback, to be returned to the sender
+    TransferReversed { amount: Amount },
+    /// The amount of a charged back transfer was returned to the sender's available funds
+    TransferRefunded { amount: Amount },
+    /// The account was locked after a chargeback
+    AccountLocked,
+    /// The account was unlocked by an operator
+    AccountUnlocked,
+    /// The account was frozen by an operator
+    AccountFrozen { reason: String },
+    /// The account was closed by an operator, and its available funds in one currency were paid out
+    AccountClosed { payout: Amount },
+}
+
+impl LedgerEventKind {
+    /// The postings recording the event in the general ledger
+    pub(crate) fn postings(&self, client: AccountId, currency: Currency) -> Vec<Posting> {
+        use LedgerAccount::*;
+
+        let available = Available(client);
+        let held = Held(client);
+        let posting = |debit, credit, amount| Posting::new(debit, credit, currency, amount);
+        match *self {
+            Self::FundsDeposited { amount } => vec![posting(Cash, available, amount)],
+            Self::FundsWithdrawn { amount } => vec![posting(available, Cash, amount)],
+            Self::FundsHeld { amount } => vec![posting(available, held, amount)],
+            Self::FundsReleased { amount } => vec![posting(held, available, amount)],
+            Self::FundsChargedBack { amount } => vec![posting(held, Cash, amount)],
+            Self::CreditProvisioned { amount } => vec![posting(ProvisionalCredits, held, amount)],
+            Self::CreditRevoked { amount } => vec![posting(held, ProvisionalCredits, amount)],
+            Self::WithdrawalReversed { amount } => vec![
+                posting(held, available, amount),
+                // the provisional credit becomes final, so it's booked as a loss
+                posting(ChargebackLosses, ProvisionalCredits, amount),
+            ],
+            Self::FundsConvertedOut { amount, .. } => vec![posting(available, FxPositions, amount)],
+            Self::FundsConvertedIn { amount, fx_gain, .. } => {
+                // the position is reduced by the amount at full precision, the difference is the gain
+                let mut postings = vec![posting(FxPositions, available, amount)];
+                let loss = LedgerBalance::from_raw(-fx_gain.raw());
+                match (fx_gain.to_amount(), loss.to_amount()) {
+                    (Some(gain), _) if !gain.is_zero() => postings.push(posting(FxPositions, FxGainsLosses, gain)),
+                    (_, Some(loss)) if !loss.is_zero() => postings.push(posting(FxGainsLosses, FxPositions, loss)),
+                    _ => {}
+                }
+                postings
+            }
+            Self::FundsTransferredOut { amount, .. } => vec![posting(available, Transfers, amount)],
+            Self::FundsTransferredIn { amount, .. } => vec![posting(Transfers, available, amount)],
+            Self::TransferReversed { amount } => vec![posting(held, Transfers, amount)],
+            Self::TransferRefunded { amount } => vec![posting(Transfers, available, amount)],
+            Self::AccountClosed { payout } if !payout.is_zero() => vec![posting(available, Cash, payout)],
+            Self::AccountClosed { .. }
+            | Self::AccountLocked
+            | Self::AccountUnlocked
+            | Self::AccountFrozen { .. } => vec![],
+        }
+    }
+}
+
+/// A single change to an account, caused by an accepted transaction
+///
+/// One transaction can cause multiple events, i.e. a chargeback of a deposit both charges
+/// back the held funds and locks the account. Transfers cause events for both clients.
+#[derive(Clone, Debug, serde::Serialize, PartialEq, Eq)]
+pub struct LedgerEvent {
+    /// The sequence number of the transaction, see [`TransactionEngine::sequence`](crate::TransactionEngine::sequence)
+    pub sequence: u64,
+    /// The id of the transaction that caused the event
+    pub tx: TransactionId,
+    /// The affected account
+    pub client: AccountId,
+    /// The currency of the affected funds
+    ///
+    /// Events that don't affect any funds, i.e. [`LedgerEventKind::AccountFrozen`], carry
+    /// the currency of the transaction.
+    pub currency: Currency,
+    /// What happened
+    #[serde(flatten)]
+    pub kind: LedgerEventKind,
+    /// The funds of the account in the currency before the event
+    pub before: Balances,
+    /// The funds of the account in the currency after the event
+    pub after: Balances,
+    /// The postings recording the event in the general ledger, see [`Ledger`](crate::Ledger)
+    pub postings: Vec<Posting>,
+}
+
+impl LedgerEvent {
+    pub(crate) fn new(sequence: u64, transaction: &Transaction, client: AccountId, currency: Currency, kind: LedgerEventKind, before: Balances, after: Balances) -> Self {
+        Self {
+            sequence,
+            tx: transaction.id(),
+            client,
+            currency,
+            postings: kind.postings(client, currency),
+            kind,
+            before,
+            after,
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+
+use crate::{AccountId, Currency, LedgerBalance, TransactionId};
+
+/// The total funds of a client in one currency, that were moved by evicted transactions
+///
+/// See [`EngineConfig::dispute_window`](crate::EngineConfig::dispute_window).
+#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct EvictedFunds {
+    pub client: AccountId,
+    pub currency: Currency,
+    pub total: LedgerBalance,
+}
+
+/// A range of consecutive transaction ids, including both ends
+#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct IdRange {
+    pub first: TransactionId,
+    pub last: TransactionId,
+}
+
+/// The transactions that were evicted from a store, see [`TransactionStore::evicted`](crate::TransactionStore::evicted)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Evicted {
+    /// The ids of the evicted transactions, as ranges that may overlap
+    pub ids: Vec<IdRange>,
+    /// The total funds the evicted transactions moved, per client and currency
+    pub funds: Vec<EvictedFunds>,
+}
+
+/// The ids of all evicted transactions
+///
+/// Evicted ids can never be used again, but their transactions aren't saved anymore. Since
+/// transactions are usually evicted roughly in the order of their ids, the ids are kept as
+/// ranges of consecutive ids, which stay few even after evicting millions of transactions.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct EvictedIds {
+    /// The last id of every range, by its first id
+    ranges: BTreeMap<u32, u32>,
+}
+
+impl EvictedIds {
+    /// Restores the ids from their ranges, and merges overlapping ones
+    pub(crate) fn from_ranges(ranges: impl IntoIterator<Item = IdRange>) -> Self {
+        let mut ranges = ranges
+            .into_iter()
+            .map(|range| (range.first.value(), range.last.value()))
+            .collect::<Vec<_>>();
+        ranges.sort();
+
+        let mut ids = Self::default();
+        for (first, last) in ranges {
+            match ids.ranges.last_key_value() {
+                Some((&start, &end)) if first <= end.saturating_add(1) => {
+                    ids.ranges.insert(start, end.max(last));
+                }
+                _ => {
+                    ids.ranges.insert(first, last);
+                }
+            }
+        }
+        ids
+    }
+
+    /// Whether the transaction with the specified id was evicted
+    pub(crate) fn contains(&self, id: TransactionId) -> bool {
+        self.ranges
+            .range(..=id.value())
+            .next_back()
+            .is_some_and(|(_, &last)| id.value() <= last)
+    }
+
+    /// The range that contains the id once it's added
+    ///
+    /// The range replaces all ranges that start within it.
+    pub(crate) fn range_with(&self, id: TransactionId) -> IdRange {
+        let id = id.value();
+        let (mut first, mut last) = (id, id);
+        // a range that contains the id, or ends right before it
+        if let Some((&start, &end)) = self.ranges.range(..=id).next_back().filter(|&(_, &end)| end >= id.saturating_sub(1)) {
+            first = start;
+            last = last.max(end);
+        }
+        // a range that starts right after the id
+        if let Some(&end) = id.checked_add(1).and_then(|next| self.ranges.get(&next)) {
+            last = end;
+        }
+
+        Self::range(first, last)
+    }
+
+    /// The ranges that start within the range, which it replaces once it's added
+    pub(crate) fn within(&self, range: IdRange) -> Vec<IdRange> {
+        self.ranges
+            .range(range.first.value()..=range.last.value())
+            .map(|(&first, &last)| Self::range(first, last))
+            .collect()
+    }
+
+    /// Adds an id, and returns the range that contains it afterwards
+    pub(crate) fn insert(&mut self, id: TransactionId) -> IdRange {
+        let range = self.range_with(id);
+        for replaced in self.within(range) {
+            self.ranges.remove(&replaced.first.value());
+        }
+        self.ranges.insert(range.first.value(), range.last.value());
+
+        range
+    }
+
+    /// Removes a range that was added by [`EvictedIds::insert`], and adds the ranges it
+    /// replaced again
+    pub(crate) fn restore(&mut self, range: IdRange, replaced: &[IdRange]) {
+        self.ranges.remove(&range.first.value());
+        for replaced in replaced {
+            self.ranges.insert(replaced.first.value(), replaced.last.value());
+        }
+    }
+
+    /// All ranges of evicted ids, ordered by their first id
+    pub(crate) fn ranges(&self) -> impl Iterator<Item = IdRange> + '_ {
+        self.ranges
+            .iter()
+            .map(|(&first, &last)| Self::range(first, last))
+    }
+
+    fn range(first: u32, last: u32) -> IdRange {
+        IdRange { first: TransactionId::new(first), last: TransactionId::new(last) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(ids: &EvictedIds) -> Vec<(u32, u32)> {
+        ids.ranges()
+            .map(|range| (range.first.value(), range.last.value()))
+            .collect()
+    }
+
+    #[test]
+    fn consecutive_ids_are_merged() {
+        let mut ids = EvictedIds::default();
+        for id in [1, 2, 5, 4, 7] {
+            ids.insert(TransactionId::new(id));
+        }
+        assert_eq!(ranges(&ids), vec![(1, 2), (4, 5), (7, 7)]);
+
+        let range = ids.insert(TransactionId::new(3));
+        assert_eq!((range.first.value(), range.last.value()), (1, 5));
+        assert_eq!(ids.range_with(TransactionId::new(2)), range);
+        assert_eq!(ranges(&ids), vec![(1, 5), (7, 7)]);
+        assert!(ids.contains(TransactionId::new(4)));
+        assert!(!ids.contains(TransactionId::new(6)));
+        assert!(!ids.contains(TransactionId::new(8)));
+    }
+
+    #[test]
+    fn inserted_id_can_be_restored() {
+        let mut ids = EvictedIds::default();
+        for id in [1, 2, 4, 5] {
+            ids.insert(TransactionId::new(id));
+        }
+        let before = ids.clone();
+
+        let range = ids.range_with(TransactionId::new(3));
+        let replaced = ids.within(range);
+        assert_eq!(ids.insert(TransactionId::new(3)), range);
+        ids.restore(range, &replaced);
+
+        assert_eq!(ids, before);
+    }
+
+    #[test]
+    fn overlapping_ranges_are_merged() {
+        let range = |first, last| IdRange { first: TransactionId::new(first), last: TransactionId::new(last) };
+        let ids = EvictedIds::from_ranges([range(5, 9), range(1, 7), range(10, 10), range(12, u32::MAX)]);
+
+        assert_eq!(ranges(&ids), vec![(1, 10), (12, u32::MAX)]);
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::{Amount, Currency, LedgerBalance};
+
+/// Possible errors to occur while loading a rate table
+#[derive(Debug, thiserror::Error)]
+pub enum RatesError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("The rate table is malformed: {0}")]
+    Format(#[from] csv::Error),
+    #[error("The rate from {from} to {to} is invalid: {message}")]
+    InvalidRate { from: Currency, to: Currency, message: String },
+    #[error("The rate table contains the rate from {from} to {to} more than once")]
+    DuplicateRate { from: Currency, to: Currency },
+}
+
+/// An exact exchange rate with eight decimal places
+///
+/// Like [`Amount`], the rate is stored as an integer number of units, so rates like `1.0850`
+/// are represented without any rounding errors.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FxRate(u64);
+
+impl FxRate {
+    /// The number of decimal places of a rate
+    pub const DECIMALS: u32 = 8;
+    /// The number of raw units in a rate of one
+    pub const SCALE: u64 = 10u64.pow(Self::DECIMALS);
+
+    /// Creates a rate from a raw number of hundred-millionths
+    pub const fn from_raw(raw: u64) -> Self {
+        Self(raw)
+    }
+
+    /// The raw number of hundred-millionths this rate consists of
+    pub const fn raw(self) -> u64 {
+        self.0
+    }
+
+    /// Converts an amount at this rate, and rounds the result as configured
+    ///
+    /// Returns `None` if the converted amount cannot be represented.
+    pub fn convert(self, amount: Amount, rounding: FxRounding) -> Option<Conversion> {
+        let product = u128::from(amount.raw()) * u128::from(self.0);
+        let scale = u128::from(Self::SCALE);
+        // the converted amount at the full precision of an amount
+        let exact = RoundingMode::HalfEven.divide(product, scale);
+        let step = 10u128.pow(Amount::DECIMALS.saturating_sub(rounding.decimals));
+        let converted = rounding.mode.divide(product, scale * step) * step;
+
+        Some(Conversion {
+            amount: Amount::from_raw(u64::try_from(converted).ok()?),
+            fx_gain: LedgerBalance::from_raw(i128::try_from(exact).ok()? - i128::try_from(converted).ok()?),
+        })
+    }
+}
+
+impl FromStr for FxRate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("`{}` is not a positive decimal number with at most eight decimal places", s);
+        let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+        let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+        if whole.is_empty() || !is_digits(whole) || !is_digits(fraction) || fraction.len() > Self::DECIMALS as usize {
+            return Err(invalid());
+        }
+
+        let whole = whole.parse::<u64>().map_err(|_| invalid())?;
+        let fraction = format!("{:0<width$}", fraction, width = Self::DECIMALS as usize)
+            .parse::<u64>()
+            .map_err(|_| invalid())?;
+
+        whole
+            .checked_mul(Self::SCALE)
+            .and_then(|raw| raw.checked_add(fraction))
+            .filter(|&raw| raw > 0)
+            .map(Self)
+            .ok_or_else(invalid)
+    }
+}
+
+impl fmt::Display for FxRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let whole = self.0 / Self::SCALE;
+        let fraction = format!("{:0width$}", self.0 % Self::SCALE, width = Self::DECIMALS as usize);
+
+        match fraction.trim_end_matches('0') {
+            "" => write!(f, "{}", whole),
+            fraction => write!(f, "{}.{}", whole, fraction),
+        }
+    }
+}
+
+impl fmt::Debug for FxRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl serde::Serialize for FxRate {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer
+    {
+        serializer.collect_str(self)
+    }
+}
+
+/// The result of converting an amount, see [`FxRate::convert`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Conversion {
+    /// The rounded amount in the target currency
+    pub amount: Amount,
+    /// What the rounding gained, i.e. the amount at full precision minus the rounded amount
+    ///
+    /// A negative gain is a loss.
+    pub fx_gain: LedgerBalance,
+}
+
+/// How converted amounts are rounded
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// To the nearest value, and ties to the even one
+    #[default]
+    HalfEven,
+    /// To the nearest value, and ties away from zero
+    HalfUp,
+    /// Towards zero
+    Down,
+    /// Away from zero
+    Up,
+}
+
+impl RoundingMode {
+    /// Divides two numbers, and rounds the quotient
+    fn divide(self, numerator: u128, denominator: u128) -> u128 {
+        let quotient = numerator / denominator;
+        let remainder = numerator % denominator;
+        let round_up = match self {
+            _ if remainder == 0 => false,
+            Self::Down => false,
+            Self::Up => true,
+            Self::HalfUp => remainder * 2 >= denominator,
+            Self::HalfEven => remainder * 2 > denominator || (remainder * 2 == denominator && quotient % 2 == 1),
+        };
+
+        quotient + u128::from(round_up)
+    }
+}
+
+impl FromStr for RoundingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "half-even" => Ok(Self::HalfEven),
+            "half-up" => Ok(Self::HalfUp),
+            "down" => Ok(Self::Down),
+            "up" => Ok(Self::Up),
+            _ => Err(format!("unknown rounding mode `{}`, expected half-even, half-up, down or up", s)),
+        }
+    }
+}
+
+/// How converted amounts are rounded, and to how many decimal places
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FxRounding {
+    pub mode: RoundingMode,
+    /// The decimal places of converted amounts, at most [`Amount::DECIMALS`]
+    pub decimals: u32,
+}
+
+impl Default for FxRounding {
+    /// Rounds half to even to two decimal places, i.e. to cents
+    fn default() -> Self {
+        Self { mode: RoundingMode::HalfEven, decimals: 2 }
+    }
+}
+
+/// A table of exchange rates between pairs of currencies
+///
+/// Rates only apply in the direction they were specified. Converting the other way
+/// requires a rate of its own.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FxRates {
+    rates: HashMap<(Currency, Currency), FxRate>,
+}
+
+impl FxRates {
+    /// Creates a new, empty rate table
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads a rate table from CSV with the columns `from`, `to` and `rate`
+    pub fn read(reader: impl Read) -> Result<Self, RatesError> {
+        #[derive(serde::Deserialize)]
+        struct Row {
+            from: Currency,
+            to: Currency,
+            rate: String,
+        }
+
+        let mut rates = Self::new();
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        for row in reader.deserialize::<Row>() {
+            let Row { from, to, rate } = row?;
+            let rate = rate
+                .parse()
+                .map_err(|message| RatesError::InvalidRate { from, to, message })?;
+            if rates.insert(from, to, rate).is_some() {
+                return Err(RatesError::DuplicateRate { from, to });
+            }
+        }
+
+        Ok(rates)
+    }
+
+    /// Loads a rate table from a CSV file, see [`FxRates::read`]
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, RatesError> {
+        Self::read(std::fs::File::open(path)?)
+    }
+
+    /// Sets the rate from one currency to another, and returns the previous rate
+    pub fn insert(&mut self, from: Currency, to: Currency, rate: FxRate) -> Option<FxRate> {
+        self.rates.insert((from, to), rate)
+    }
+
+    /// The rate from one currency to another, if known
+    pub fn get(&self, from: Currency, to: Currency) -> Option<FxRate> {
+        self.rates.get(&(from, to)).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert(amount: &str, rate: &str, mode: RoundingMode, decimals: u32) -> (String, String) {
+        let conversion = rate
+            .parse::<FxRate>()
+            .unwrap()
+            .convert(amount.parse().unwrap(), FxRounding { mode, decimals })
+            .unwrap();
+        (conversion.amount.to_string(), conversion.fx_gain.to_string())
+    }
+
+    #[test]
+    fn conversion_is_rounded() {
+        assert_eq!(convert("10", "1.085", RoundingMode::HalfEven, 2), ("10.85".to_owned(), "0".to_owned()));
+        assert_eq!(convert("1.005", "1", RoundingMode::HalfEven, 2), ("1".to_owned(), "0.005".to_owned()));
+        assert_eq!(convert("1.015", "1", RoundingMode::HalfEven, 2), ("1.02".to_owned(), "-0.005".to_owned()));
+        assert_eq!(convert("1.005", "1", RoundingMode::HalfUp, 2), ("1.01".to_owned(), "-0.005".to_owned()));
+        assert_eq!(convert("1.0099", "1", RoundingMode::Down, 2), ("1".to_owned(), "0.0099".to_owned()));
+        assert_eq!(convert("1.0001", "1", RoundingMode::Up, 0), ("2".to_owned(), "-0.9999".to_owned()));
+        assert_eq!(convert("1", "0.12345678", RoundingMode::HalfEven, 4), ("0.1235".to_owned(), "0".to_owned()));
+    }
+
+    #[test]
+    fn rates_are_read() {
+        let rates = FxRates::read("from,to,rate\nEUR,USD,1.085\nUSD,EUR,0.92165899".as_bytes()).unwrap();
+
+        assert_eq!(rates.get(Currency::EUR, Currency::USD), Some(FxRate::from_raw(108_500_000)));
+        assert_eq!(rates.get(Currency::USD, Currency::EUR).unwrap().to_string(), "0.92165899");
+        assert_eq!(rates.get(Currency::EUR, Currency::GBP), None);
+    }
+
+    #[test]
+    fn invalid_rates_are_rejected() {
+        for rate in ["0", "-1", "1.123456789", "one", ""] {
+            let table = format!("from,to,rate\nEUR,USD,{}", rate);
+            assert!(matches!(FxRates::read(table.as_bytes()), Err(RatesError::InvalidRate { .. })), "{}", rate);
+        }
+        assert!(matches!(
+            FxRates::read("from,to,rate\nEUR,USD,1\nEUR,USD,2".as_bytes()),
+            Err(RatesError::DuplicateRate { .. }),
+        ));
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{Account, AccountId, AccountStatus, Amount, Currency, Ledger, LedgerBalance, StoreError, StoredTransaction, TransactionId, TransactionType};
+
+/// A violated consistency rule of the engine state
+///
+/// See [`TransactionEngine::verify_invariants`](crate::TransactionEngine::verify_invariants).
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum InvariantViolation {
+    #[error("The total {currency} funds of client {client} are {actual}, but its transactions add up to {expected}")]
+    TotalMismatch { client: AccountId, currency: Currency, expected: LedgerBalance, actual: Amount },
+    #[error("The held {currency} funds of client {client} are {actual}, but its open disputes add up to {expected}")]
+    HeldMismatch { client: AccountId, currency: Currency, expected: LedgerBalance, actual: Amount },
+    #[error("The closed account of client {client} still holds {currency} funds")]
+    ClosedWithFunds { client: AccountId, currency: Currency },
+    #[error("The transaction {tx} belongs to client {client}, but there's no account for the client")]
+    MissingAccount { client: AccountId, tx: TransactionId },
+    #[error("The {currency} ledger balances add up to {total} instead of zero")]
+    UnbalancedLedger { currency: Currency, total: LedgerBalance },
+}
+
+/// The funds of a client in one currency, as implied by its saved transactions
+#[derive(Default)]
+pub(crate) struct Expected {
+    pub(crate) total: i128,
+    pub(crate) held: i128,
+}
+
+/// How a saved transaction changed the funds of the involved clients, per client and currency
+pub(crate) fn effects(record: &StoredTransaction) -> Vec<((AccountId, Currency), Expected)> {
+    let transaction = record.transaction();
+    let client = transaction.client();
+    let currency = transaction.currency();
+    let amount = i128::from(transaction.amount().unwrap_or_default().raw());
+    let disputed = i128::from(record.disputed().raw());
+    let charged_back = i128::from(record.charged_back().raw());
+    let mut effects = Vec::new();
+
+    if let (Some(target), Some(converted)) = (transaction.target_currency(), record.converted()) {
+        effects.push(((client, target), Expected { total: i128::from(converted.raw()), held: 0 }));
+    }
+    // the destination of a transfer received the funds like a deposit, and holds the disputed portion
+    if let Some(destination) = transaction.destination() {
+        effects.push(((destination, currency), Expected { total: amount - charged_back, held: disputed }));
+    }
+
+    // charged back portions are reversed, and don't count anymore
+    let effect = match transaction.transaction_type() {
+        TransactionType::Deposit => Expected { total: amount - charged_back, held: disputed },
+        TransactionType::Transfer => Expected { total: charged_back - amount, held: 0 },
+        // a disputed withdrawal is provisionally credited to the held funds, which offsets it
+        TransactionType::Withdrawal => Expected { total: disputed + charged_back - amount, held: disputed },
+        _ => Expected { total: -amount, held: 0 },
+    };
+    effects.push(((client, currency), effect));
+
+    effects
+}
+
+/// Checks the accounts, saved transactions, and ledger of an engine against each other
+///
+/// `evicted` holds the total funds per client and currency of the transactions that were
+/// evicted from the store.
+pub(crate) fn verify(
+    accounts: Vec<Account>,
+    records: impl Iterator<Item = Result<StoredTransaction, StoreError>>,
+    evicted: &BTreeMap<(AccountId, Currency), LedgerBalance>,
+    ledger: &Ledger,
+) -> Result<Vec<InvariantViolation>, StoreError> {
+    let mut violations = Vec::new();
+    let mut expected = HashMap::<(AccountId, Currency), Expected>::new();
+    let accounts = accounts
+        .into_iter()
+        .map(|account| (account.id(), account))
+        .collect::<HashMap<_, _>>();
+
+    for (&key, total) in evicted {
+        expected.entry(key).or_default().total += total.raw();
+    }
+    for record in records {
+        let record = record?;
+        let transaction = record.transaction();
+        for client in std::iter::once(transaction.client()).chain(transaction.destination()) {
+            if !accounts.contains_key(&client) {
+                violations.push(InvariantViolation::MissingAccount { client, tx: transaction.id() });
+            }
+        }
+
+        for (key, effect) in effects(&record) {
+            let expected = expected.entry(key).or_default();
+            expected.total += effect.total;
+            expected.held += effect.held;
+        }
+    }
+
+    let mut clients = accounts.keys().copied().collect::<Vec<_>>();
+    clients.sort();
+    for client in clients {
+        let account = &accounts[&client];
+        let mut currencies = account.currencies().collect::<Vec<_>>();
+        // transactions in currencies the account doesn't know about have to be checked as well
+        currencies.extend(expected
+            .keys()
+            .filter(|&&(id, currency)| id == client && !currencies.contains(&currency))
+            .map(|&(_, currency)| currency)
+            .collect::<Vec<_>>());
+        currencies.sort();
+
+        for currency in currencies {
+            let expected = expected.remove(&(client, currency)).unwrap_or_default();
+            let balances = account.balances(currency);
+
+            // closing an account pays out its funds without saving a transaction
+            if *account.status() == AccountStatus::Closed {
+                if !balances.total().is_zero() {
+                    violations.push(InvariantViolation::ClosedWithFunds { client, currency });
+                }
+            } else {
+                if expected.total != i128::from(balances.total().raw()) {
+                    violations.push(InvariantViolation::TotalMismatch {
+                        client,
+                        currency,
+                        expected: LedgerBalance::from_raw(expected.total),
+                        actual: balances.total(),
+                    });
+                }
+                if expected.held != i128::from(balances.held.raw()) {
+                    violations.push(InvariantViolation::HeldMismatch {
+                        client,
+                        currency,
+                        expected: LedgerBalance::from_raw(expected.held),
+                        actual: balances.held,
+                    });
+                }
+            }
+        }
+    }
+
+    for (currency, total) in ledger.trial_balance().totals() {
+        if !total.is_zero() {
+            violations.push(InvariantViolation::UnbalancedLedger { currency, total });
+        }
+    }
+
+    Ok(violations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::process;
+    use crate::{Balances, DisputableTransactions, EngineConfig, Snapshot, TransactionEngine, TransactionState};
+
+    const TRANSACTIONS: &str = r#"type, client, tx, amount
+                                  deposit,   1,  1,     50
+                                  deposit,   1,  2,     20
+                                  withdrawal,1,  3,     10
+                                  dispute,   1,  2,
+                                  dispute,   1,  3,
+                                  deposit,   2,  4,     30
+                                  dispute,   2,  4,
+                                  chargeback,2,  4,
+                                  deposit,   3,  5,     15
+                                  withdrawal,3,  6,      5
+                                  dispute,   3,  6,
+                                  chargeback,3,  6,
+                                  deposit,   4,  7,      5
+                                  close,     4,  8,       "#;
+
+    fn snapshot() -> Snapshot {
+        let config = EngineConfig { disputable: DisputableTransactions::DepositsAndWithdrawals, ..EngineConfig::default() };
+        let mut engine = TransactionEngine::with_config(config);
+        process(&mut engine, TRANSACTIONS);
+        // every transaction has to be accepted
+        assert_eq!(engine.sequence(), 14);
+
+        engine.snapshot().unwrap()
+    }
+
+    fn violations(snapshot: Snapshot) -> Vec<InvariantViolation> {
+        TransactionEngine::from_snapshot(EngineConfig::default(), snapshot)
+            .unwrap()
+            .verify_invariants()
+            .unwrap()
+    }
+
+    #[test]
+    fn consistent_engine_has_no_violations() {
+        assert_eq!(violations(snapshot()), vec![]);
+    }
+
+    #[test]
+    fn modified_balance_is_detected() {
+        let mut snapshot = snapshot();
+        let account = &mut snapshot.accounts[0];
+        let client = account.id();
+        let funds = Balances { available: account.available(Currency::USD) + Amount::from(1), held: account.held(Currency::USD) };
+        *account = Account::restore(client, [(Currency::USD, funds)].into(), account.status().clone()).unwrap();
+
+        assert!(matches!(
+            violations(snapshot).as_slice(),
+            [InvariantViolation::TotalMismatch { client: c, .. }] if *c == client,
+        ));
+    }
+
+    #[test]
+    fn modified_dispute_is_detected() {
+        let mut snapshot = snapshot();
+        let record = &mut snapshot.transactions[2];
+        *record = StoredTransaction::restore(record.transaction().clone(), TransactionState::Resolved, 1, None);
+
+        assert!(matches!(
+            violations(snapshot).as_slice(),
+            [InvariantViolation::TotalMismatch { .. }, InvariantViolation::HeldMismatch { .. }],
+        ));
+    }
+
+    #[test]
+    fn missing_account_is_detected() {
+        let mut snapshot = snapshot();
+        let account = snapshot.accounts.remove(1);
+
+        assert!(matches!(
+            violations(snapshot).as_slice(),
+            [InvariantViolation::MissingAccount { client, .. }] if *client == account.id(),
+        ));
+    }
+
+    #[test]
+    fn unbalanced_ledger_is_detected() {
+        let mut snapshot = snapshot();
+        snapshot.ledger.retain(|line| line.account != crate::LedgerAccount::Cash);
+
+        assert!(matches!(violations(snapshot).as_slice(), [InvariantViolation::UnbalancedLedger { .. }]));
+    }
+}
@@ -0,0 +1,448 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::{SnapshotError, StoreError, Transaction, TransactionError};
+
+/// Possible errors to occur while writing or recovering a journal
+#[derive(Debug, thiserror::Error)]
+pub enum JournalError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("The journal entry in line {line} is corrupted")]
+    Corrupted { line: u64 },
+    #[error("The journal skips from sequence number {expected} to {found}")]
+    Gap { expected: u64, found: u64 },
+    #[error("The journaled transaction {sequence} was rejected while replaying it: {error}")]
+    Replay { sequence: u64, error: TransactionError },
+    #[error("The journaled transaction {sequence} could not be applied, so the engine has to be recovered first")]
+    Unapplied { sequence: u64 },
+    #[error(transparent)]
+    Snapshot(#[from] SnapshotError),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+/// When the journal forces its entries to disk
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync after every entry
+    ///
+    /// This is the only policy that guarantees that no accepted transaction is
+    /// lost on a power failure, but it's also the slowest one.
+    #[default]
+    Always,
+    /// Sync after every `n` entries
+    ///
+    /// At most the last `n - 1` entries are lost on a power failure.
+    Every(u32),
+    /// Never sync explicitly, and leave it to the operating system
+    ///
+    /// Entries still survive a crash of the process, but not a power failure.
+    Never,
+}
+
+/// One accepted transaction in the journal
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct JournalEntry {
+    /// The sequence number of the transaction, see [`TransactionEngine::sequence`](crate::TransactionEngine::sequence)
+    pub sequence: u64,
+    /// The accepted transaction
+    pub transaction: Transaction,
+}
+
+/// The valid entries of a journal file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JournalContents {
+    /// All valid entries, in the order they were written
+    pub entries: Vec<JournalEntry>,
+    /// The length of the journal in bytes, up to and including the last valid entry
+    pub valid_len: u64,
+    /// The number of bytes of a torn entry at the end of the journal
+    ///
+    /// A torn entry is the result of a crash while the entry was written. Since the
+    /// entry was never completely written, its transaction was never applied either.
+    pub torn_len: u64,
+}
+
+/// The outcome of [`TransactionEngine::recover`](crate::TransactionEngine::recover)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Recovery {
+    /// The number of journaled transactions that were applied
+    pub replayed: u64,
+    /// The number of journaled transactions that were already part of the snapshot
+    pub skipped: u64,
+    /// The number of bytes of the torn entry that was removed from the end of the journal
+    pub torn_len: u64,
+}
+
+/// An append-only write-ahead log of accepted transactions
+///
+/// Every entry is written as one line of the form `<crc32> <json>`, where the CRC-32
+/// checksum, written as eight hex digits, covers the JSON encoded [`JournalEntry`].
+/// Together with a [`Snapshot`](crate::Snapshot), the journal can be used to rebuild
+/// the engine after a crash. See [`TransactionEngine::recover`](crate::TransactionEngine::recover).
+#[derive(Debug)]
+pub struct Journal {
+    writer: BufWriter<File>,
+    sync_policy: SyncPolicy,
+    unsynced: u32,
+    /// The sequence number of a journaled transaction that could not be applied
+    unapplied: Option<u64>,
+}
+
+impl Journal {
+    /// Opens a journal for appending, and creates it if it doesn't exist yet
+    ///
+    /// Torn entries at the end of an existing journal have to be removed before
+    /// appending to it. [`TransactionEngine::recover`](crate::TransactionEngine::recover)
+    /// takes care of this.
+    pub fn open(path: impl AsRef<Path>, sync_policy: SyncPolicy) -> Result<Self, JournalError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+
+        Ok(Self {
+            writer: BufWriter::new(file),
+            sync_policy,
+            unsynced: 0,
+            unapplied: None,
+        })
+    }
+
+    /// Appends a transaction to the journal
+    ///
+    /// The entry is flushed to the operating system before this returns. Whether
+    /// it's also synced to disk depends on the [`SyncPolicy`]. Fails once a journaled
+    /// transaction could not be applied, see [`JournalError::Unapplied`].
+    pub fn append(&mut self, sequence: u64, transaction: &Transaction) -> Result<(), JournalError> {
+        if let Some(sequence) = self.unapplied {
+            return Err(JournalError::Unapplied { sequence });
+        }
+        let entry = JournalEntry { sequence, transaction: transaction.clone() };
+        let json = serde_json::to_string(&entry).map_err(std::io::Error::from)?;
+
+        writeln!(self.writer, "{:08x} {}", crc32fast::hash(json.as_bytes()), json)?;
+        self.writer.flush()?;
+        self.unsynced += 1;
+
+        match self.sync_policy {
+            SyncPolicy::Always => self.sync()?,
+            SyncPolicy::Every(n) if self.unsynced >= n => self.sync()?,
+            SyncPolicy::Every(_) | SyncPolicy::Never => {}
+        }
+
+        Ok(())
+    }
+
+    /// Refuses all further entries, since the journaled transaction with the sequence number
+    /// was not applied
+    ///
+    /// The engine and the journal no longer agree on the sequence numbers, so a later entry
+    /// would be skipped on recovery. Recovering the engine replays the transaction instead.
+    pub(crate) fn mark_unapplied(&mut self, sequence: u64) {
+        self.unapplied = Some(sequence);
+    }
+
+    /// Forces all written entries to disk
+    pub fn sync(&mut self) -> Result<(), JournalError> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.unsynced = 0;
+
+        Ok(())
+    }
+
+    /// Removes all entries from the journal
+    ///
+    /// This should only be done after a snapshot containing all journaled transactions
+    /// was saved.
+    pub fn clear(&mut self) -> Result<(), JournalError> {
+        self.writer.flush()?;
+        self.writer.get_ref().set_len(0)?;
+        self.sync()
+    }
+
+    /// Reads all valid entries of a journal
+    ///
+    /// A missing journal is treated as an empty journal. A torn entry at the end of the
+    /// journal is ignored, while corrupted entries followed by other entries result in
+    /// an error, since they cannot be explained by a crash.
+    pub fn read(path: impl AsRef<Path>) -> Result<JournalContents, JournalError> {
+        let mut bytes = Vec::new();
+        match File::open(path) {
+            Ok(mut file) => file.read_to_end(&mut bytes)?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(error) => return Err(error.into()),
+        };
+
+        let mut contents = JournalContents {
+            entries: Vec::new(),
+            valid_len: 0,
+            torn_len: 0,
+        };
+        let mut lines = bytes.split_inclusive(|byte| *byte == b'\n').peekable();
+        let mut line_number = 0;
+
+        while let Some(line) = lines.next() {
+            line_number += 1;
+            let is_last = lines.peek().is_none();
+
+            match Self::parse(line) {
+                Some(entry) => {
+                    contents.entries.push(entry);
+                    contents.valid_len += line.len() as u64;
+                }
+                None if is_last => contents.torn_len = line.len() as u64,
+                None => return Err(JournalError::Corrupted { line: line_number }),
+            }
+        }
+
+        Ok(contents)
+    }
+
+    /// Parses one complete line of the journal
+    fn parse(line: &[u8]) -> Option<JournalEntry> {
+        let line = line.strip_suffix(b"\n")?;
+        let line = std::str::from_utf8(line).ok()?;
+        let (checksum, json) = line.split_once(' ')?;
+        let checksum = u32::from_str_radix(checksum, 16).ok()?;
+
+        match crc32fast::hash(json.as_bytes()) == checksum {
+            true => serde_json::from_str(json).ok(),
+            false => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing::{process, read, FailingAccounts, TempPath};
+    use crate::{AccountId, Amount, DeadlineAction, DisputeDeadline, EngineConfig, Snapshot, Timestamp, TransactionEngine, TransactionId};
+
+    const TRANSACTIONS: &str = r#"type, client, tx, amount
+                                  deposit,   1,  1,     50
+                                  deposit,   1,  2,     20
+                                  withdrawal,1,  3,    100
+                                  deposit,   2,  4,     30
+                                  dispute,   1,  1,
+                                  withdrawal,2,  5,     10
+                                  resolve,   1,  1,       "#;
+
+    fn transactions() -> Vec<Transaction> {
+        read(TRANSACTIONS)
+    }
+
+    /// A fresh journal path, that's unique per test
+    fn journal_path(name: &str) -> TempPath {
+        TempPath::new(&format!("{}.journal", name))
+    }
+
+    /// Processes all transactions while writing them to the journal, and returns the
+    /// resulting engine
+    fn journaled_engine(path: &Path) -> TransactionEngine {
+        let mut journal = Journal::open(path, SyncPolicy::Always).unwrap();
+        let mut engine = TransactionEngine::new();
+
+        for transaction in transactions() {
+            let _ = engine.process_journaled(transaction, &mut journal).unwrap();
+        }
+
+        engine
+    }
+
+    #[test]
+    fn only_accepted_transactions_are_journaled() {
+        let path = journal_path("accepted");
+        let engine = journaled_engine(&path);
+
+        let contents = Journal::read(&path).unwrap();
+        assert_eq!(contents.entries.len(), 6);
+        assert_eq!(contents.entries.last().unwrap().sequence, engine.sequence());
+        assert_eq!(contents.torn_len, 0);
+    }
+
+    #[test]
+    fn recover_from_journal() {
+        let path = journal_path("recover");
+        let engine = journaled_engine(&path);
+
+        let (recovered, _, recovery) = TransactionEngine::recover(EngineConfig::default(), None, &path, SyncPolicy::Always).unwrap();
+        let mut unjournaled = TransactionEngine::new();
+        process(&mut unjournaled, TRANSACTIONS);
+
+        assert_eq!(recovered, engine);
+        assert_eq!(recovered, unjournaled);
+        assert_eq!(recovery.replayed, 6);
+    }
+
+    #[test]
+    fn recover_from_snapshot_and_journal_tail() {
+        let path = journal_path("snapshot");
+        let mut journal = Journal::open(&path, SyncPolicy::Every(2)).unwrap();
+        let mut engine = TransactionEngine::new();
+        let mut snapshot = None;
+
+        for (i, transaction) in transactions().into_iter().enumerate() {
+            let _ = engine.process_journaled(transaction, &mut journal).unwrap();
+            if i == 3 {
+                snapshot = Some(engine.snapshot().unwrap());
+            }
+        }
+        drop(journal);
+
+        let (recovered, _, recovery) = TransactionEngine::recover(EngineConfig::default(), snapshot, &path, SyncPolicy::Always).unwrap();
+
+        assert_eq!(recovered, engine);
+        assert_eq!(recovery.skipped, 3);
+        assert_eq!(recovery.replayed, 3);
+    }
+
+    #[test]
+    fn recover_closed_disputes() {
+        let path = journal_path("deadline");
+        let config = EngineConfig {
+            dispute_deadline: Some(DisputeDeadline { after: Duration::from_secs(60), after_transactions: None, action: DeadlineAction::Chargeback }),
+            ..EngineConfig::default()
+        };
+        let mut journal = Journal::open(&path, SyncPolicy::Always).unwrap();
+        let mut engine = TransactionEngine::with_config(config.clone());
+        // both deposits of client 1, and the dispute of the first one
+        for index in [0, 1, 4] {
+            let transaction = transactions()[index].clone().at(Timestamp::new(100));
+            engine.process_journaled(transaction, &mut journal).unwrap().unwrap();
+        }
+
+        let closed = engine.advance_time_journaled(Timestamp::new(200), &mut journal).unwrap();
+        assert_eq!(closed.len(), 1);
+        drop(journal);
+        let (recovered, _, recovery) = TransactionEngine::recover(config, None, &path, SyncPolicy::Always).unwrap();
+
+        assert_eq!(recovered, engine);
+        assert_eq!(recovery.replayed, 4);
+    }
+
+    #[test]
+    fn recover_transaction_that_could_not_be_applied() {
+        let path = journal_path("unapplied");
+        let (first, second) = (AccountId::new(1), AccountId::new(2));
+        let accounts = FailingAccounts { failing: Some(second), ..FailingAccounts::default() };
+        let mut journal = Journal::open(&path, SyncPolicy::Always).unwrap();
+        let mut engine = TransactionEngine::with_stores(EngineConfig::default(), accounts, HashMap::new()).unwrap();
+        let mut expected = TransactionEngine::new();
+        process(&mut expected, r#"type, client, tx, amount
+                                  deposit,   1,  1,     50
+                                  deposit,   2,  2,     30"#);
+        let deposits = [
+            Transaction::deposit(TransactionId::new(1), first, Amount::from(50)),
+            Transaction::deposit(TransactionId::new(2), second, Amount::from(30)),
+        ];
+
+        engine.process_journaled(deposits[0].clone(), &mut journal).unwrap().unwrap();
+        let result = engine.process_journaled(deposits[1].clone(), &mut journal).unwrap();
+        assert!(matches!(result.unwrap_err().error(), TransactionError::Storage(_)));
+        // the next transaction would otherwise be journaled under the sequence number of the failed one
+        let result = engine.process_journaled(Transaction::deposit(TransactionId::new(3), first, Amount::from(10)), &mut journal);
+        assert!(matches!(result, Err(JournalError::Unapplied { sequence: 2 })), "{:?}", result);
+        drop(journal);
+
+        let (recovered, _, recovery) = TransactionEngine::recover(EngineConfig::default(), None, &path, SyncPolicy::Always).unwrap();
+        assert_eq!(recovery.replayed, 2);
+        assert_eq!(recovered, expected);
+    }
+
+    #[test]
+    fn recover_ignores_truncated_entry() {
+        let path = journal_path("truncated");
+        journaled_engine(&path);
+        let len = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 10).unwrap();
+
+        let (mut recovered, mut journal, recovery) = TransactionEngine::recover(EngineConfig::default(), None, &path, SyncPolicy::Always).unwrap();
+
+        // the torn resolve is lost, so the deposit is still disputed
+        assert_eq!(recovery.replayed, 5);
+        assert!(recovery.torn_len > 0);
+        assert!(recovered.transaction_state(transactions()[0].id()).unwrap().unwrap().is_disputed());
+
+        // the torn entry was removed, so the journal can be appended to again
+        let resolve = transactions().pop().unwrap();
+        recovered.process_journaled(resolve, &mut journal).unwrap().unwrap();
+        drop(journal);
+        let (recovered_again, _, recovery) = TransactionEngine::recover(EngineConfig::default(), None, &path, SyncPolicy::Always).unwrap();
+
+        assert_eq!(recovered_again, recovered);
+        assert_eq!(recovery.replayed, 6);
+        assert_eq!(recovery.torn_len, 0);
+    }
+
+    #[test]
+    fn recover_ignores_corrupted_last_entry() {
+        let path = journal_path("corrupted-last");
+        journaled_engine(&path);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let len = bytes.len();
+        bytes[len - 5] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+
+        let (_, _, recovery) = TransactionEngine::recover(EngineConfig::default(), None, &path, SyncPolicy::Always).unwrap();
+
+        assert_eq!(recovery.replayed, 5);
+    }
+
+    #[test]
+    fn recover_fails_on_corrupted_entry() {
+        let path = journal_path("corrupted");
+        journaled_engine(&path);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let second_line = bytes.iter().position(|byte| *byte == b'\n').unwrap() + 1;
+        bytes[second_line + 20] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+
+        assert!(matches!(
+            TransactionEngine::recover(EngineConfig::default(), None, &path, SyncPolicy::Always),
+            Err(JournalError::Corrupted { line: 2 })
+        ));
+    }
+
+    #[test]
+    fn recover_fails_on_gap() {
+        let path = journal_path("gap");
+        let engine = journaled_engine(&path);
+        let mut snapshot_engine = TransactionEngine::new();
+        snapshot_engine.handle_transaction(transactions().remove(0)).unwrap();
+        let snapshot = snapshot_engine.snapshot().unwrap();
+        std::fs::write(&path, "").unwrap();
+        let mut journal = Journal::open(&path, SyncPolicy::Never).unwrap();
+        journal.append(engine.sequence(), &transactions()[1]).unwrap();
+        drop(journal);
+
+        assert!(matches!(
+            TransactionEngine::recover(EngineConfig::default(), Some(snapshot), &path, SyncPolicy::Always),
+            Err(JournalError::Gap { expected: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn clear_after_snapshot() {
+        let path = journal_path("clear");
+        let mut journal = Journal::open(&path, SyncPolicy::Always).unwrap();
+        let mut engine = TransactionEngine::new();
+        for transaction in transactions() {
+            let _ = engine.process_journaled(transaction, &mut journal).unwrap();
+        }
+
+        let snapshot: Snapshot = engine.snapshot().unwrap();
+        journal.clear().unwrap();
+        drop(journal);
+
+        let (recovered, _, recovery) = TransactionEngine::recover(EngineConfig::default(), Some(snapshot), &path, SyncPolicy::Always).unwrap();
+        assert_eq!(recovered, engine);
+        assert_eq!(recovery.replayed, 0);
+    }
+}
@@ -0,0 +1,404 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::{AccountId, Amount, AmountError, Balances, Currency};
+
+/// An account of the general ledger
+///
+/// Every client has two ledger accounts, one for their available and one for their held
+/// funds. The remaining accounts belong to the system, and form the other side of all
+/// client postings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LedgerAccount {
+    /// The money received from and paid out to clients
+    Cash,
+    /// Amounts of disputed withdrawals that were provisionally credited to clients
+    ProvisionalCredits,
+    /// Amounts of disputed withdrawals that were charged back, and are therefore lost
+    ChargebackLosses,
+    /// The funds converted from and to each currency
+    FxPositions,
+    /// What was gained or lost by rounding converted amounts
+    FxGainsLosses,
+    /// Funds in transit between clients, that's always empty after a transaction
+    Transfers,
+    /// Funds clients already had before the ledger recorded their accounts
+    ///
+    /// These arise when the engine continues on stores or snapshots without a ledger.
+    OpeningBalances,
+    /// The available funds of a client
+    Available(AccountId),
+    /// The held funds of a client
+    Held(AccountId),
+}
+
+impl fmt::Display for LedgerAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cash => f.write_str("cash"),
+            Self::ProvisionalCredits => f.write_str("provisional_credits"),
+            Self::ChargebackLosses => f.write_str("chargeback_losses"),
+            Self::FxPositions => f.write_str("fx_positions"),
+            Self::FxGainsLosses => f.write_str("fx_gains_losses"),
+            Self::Transfers => f.write_str("transfers"),
+            Self::OpeningBalances => f.write_str("opening_balances"),
+            Self::Available(client) => write!(f, "available:{}", client),
+            Self::Held(client) => write!(f, "held:{}", client),
+        }
+    }
+}
+
+impl FromStr for LedgerAccount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let client = |id: &str| id
+            .parse()
+            .map(AccountId::new)
+            .map_err(|_| format!("invalid client id in ledger account `{}`", s));
+
+        match s.split_once(':') {
+            None if s == "cash" => Ok(Self::Cash),
+            None if s == "provisional_credits" => Ok(Self::ProvisionalCredits),
+            None if s == "chargeback_losses" => Ok(Self::ChargebackLosses),
+            None if s == "fx_positions" => Ok(Self::FxPositions),
+            None if s == "fx_gains_losses" => Ok(Self::FxGainsLosses),
+            None if s == "transfers" => Ok(Self::Transfers),
+            None if s == "opening_balances" => Ok(Self::OpeningBalances),
+            Some(("available", id)) => Ok(Self::Available(client(id)?)),
+            Some(("held", id)) => Ok(Self::Held(client(id)?)),
+            _ => Err(format!("unknown ledger account `{}`", s)),
+        }
+    }
+}
+
+impl serde::Serialize for LedgerAccount {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for LedgerAccount {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: serde::Deserializer<'de>
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// A single double-entry posting, that moves an amount from one ledger account to another
+///
+/// Since both sides of a posting always carry the same amount in the same currency, every
+/// posting is balanced.
+#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct Posting {
+    /// The account that is debited
+    pub debit: LedgerAccount,
+    /// The account that is credited
+    pub credit: LedgerAccount,
+    /// The currency of the amount
+    pub currency: Currency,
+    /// The amount that is moved
+    pub amount: Amount,
+}
+
+impl Posting {
+    pub(crate) fn new(debit: LedgerAccount, credit: LedgerAccount, currency: Currency, amount: Amount) -> Self {
+        Self { debit, credit, currency, amount }
+    }
+}
+
+/// The signed balance of a ledger account
+///
+/// Credit balances are positive, and debit balances are negative. So client accounts,
+/// which hold money owed to the clients, have positive balances, while [`LedgerAccount::Cash`]
+/// has a negative one. Unlike an [`Amount`], a balance is not limited to [`Amount::MAX`].
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LedgerBalance(i128);
+
+impl LedgerBalance {
+    /// The balance zero
+    pub const ZERO: Self = Self(0);
+
+    /// Creates a balance from a raw number of ten-thousandths
+    pub const fn from_raw(raw: i128) -> Self {
+        Self(raw)
+    }
+
+    /// The raw number of ten-thousandths this balance consists of
+    pub const fn raw(self) -> i128 {
+        self.0
+    }
+
+    /// Whether the balance is zero
+    pub const fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    /// The balance as an amount, if it's neither negative nor too large
+    pub fn to_amount(self) -> Option<Amount> {
+        u64::try_from(self.0).ok().map(Amount::from_raw)
+    }
+
+    fn credit(&mut self, amount: Amount) {
+        self.0 += i128::from(amount.raw());
+    }
+
+    fn debit(&mut self, amount: Amount) {
+        self.0 -= i128::from(amount.raw());
+    }
+}
+
+impl fmt::Display for LedgerBalance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scale = i128::from(Amount::SCALE);
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let whole = self.0.unsigned_abs() / scale as u128;
+        let fraction = (self.0.unsigned_abs() % scale as u128) as u64;
+
+        // the fraction is formatted like an amount, i.e. `0.5`, so the leading zero is skipped
+        match fraction {
+            0 => write!(f, "{}{}", sign, whole),
+            _ => write!(f, "{}{}{}", sign, whole, &Amount::from_raw(fraction).to_string()[1..]),
+        }
+    }
+}
+
+impl fmt::Debug for LedgerBalance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl FromStr for LedgerBalance {
+    type Err = AmountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (sign, magnitude) = match s.strip_prefix('-') {
+            Some(magnitude) => (-1, magnitude),
+            None => (1, s),
+        };
+        let (whole, fraction) = magnitude.split_once('.').unwrap_or((magnitude, "0"));
+
+        if whole.is_empty() || !whole.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(AmountError::Invalid);
+        }
+        let whole = whole
+            .parse::<i128>()
+            .map_err(|_| AmountError::Overflow)?;
+        let fraction = format!("0.{}", fraction).parse::<Amount>()?;
+
+        whole
+            .checked_mul(i128::from(Amount::SCALE))
+            .and_then(|raw| raw.checked_add(i128::from(fraction.raw())))
+            .map(|raw| Self(sign * raw))
+            .ok_or(AmountError::Overflow)
+    }
+}
+
+impl serde::Serialize for LedgerBalance {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for LedgerBalance {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: serde::Deserializer<'de>
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// The balance of one ledger account in one currency, see [`TrialBalance`]
+#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct LedgerLine {
+    pub account: LedgerAccount,
+    pub currency: Currency,
+    pub balance: LedgerBalance,
+}
+
+/// The balances of all ledger accounts at one point in time
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TrialBalance {
+    /// The balances of all ledger accounts, ordered by account and currency
+    pub lines: Vec<LedgerLine>,
+}
+
+impl TrialBalance {
+    /// The sum of all balances in each currency, ordered by currency
+    pub fn totals(&self) -> BTreeMap<Currency, LedgerBalance> {
+        let mut totals = BTreeMap::<_, LedgerBalance>::new();
+        for line in &self.lines {
+            totals.entry(line.currency).or_default().0 += line.balance.0;
+        }
+        totals
+    }
+
+    /// Whether the balances in each currency sum up to zero, as they always should in a
+    /// double-entry ledger
+    pub fn is_balanced(&self) -> bool {
+        self.totals().values().all(|total| total.is_zero())
+    }
+}
+
+/// A double-entry general ledger
+///
+/// The ledger records every movement of money as [`Posting`]s between [`LedgerAccount`]s.
+/// The balances of the client accounts are the available and held funds of the clients,
+/// while the system accounts record where the money came from and went to. Since every
+/// posting is balanced, all balances always sum up to zero. See [`Ledger::trial_balance`].
+///
+/// Every ledger account keeps a separate balance per currency, and the balances of each
+/// currency sum up to zero on their own.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Ledger {
+    balances: BTreeMap<(LedgerAccount, Currency), LedgerBalance>,
+}
+
+impl Ledger {
+    /// Creates a new, empty ledger
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Restores a ledger from the lines of a trial balance
+    pub(crate) fn from_lines(lines: impl IntoIterator<Item = LedgerLine>) -> Self {
+        let mut ledger = Self::new();
+        for line in lines {
+            ledger.balances.entry((line.account, line.currency)).or_default().0 += line.balance.0;
+        }
+        ledger
+    }
+
+    /// The balance of a ledger account in a currency
+    pub fn balance(&self, account: LedgerAccount, currency: Currency) -> LedgerBalance {
+        self.balances
+            .get(&(account, currency))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The funds of a client in a currency as derived from the postings, if the ledger
+    /// recorded the client
+    pub fn client_balances(&self, client: AccountId, currency: Currency) -> Option<Balances> {
+        if !self.contains_client(client) {
+            return None;
+        }
+
+        Some(Balances {
+            available: self.balance(LedgerAccount::Available(client), currency).to_amount()?,
+            held: self.balance(LedgerAccount::Held(client), currency).to_amount()?,
+        })
+    }
+
+    /// The currencies of all postings recorded for the client, ordered by their codes
+    pub(crate) fn client_currencies(&self, client: AccountId) -> BTreeSet<Currency> {
+        [LedgerAccount::Available(client), LedgerAccount::Held(client)]
+            .into_iter()
+            .flat_map(|account| self.balances.range((account, Currency::MIN)..=(account, Currency::MAX)))
+            .map(|(&(_, currency), _)| currency)
+            .collect()
+    }
+
+    /// Whether any postings for the client were recorded, in any currency
+    pub fn contains_client(&self, client: AccountId) -> bool {
+        [LedgerAccount::Available(client), LedgerAccount::Held(client)]
+            .into_iter()
+            .any(|account| self.balances
+                .range((account, Currency::MIN)..=(account, Currency::MAX))
+                .next()
+                .is_some())
+    }
+
+    /// The balances of all ledger accounts
+    pub fn trial_balance(&self) -> TrialBalance {
+        TrialBalance {
+            lines: self.balances
+                .iter()
+                .map(|(&(account, currency), &balance)| LedgerLine { account, currency, balance })
+                .collect(),
+        }
+    }
+
+    /// The recorded balances of the ledger accounts the postings affect
+    pub(crate) fn lines(&self, postings: &[Posting]) -> Vec<LedgerLine> {
+        postings
+            .iter()
+            .flat_map(|posting| [(posting.debit, posting.currency), (posting.credit, posting.currency)])
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .filter_map(|(account, currency)| self.balances
+                .get(&(account, currency))
+                .map(|&balance| LedgerLine { account, currency, balance }))
+            .collect()
+    }
+
+    /// Records a posting
+    pub(crate) fn post(&mut self, posting: &Posting) {
+        self.balances.entry((posting.debit, posting.currency)).or_default().debit(posting.amount);
+        self.balances.entry((posting.credit, posting.currency)).or_default().credit(posting.amount);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn postings_are_balanced() {
+        let client = AccountId::new(1);
+        let mut ledger = Ledger::new();
+        ledger.post(&Posting::new(LedgerAccount::Cash, LedgerAccount::Available(client), Currency::EUR, Amount::from(50)));
+        ledger.post(&Posting::new(LedgerAccount::Available(client), LedgerAccount::Held(client), Currency::EUR, Amount::from(20)));
+        ledger.post(&Posting::new(LedgerAccount::Cash, LedgerAccount::Available(client), Currency::GBP, Amount::from(10)));
+
+        assert_eq!(ledger.balance(LedgerAccount::Cash, Currency::EUR).to_string(), "-50");
+        assert_eq!(ledger.client_balances(client, Currency::EUR), Some(Balances { available: Amount::from(30), held: Amount::from(20) }));
+        assert_eq!(ledger.client_balances(client, Currency::GBP), Some(Balances { available: Amount::from(10), held: Amount::ZERO }));
+        assert!(!ledger.contains_client(AccountId::new(2)));
+        assert!(ledger.trial_balance().is_balanced());
+    }
+
+    #[test]
+    fn lines_of_postings() {
+        let client = AccountId::new(1);
+        let mut ledger = Ledger::new();
+        ledger.post(&Posting::new(LedgerAccount::Cash, LedgerAccount::Available(client), Currency::EUR, Amount::from(50)));
+        let postings = [
+            Posting::new(LedgerAccount::Available(client), LedgerAccount::Held(client), Currency::EUR, Amount::from(20)),
+            Posting::new(LedgerAccount::Cash, LedgerAccount::Available(client), Currency::GBP, Amount::from(10)),
+        ];
+
+        assert_eq!(ledger.lines(&postings[..1]), vec![
+            LedgerLine { account: LedgerAccount::Available(client), currency: Currency::EUR, balance: LedgerBalance(50 * 10_000) },
+        ]);
+    }
+
+    #[test]
+    fn balance_round_trips() {
+        for balance in ["0", "1.5", "-1.5", "-0.0001", "-340282366920938463463374607431768"] {
+            assert_eq!(balance.parse::<LedgerBalance>().unwrap().to_string(), balance);
+        }
+        assert!("--1".parse::<LedgerBalance>().is_err());
+        assert!("1.00001".parse::<LedgerBalance>().is_err());
+    }
+
+    #[test]
+    fn account_round_trips() {
+        for account in ["cash", "provisional_credits", "chargeback_losses", "fx_positions", "fx_gains_losses", "transfers", "opening_balances", "available:1", "held:65535"] {
+            assert_eq!(account.parse::<LedgerAccount>().unwrap().to_string(), account);
+        }
+        assert!("held:65536".parse::<LedgerAccount>().is_err());
+        assert!("bank".parse::<LedgerAccount>().is_err());
+    }
+}
@@ -1,14 +1,41 @@
 pub use self::{
-    account::{Account, AccountError, AccountId},
-    engine::{TransactionEngine, TransactionError},
-    transaction::{Transaction, TransactionId, TransactionType},
+    account::{Account, AccountError, AccountId, AccountOperation, AccountRow, AccountStatus},
+    amount::{Amount, AmountError},
+    currency::{Currency, CurrencyError},
+    disk::{DiskAccountStore, DiskTransactionStore},
+    dispute::{ClosedDispute, DeadlineAction, DisputableTransactions, DisputeDeadline, DisputeOpening, RedisputePolicy, TransactionState},
+    engine::{EngineConfig, TransactionEngine, TransactionError},
+    eviction::{Evicted, EvictedFunds, IdRange},
+    event::{Balances, LedgerEvent, LedgerEventKind},
+    fx::{Conversion, FxRate, FxRates, FxRounding, RatesError, RoundingMode},
+    invariants::InvariantViolation,
+    ledger::{Ledger, LedgerAccount, LedgerBalance, LedgerLine, Posting, TrialBalance},
+    journal::{Journal, JournalContents, JournalEntry, JournalError, Recovery, SyncPolicy},
+    outcome::Rejection,
+    snapshot::{Snapshot, SnapshotError},
+    sqlite::SqliteStore,
+    store::{AccountStore, StoreError, TransactionStore},
+    timestamp::Timestamp,
+    transaction::{StoredTransaction, Transaction, TransactionBuildError, TransactionBuilder, TransactionId, TransactionRow, TransactionType},
 };
 
 mod account;
+mod amount;
+mod currency;
+mod disk;
+mod dispute;
 mod engine;
+mod event;
+mod eviction;
+mod fx;
+mod invariants;
+mod journal;
+mod ledger;
+mod outcome;
+mod snapshot;
+mod sqlite;
+mod store;
+#[cfg(test)]
+mod testing;
+mod timestamp;
 mod transaction;
-
-/// An amount of money with a maximal precision of at least four decimals.
-///
-/// The maximum amount that can be represented is [`fixed::types::U50F14::MAX`].
-pub type Amount = fixed::types::U50F14;
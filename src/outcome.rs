@@ -0,0 +1,96 @@
+use crate::{AccountId, Transaction, TransactionError, TransactionId, TransactionType};
+
+/// A transaction that was rejected by the transaction engine, together with the
+/// context needed to report it
+///
+/// Rejections serialize to flat records with the columns `line`, `tx`, `client`, `type`,
+/// `reason` and `message`, where `reason` is the stable, machine-readable code of the
+/// error. See [`TransactionError::code`].
+#[derive(Debug)]
+pub struct Rejection {
+    line: Option<u64>,
+    id: TransactionId,
+    client: AccountId,
+    transaction_type: TransactionType,
+    error: TransactionError,
+}
+
+impl Rejection {
+    /// Creates a new rejection of a transaction
+    pub fn new(transaction: &Transaction, error: TransactionError) -> Self {
+        Self {
+            line: None,
+            id: transaction.id(),
+            client: transaction.client(),
+            transaction_type: transaction.transaction_type(),
+            error,
+        }
+    }
+
+    /// Attaches the input line number of the rejected transaction
+    pub fn at_line(mut self, line: u64) -> Self {
+        self.line = Some(line);
+        self
+    }
+
+    /// The input line number of the rejected transaction, if known
+    pub fn line(&self) -> Option<u64> {
+        self.line
+    }
+
+    /// The id of the rejected transaction
+    pub fn id(&self) -> TransactionId {
+        self.id
+    }
+
+    /// The account id of the rejected transaction
+    pub fn client(&self) -> AccountId {
+        self.client
+    }
+
+    /// The type of the rejected transaction
+    pub fn transaction_type(&self) -> TransactionType {
+        self.transaction_type
+    }
+
+    /// The reason the transaction was rejected
+    pub fn error(&self) -> &TransactionError {
+        &self.error
+    }
+
+    /// Returns the reason the transaction was rejected
+    pub fn into_error(self) -> TransactionError {
+        self.error
+    }
+}
+
+impl serde::Serialize for Rejection {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer
+    {
+        use serde::ser::SerializeStruct;
+        let mut map = serializer.serialize_struct("Rejection", 6)?;
+
+        map.serialize_field("line", &self.line)?;
+        map.serialize_field("tx", &self.id)?;
+        map.serialize_field("client", &self.client)?;
+        map.serialize_field("type", &self.transaction_type)?;
+        map.serialize_field("reason", self.error.code())?;
+        map.serialize_field("message", &self.error.to_string())?;
+
+        map.end()
+    }
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        write!(
+            f,
+            "{} {} of client {} rejected: {} ({})",
+            self.transaction_type, self.id, self.client, self.error, self.error.code(),
+        )
+    }
+}
@@ -0,0 +1,227 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::{Account, AccountId, EvictedFunds, IdRange, LedgerLine, StoreError, StoredTransaction, Timestamp, TransactionId};
+
+/// Possible errors to occur while saving or loading a snapshot
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("The snapshot is malformed: {0}")]
+    Format(#[from] serde_json::Error),
+    #[error("The snapshot version {0} is not supported")]
+    UnsupportedVersion(u32),
+    #[error("The snapshot contains the account {0} more than once")]
+    DuplicateAccount(AccountId),
+    #[error("The snapshot contains the transaction {0} more than once")]
+    DuplicateTransaction(TransactionId),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+/// A versioned copy of the full state of a [`TransactionEngine`](crate::TransactionEngine)
+///
+/// A snapshot contains all accounts, all saved deposits and withdrawals together with
+/// their dispute state, and the balances of the general ledger. So an engine restored
+/// from a snapshot behaves exactly like the engine the snapshot was taken from, i.e.
+/// open disputes can still be resolved.
+///
+/// Snapshots are serialized as JSON. The engine configuration is not part of the
+/// snapshot, and has to be provided when restoring the engine.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct Snapshot {
+    version: u32,
+    sequence: u64,
+    pub(crate) accounts: Vec<Account>,
+    pub(crate) transactions: Vec<StoredTransaction>,
+    pub(crate) ledger: Vec<LedgerLine>,
+    clock: Option<Timestamp>,
+    pub(crate) evicted: Vec<EvictedFunds>,
+    pub(crate) evicted_ids: Vec<IdRange>,
+}
+
+/// The version of a serialized snapshot, which is checked before the rest is read
+#[derive(serde::Deserialize)]
+struct SnapshotVersion {
+    version: u32,
+}
+
+impl Snapshot {
+    /// The current version of the snapshot format
+    pub const VERSION: u32 = 1;
+
+    pub(crate) fn new(
+        sequence: u64,
+        clock: Option<Timestamp>,
+        accounts: Vec<Account>,
+        transactions: Vec<StoredTransaction>,
+        ledger: Vec<LedgerLine>,
+        evicted: Vec<EvictedFunds>,
+        evicted_ids: Vec<IdRange>,
+    ) -> Self {
+        Self {
+            version: Self::VERSION,
+            sequence,
+            accounts,
+            transactions,
+            ledger,
+            clock,
+            evicted,
+            evicted_ids,
+        }
+    }
+
+    /// The version of the snapshot format
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// The sequence number of the engine at the time the snapshot was taken
+    ///
+    /// See [`TransactionEngine::sequence`](crate::TransactionEngine::sequence).
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// The clock of the engine at the time the snapshot was taken
+    ///
+    /// See [`TransactionEngine::clock`](crate::TransactionEngine::clock).
+    pub fn clock(&self) -> Option<Timestamp> {
+        self.clock
+    }
+
+    /// Writes the snapshot as JSON
+    pub fn write(&self, writer: impl Write) -> Result<(), SnapshotError> {
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+
+    /// Reads a snapshot from JSON
+    ///
+    /// Fails if the snapshot was written with an unsupported version of the format.
+    pub fn read(mut reader: impl Read) -> Result<Self, SnapshotError> {
+        let mut json = Vec::new();
+        reader.read_to_end(&mut json)?;
+        match serde_json::from_slice::<SnapshotVersion>(&json)?.version {
+            Self::VERSION => Ok(serde_json::from_slice(&json)?),
+            version => Err(SnapshotError::UnsupportedVersion(version)),
+        }
+    }
+
+    /// Saves the snapshot to a file
+    ///
+    /// The snapshot is first written to a temporary file next to the target, which is
+    /// then renamed. So an interrupted save never leaves a partially written snapshot.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        let path = path.as_ref();
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        self.write(&mut writer)?;
+        writer
+            .into_inner()
+            .map_err(|error| error.into_error())?
+            .sync_all()?;
+        std::fs::rename(&temporary, path)?;
+
+        Ok(())
+    }
+
+    /// Loads a snapshot from a file
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SnapshotError> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::process;
+    use crate::{EngineConfig, TransactionEngine};
+
+    const DAY_ONE: &str = r#"type, client, tx, amount, reason
+                             deposit,   1,  1,     50,
+                             deposit,   1,  2,     20,
+                             deposit,   2,  3,     30,
+                             deposit,   3,  4,     10,
+                             dispute,   1,  1,       ,
+                             dispute,   2,  3,       ,
+                             chargeback,2,  3,       ,
+                             freeze,    3,  5,       , fraud investigation"#;
+    const DAY_TWO: &str = r#"type, client, tx, amount, reason
+                             resolve,   1,  1,       ,
+                             dispute,   1,  2,       ,
+                             deposit,   1,  2,     99,
+                             deposit,   2,  6,     10,
+                             unlock,    3,  7,       ,
+                             withdrawal,3,  8,      5,"#;
+
+    #[test]
+    fn snapshot_round_trips() {
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, DAY_ONE);
+
+        let mut json = Vec::new();
+        engine.snapshot().unwrap().write(&mut json).unwrap();
+        let snapshot = Snapshot::read(json.as_slice()).unwrap();
+        let restored = TransactionEngine::from_snapshot(EngineConfig::default(), snapshot).unwrap();
+
+        assert_eq!(restored, engine);
+    }
+
+    #[test]
+    fn restored_engine_resumes_processing() {
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, DAY_ONE);
+        process(&mut engine, DAY_TWO);
+
+        let mut yesterday = TransactionEngine::new();
+        process(&mut yesterday, DAY_ONE);
+        let mut json = Vec::new();
+        yesterday.snapshot().unwrap().write(&mut json).unwrap();
+        let snapshot = Snapshot::read(json.as_slice()).unwrap();
+        let mut today = TransactionEngine::from_snapshot(EngineConfig::default(), snapshot).unwrap();
+        process(&mut today, DAY_TWO);
+
+        assert_eq!(today, engine);
+    }
+
+    #[test]
+    fn unsupported_version_fails() {
+        let json = r#"{"version":2,"accounts":[],"transactions":[]}"#;
+
+        assert!(matches!(
+            Snapshot::read(json.as_bytes()),
+            Err(SnapshotError::UnsupportedVersion(2))
+        ));
+    }
+
+    #[test]
+    fn duplicate_account_fails() {
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, DAY_ONE);
+        let mut snapshot = engine.snapshot().unwrap();
+        snapshot.accounts.push(snapshot.accounts[0].clone());
+
+        assert!(matches!(
+            TransactionEngine::from_snapshot(EngineConfig::default(), snapshot),
+            Err(SnapshotError::DuplicateAccount(_))
+        ));
+    }
+
+    #[test]
+    fn duplicate_transaction_fails() {
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, DAY_ONE);
+        let mut snapshot = engine.snapshot().unwrap();
+        snapshot.transactions.push(snapshot.transactions[0].clone());
+
+        assert!(matches!(
+            TransactionEngine::from_snapshot(EngineConfig::default(), snapshot),
+            Err(SnapshotError::DuplicateTransaction(_))
+        ));
+    }
+}
@@ -0,0 +1,670 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::rc::Rc;
+
+use rusqlite::{params, types::Type, Connection, OptionalExtension, Row};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{Account, AccountId, AccountStatus, AccountStore, Balances, Currency, DisputeOpening, Evicted, EvictedFunds, IdRange, LedgerAccount, LedgerLine, StoreError, StoredTransaction, Timestamp, Transaction, TransactionId, TransactionStore};
+
+/// The tables created in every database
+///
+/// Amounts are stored as decimal text, exactly as they are written to CSV, so no
+/// precision is lost. Like the CSV output, the accounts table contains one row per
+/// client and currency. The ledger table contains one row per ledger account and currency.
+/// The ids of evicted transactions are stored as ranges, and the clock of the engine as a
+/// key of the metadata table.
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS accounts (
+    client    INTEGER NOT NULL,
+    currency  TEXT    NOT NULL,
+    available TEXT    NOT NULL,
+    held      TEXT    NOT NULL,
+    total     TEXT    NOT NULL,
+    locked    INTEGER NOT NULL,
+    status    TEXT    NOT NULL,
+    reason    TEXT,
+    PRIMARY KEY (client, currency)
+);
+CREATE TABLE IF NOT EXISTS transactions (
+    tx        INTEGER PRIMARY KEY,
+    type      TEXT    NOT NULL,
+    client    INTEGER NOT NULL,
+    amount    TEXT,
+    currency  TEXT    NOT NULL,
+    target_currency TEXT,
+    destination INTEGER,
+    reason    TEXT,
+    timestamp INTEGER,
+    state     TEXT    NOT NULL,
+    disputes  INTEGER NOT NULL,
+    disputed  TEXT    NOT NULL,
+    charged_back TEXT NOT NULL,
+    opened_at INTEGER,
+    opened_sequence INTEGER,
+    converted TEXT
+);
+CREATE INDEX IF NOT EXISTS transactions_client ON transactions (client);
+CREATE TABLE IF NOT EXISTS ledger (
+    account   TEXT    NOT NULL,
+    currency  TEXT    NOT NULL,
+    balance   TEXT    NOT NULL,
+    PRIMARY KEY (account, currency)
+);
+CREATE TABLE IF NOT EXISTS evicted_ids (
+    first     INTEGER PRIMARY KEY,
+    last      INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS evicted_funds (
+    client    INTEGER NOT NULL,
+    currency  TEXT    NOT NULL,
+    total     TEXT    NOT NULL,
+    PRIMARY KEY (client, currency)
+);
+CREATE TABLE IF NOT EXISTS metadata (
+    key       TEXT    PRIMARY KEY,
+    value     INTEGER NOT NULL
+);
+"#;
+
+/// An [`AccountStore`] and [`TransactionStore`] that persists everything in an SQLite database
+///
+/// The store can be cloned cheaply, and all clones share the same database connection.
+/// This way, one store can be used for both accounts and transactions:
+/// ```no_run
+/// # use transaction_engine::{EngineConfig, SqliteStore, TransactionEngine};
+/// let store = SqliteStore::open("engine.db")?;
+/// let engine = TransactionEngine::with_stores(EngineConfig::default(), store.clone(), store)?;
+/// # Ok::<(), transaction_engine::StoreError>(())
+/// ```
+///
+/// Every write is committed on its own, unless writes are grouped using
+/// [`SqliteStore::begin`] and [`SqliteStore::commit`].
+#[derive(Clone, Debug)]
+pub struct SqliteStore {
+    connection: Rc<Connection>,
+}
+
+impl SqliteStore {
+    /// Opens the database at the specified path, and creates it if it doesn't exist yet
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    /// Opens a new database that only lives in memory
+    pub fn open_in_memory() -> Result<Self, StoreError> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(connection: Connection) -> Result<Self, StoreError> {
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self { connection: Rc::new(connection) })
+    }
+
+    /// Performs multiple writes, that are either all applied, or not at all
+    ///
+    /// This uses a savepoint, so it also works within [`SqliteStore::begin`].
+    fn atomically(&self, writes: impl FnOnce() -> Result<(), StoreError>) -> Result<(), StoreError> {
+        self.connection.execute_batch("SAVEPOINT writes")?;
+        match writes() {
+            Ok(()) => {
+                self.connection.execute_batch("RELEASE writes")?;
+                Ok(())
+            }
+            Err(error) => {
+                self.connection.execute_batch("ROLLBACK TO writes; RELEASE writes")?;
+                Err(error)
+            }
+        }
+    }
+
+    /// Starts a database transaction, that groups all following writes until [`SqliteStore::commit`]
+    ///
+    /// If the store is dropped before committing, all writes since the start are discarded.
+    pub fn begin(&self) -> Result<(), StoreError> {
+        self.connection.execute_batch("BEGIN")?;
+        Ok(())
+    }
+
+    /// Commits the database transaction started by [`SqliteStore::begin`]
+    pub fn commit(&self) -> Result<(), StoreError> {
+        self.connection.execute_batch("COMMIT")?;
+        Ok(())
+    }
+
+    /// Reads one row of the accounts table, that contains the funds in a single currency
+    fn account(row: &Row<'_>) -> rusqlite::Result<Account> {
+        let status = AccountStatus::from_name(&row.get::<_, String>("status")?, row.get("reason")?)
+            .map_err(|error| malformed(row, "status", error.into()))?;
+        let funds = Balances {
+            available: text(row, "available")?,
+            held: text(row, "held")?,
+        };
+
+        Account::restore(
+            AccountId::new(row.get("client")?),
+            BTreeMap::from([(text(row, "currency")?, funds)]),
+            status,
+        )
+        .map_err(|error| malformed(row, "available", error.into()))
+    }
+
+    /// Reads the accounts of a query ordered by client, and combines the rows of each client
+    fn accounts(query: &str, params: impl rusqlite::Params, connection: &Connection) -> Result<Vec<Account>, StoreError> {
+        let mut accounts = Vec::<Account>::new();
+        for account in connection.prepare_cached(query)?.query_map(params, Self::account)? {
+            let account = account?;
+            match accounts.last_mut().filter(|last| last.id() == account.id()) {
+                Some(last) => last.merge(account),
+                None => accounts.push(account),
+            }
+        }
+
+        Ok(accounts)
+    }
+
+    fn record(row: &Row<'_>) -> rusqlite::Result<StoredTransaction> {
+        let transaction = Transaction::new(
+            TransactionId::new(row.get("tx")?),
+            text(row, "type")?,
+            AccountId::new(row.get("client")?),
+            optional_text(row, "amount")?,
+            text(row, "currency")?,
+            row.get("reason")?,
+        )
+        .with_target_currency(optional_text(row, "target_currency")?)
+        .with_destination(row.get::<_, Option<u16>>("destination")?.map(AccountId::new))
+        .with_timestamp(optional_timestamp(row, "timestamp")?);
+        let opened = match row.get::<_, Option<i64>>("opened_sequence")? {
+            Some(sequence) => Some(DisputeOpening { timestamp: optional_timestamp(row, "opened_at")?, sequence: sequence as u64 }),
+            None => None,
+        };
+
+        Ok(StoredTransaction::restore(
+            transaction,
+            text(row, "state")?,
+            row.get("disputes")?,
+            optional_text(row, "converted")?,
+        )
+        .with_portions(text(row, "disputed")?, text(row, "charged_back")?)
+        .map_err(|error| malformed(row, "disputed", error.into()))?
+        .with_opening(opened))
+    }
+}
+
+impl AccountStore for SqliteStore {
+    fn get(&self, id: AccountId) -> Result<Option<Account>, StoreError> {
+        Ok(Self::accounts("SELECT * FROM accounts WHERE client = ?1", params![id.value()], &self.connection)?.pop())
+    }
+
+    fn insert(&mut self, account: Account) -> Result<(), StoreError> {
+        self.atomically(|| {
+            self.connection
+                .prepare_cached("DELETE FROM accounts WHERE client = ?1")?
+                .execute(params![account.id().value()])?;
+            for row in account.rows() {
+                self.connection
+                    .prepare_cached("INSERT INTO accounts VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)")?
+                    .execute(params![
+                        row.client.value(),
+                        row.currency.code(),
+                        to_text(&row.available)?,
+                        to_text(&row.held)?,
+                        to_text(&row.total)?,
+                        row.locked,
+                        row.status,
+                        row.reason,
+                    ])?;
+            }
+            Ok(())
+        })
+    }
+
+    fn remove(&mut self, id: AccountId) -> Result<(), StoreError> {
+        self.connection
+            .prepare_cached("DELETE FROM accounts WHERE client = ?1")?
+            .execute(params![id.value()])?;
+        Ok(())
+    }
+
+    fn accounts(&self) -> Result<Vec<Account>, StoreError> {
+        Self::accounts("SELECT * FROM accounts ORDER BY client, currency", [], &self.connection)
+    }
+
+    fn ledger(&self) -> Result<Vec<LedgerLine>, StoreError> {
+        Ok(self.connection
+            .prepare_cached("SELECT * FROM ledger")?
+            .query_map([], |row| Ok(LedgerLine {
+                account: text(row, "account")?,
+                currency: text(row, "currency")?,
+                balance: text(row, "balance")?,
+            }))?
+            .collect::<Result<_, _>>()?)
+    }
+
+    fn insert_ledger(&mut self, lines: &[LedgerLine]) -> Result<(), StoreError> {
+        self.atomically(|| {
+            for line in lines {
+                self.connection
+                    .prepare_cached("INSERT OR REPLACE INTO ledger VALUES (?1, ?2, ?3)")?
+                    .execute(params![line.account.to_string(), line.currency.code(), line.balance.to_string()])?;
+            }
+            Ok(())
+        })
+    }
+
+    fn remove_ledger(&mut self, lines: &[(LedgerAccount, Currency)]) -> Result<(), StoreError> {
+        self.atomically(|| {
+            for (account, currency) in lines {
+                self.connection
+                    .prepare_cached("DELETE FROM ledger WHERE account = ?1 AND currency = ?2")?
+                    .execute(params![account.to_string(), currency.code()])?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl TransactionStore for SqliteStore {
+    fn get(&self, id: TransactionId) -> Result<Option<StoredTransaction>, StoreError> {
+        Ok(self.connection
+            .prepare_cached("SELECT * FROM transactions WHERE tx = ?1")?
+            .query_row(params![id.value()], Self::record)
+            .optional()?)
+    }
+
+    fn insert(&mut self, record: StoredTransaction) -> Result<(), StoreError> {
+        let transaction = record.transaction();
+        self.connection
+            .prepare_cached("INSERT OR REPLACE INTO transactions VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)")?
+            .execute(params![
+                transaction.id().value(),
+                to_text(&transaction.transaction_type())?,
+                transaction.client().value(),
+                transaction.amount().as_ref().map(to_text).transpose()?,
+                transaction.currency().code(),
+                transaction.target_currency().map(|currency| currency.code().to_owned()),
+                transaction.destination().map(AccountId::value),
+                transaction.reason(),
+                // SQLite only knows signed integers, the casts round-trip all values
+                transaction.timestamp().map(|timestamp| timestamp.value() as i64),
+                to_text(&record.state())?,
+                record.disputes(),
+                to_text(&record.disputed())?,
+                to_text(&record.charged_back())?,
+                record.opened().and_then(|opened| opened.timestamp).map(|timestamp| timestamp.value() as i64),
+                record.opened().map(|opened| opened.sequence as i64),
+                record.converted().as_ref().map(to_text).transpose()?,
+            ])?;
+        Ok(())
+    }
+
+    fn remove(&mut self, id: TransactionId) -> Result<(), StoreError> {
+        self.connection
+            .prepare_cached("DELETE FROM transactions WHERE tx = ?1")?
+            .execute(params![id.value()])?;
+        Ok(())
+    }
+
+    fn records(&self) -> Box<dyn Iterator<Item = Result<StoredTransaction, StoreError>> + '_> {
+        // rusqlite's row iterators borrow the statement, so the records are collected first
+        let records = self.connection
+            .prepare_cached("SELECT * FROM transactions ORDER BY tx")
+            .and_then(|mut statement| statement
+                .query_map([], Self::record)?
+                .collect::<Result<Vec<_>, _>>());
+
+        match records {
+            Ok(records) => Box::new(records.into_iter().map(Ok)),
+            Err(error) => Box::new(std::iter::once(Err(error.into()))),
+        }
+    }
+
+    fn evicted(&self) -> Result<Evicted, StoreError> {
+        let ids = self.connection
+            .prepare_cached("SELECT first, last FROM evicted_ids")?
+            .query_map([], |row| Ok(IdRange {
+                first: TransactionId::new(row.get("first")?),
+                last: TransactionId::new(row.get("last")?),
+            }))?
+            .collect::<Result<_, _>>()?;
+        let funds = self.connection
+            .prepare_cached("SELECT client, currency, total FROM evicted_funds")?
+            .query_map([], |row| Ok(EvictedFunds {
+                client: AccountId::new(row.get("client")?),
+                currency: text(row, "currency")?,
+                total: text(row, "total")?,
+            }))?
+            .collect::<Result<_, _>>()?;
+
+        Ok(Evicted { ids, funds })
+    }
+
+    fn insert_evicted(&mut self, evicted: &Evicted) -> Result<(), StoreError> {
+        self.atomically(|| {
+            for range in &evicted.ids {
+                self.connection
+                    .prepare_cached("DELETE FROM evicted_ids WHERE first BETWEEN ?1 AND ?2")?
+                    .execute(params![range.first.value(), range.last.value()])?;
+                self.connection
+                    .prepare_cached("INSERT INTO evicted_ids VALUES (?1, ?2)")?
+                    .execute(params![range.first.value(), range.last.value()])?;
+            }
+            for funds in &evicted.funds {
+                self.connection
+                    .prepare_cached("INSERT OR REPLACE INTO evicted_funds VALUES (?1, ?2, ?3)")?
+                    .execute(params![funds.client.value(), funds.currency.code(), funds.total.to_string()])?;
+            }
+            Ok(())
+        })
+    }
+
+    fn remove_evicted(&mut self, evicted: &Evicted) -> Result<(), StoreError> {
+        self.atomically(|| {
+            for range in &evicted.ids {
+                self.connection
+                    .prepare_cached("DELETE FROM evicted_ids WHERE first = ?1")?
+                    .execute(params![range.first.value()])?;
+            }
+            for funds in &evicted.funds {
+                self.connection
+                    .prepare_cached("DELETE FROM evicted_funds WHERE client = ?1 AND currency = ?2")?
+                    .execute(params![funds.client.value(), funds.currency.code()])?;
+            }
+            Ok(())
+        })
+    }
+
+    fn clock(&self) -> Result<Option<Timestamp>, StoreError> {
+        Ok(self.connection
+            .prepare_cached("SELECT value FROM metadata WHERE key = 'clock'")?
+            .query_row([], |row| row.get::<_, i64>(0))
+            .optional()?
+            .map(|clock| Timestamp::new(clock as u64)))
+    }
+
+    fn set_clock(&mut self, clock: Option<Timestamp>) -> Result<(), StoreError> {
+        match clock {
+            Some(clock) => self.connection
+                .prepare_cached("INSERT OR REPLACE INTO metadata VALUES ('clock', ?1)")?
+                .execute(params![clock.value() as i64])?,
+            None => self.connection
+                .prepare_cached("DELETE FROM metadata WHERE key = 'clock'")?
+                .execute([])?,
+        };
+        Ok(())
+    }
+}
+
+/// Reads an optional timestamp, which is stored as a signed integer
+fn optional_timestamp(row: &Row<'_>, column: &str) -> rusqlite::Result<Option<Timestamp>> {
+    Ok(row.get::<_, Option<i64>>(column)?.map(|timestamp| Timestamp::new(timestamp as u64)))
+}
+
+/// The text representation of a value that serializes as a string, i.e. an amount
+fn to_text(value: &impl Serialize) -> Result<String, StoreError> {
+    match serde_json::to_value(value)? {
+        serde_json::Value::String(text) => Ok(text),
+        value => Ok(value.to_string()),
+    }
+}
+
+/// Reads a text column, and deserializes the value from it
+fn text<V: DeserializeOwned>(row: &Row<'_>, column: &str) -> rusqlite::Result<V> {
+    let text = row.get::<_, String>(column)?;
+    serde_json::from_value(serde_json::Value::String(text)).map_err(|error| malformed(row, column, Box::new(error)))
+}
+
+/// The error for a text column whose value cannot be used
+fn malformed(row: &Row<'_>, column: &str, error: Box<dyn std::error::Error + Send + Sync>) -> rusqlite::Error {
+    let index = row.as_ref().column_index(column).unwrap_or_default();
+    rusqlite::Error::FromSqlConversionFailure(index, Type::Text, error)
+}
+
+/// Reads a column like [`text`], that may also be `NULL`
+fn optional_text<V: DeserializeOwned>(row: &Row<'_>, column: &str) -> rusqlite::Result<Option<V>> {
+    row.get::<_, Option<String>>(column)?
+        .map(|_| text(row, column))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{process, TempPath};
+    use crate::{EngineConfig, Snapshot, TransactionEngine};
+
+    const DAY_ONE: &str = r#"type, client, tx, amount, reason
+                             deposit,   1,  1,     50,
+                             deposit,   1,  2,   20.5,
+                             deposit,   2,  3,     30,
+                             withdrawal,1,  4,     10,
+                             deposit,   3,  5,     10,
+                             dispute,   1,  1,       ,
+                             dispute,   2,  3,       ,
+                             chargeback,2,  3,       ,
+                             freeze,    3,  6,       , fraud investigation"#;
+    const DAY_TWO: &str = r#"type, client, tx, amount, currency, reason
+                             resolve,   1,  1,       ,         ,
+                             deposit,   1,  2,     99,         ,
+                             unlock,    3,  7,       ,         ,
+                             withdrawal,3,  8,      5,         ,
+                             deposit,   3,  9,     40,      EUR,
+                             dispute,   3,  9,       ,         ,"#;
+
+    fn memory_snapshot(days: &[&str]) -> Snapshot {
+        let mut engine = TransactionEngine::new();
+        for day in days {
+            process(&mut engine, day);
+        }
+        engine.snapshot().unwrap()
+    }
+
+    #[test]
+    fn sqlite_engine_matches_memory_engine() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let mut engine = TransactionEngine::with_stores(EngineConfig::default(), store.clone(), store).unwrap();
+        process(&mut engine, DAY_ONE);
+        process(&mut engine, DAY_TWO);
+
+        assert_eq!(engine.snapshot().unwrap(), memory_snapshot(&[DAY_ONE, DAY_TWO]));
+    }
+
+    #[test]
+    fn state_persists_across_runs() {
+        let path = TempPath::new("runs.db");
+
+        for day in [DAY_ONE, DAY_TWO] {
+            let store = SqliteStore::open(&path).unwrap();
+            let mut engine = TransactionEngine::with_stores(EngineConfig::default(), store.clone(), store).unwrap();
+            process(&mut engine, day);
+        }
+
+        let store = SqliteStore::open(&path).unwrap();
+        let engine = TransactionEngine::with_stores(EngineConfig::default(), store.clone(), store).unwrap();
+        let snapshot = engine.snapshot().unwrap();
+        let expected = memory_snapshot(&[DAY_ONE, DAY_TWO]);
+        // the sequence number belongs to the engine, not the stores, and restarts with every run
+        let without_sequence = |transactions: Vec<StoredTransaction>| {
+            transactions
+                .into_iter()
+                .map(|record| {
+                    let opened = record.opened().map(|opened| DisputeOpening { sequence: 0, ..opened });
+                    record.with_opening(opened)
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(snapshot.accounts, expected.accounts);
+        assert_eq!(without_sequence(snapshot.transactions), without_sequence(expected.transactions));
+        assert_eq!(snapshot.ledger, expected.ledger);
+    }
+
+    #[test]
+    fn account_funds_are_derived_from_the_ledger() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let mut engine = TransactionEngine::with_stores(EngineConfig::default(), store.clone(), store.clone()).unwrap();
+        process(&mut engine, "type, client, tx, amount\ndeposit, 1, 1, 50\n");
+        store.connection.execute("UPDATE accounts SET available = '80', total = '80'", []).unwrap();
+
+        let events = engine.handle_transaction(Transaction::deposit(TransactionId::new(2), AccountId::new(1), crate::Amount::from(10))).unwrap();
+        assert_eq!(events[0].after.available, crate::Amount::from(60));
+        assert_eq!(AccountStore::get(&store, AccountId::new(1)).unwrap().unwrap().available(Currency::USD), crate::Amount::from(60));
+    }
+
+    #[test]
+    fn corrupted_portions_are_rejected() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let mut engine = TransactionEngine::with_stores(EngineConfig::default(), store.clone(), store.clone()).unwrap();
+        process(&mut engine, "type, client, tx, amount\ndeposit, 1, 1, 50\ndispute, 1, 1,\n");
+        store.connection.execute("UPDATE transactions SET disputed = '60'", []).unwrap();
+
+        assert!(matches!(engine.transaction(TransactionId::new(1)), Err(StoreError::Database(_))));
+        assert!(engine.handle_transaction(Transaction::dispute(TransactionId::new(1), AccountId::new(1))).is_err());
+    }
+
+    #[test]
+    fn uncommitted_writes_are_discarded() {
+        let path = TempPath::new("uncommitted.db");
+
+        let store = SqliteStore::open(&path).unwrap();
+        store.begin().unwrap();
+        let mut engine = TransactionEngine::with_stores(EngineConfig::default(), store.clone(), store).unwrap();
+        process(&mut engine, DAY_ONE);
+        drop(engine);
+
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(AccountStore::accounts(&store).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn conversions_are_saved() {
+        let mut config = EngineConfig::default();
+        config.rates.insert(Currency::EUR, Currency::USD, "1.085".parse().unwrap());
+        let store = SqliteStore::open_in_memory().unwrap();
+        let mut engine = TransactionEngine::with_stores(config, store.clone(), store).unwrap();
+        process(&mut engine, "type, client, tx, amount, currency, target_currency\ndeposit, 1, 1, 50, EUR,\nconvert, 1, 2, 10, EUR, USD\n");
+
+        let record = engine.transaction(TransactionId::new(2)).unwrap().unwrap();
+        assert_eq!(record.transaction().target_currency(), Some(Currency::USD));
+        assert_eq!(record.converted(), Some("10.85".parse().unwrap()));
+    }
+
+    #[test]
+    fn transfers_are_saved() {
+        let transactions = "type, client, tx, amount, destination\ndeposit, 1, 1, 50,\ndeposit, 2, 2, 10,\ntransfer, 1, 3, 30, 2\n";
+        let store = SqliteStore::open_in_memory().unwrap();
+        let mut engine = TransactionEngine::with_stores(EngineConfig::default(), store.clone(), store).unwrap();
+        process(&mut engine, transactions);
+
+        let record = engine.transaction(TransactionId::new(3)).unwrap().unwrap();
+        assert_eq!(record.transaction().destination(), Some(AccountId::new(2)));
+        assert_eq!(engine.snapshot().unwrap(), memory_snapshot(&[transactions]));
+    }
+
+    #[test]
+    fn partial_disputes_are_saved() {
+        let transactions = "type, client, tx, amount\ndeposit, 1, 1, 50\ndispute, 1, 1, 20\nchargeback, 1, 1, 5\n";
+        let store = SqliteStore::open_in_memory().unwrap();
+        let mut engine = TransactionEngine::with_stores(EngineConfig::default(), store.clone(), store).unwrap();
+        process(&mut engine, transactions);
+
+        let record = engine.transaction(TransactionId::new(1)).unwrap().unwrap();
+        assert_eq!(record.disputed(), crate::Amount::from(15));
+        assert_eq!(record.charged_back(), crate::Amount::from(5));
+        assert_eq!(record.opened().map(|opened| opened.sequence), Some(2));
+        assert_eq!(engine.snapshot().unwrap(), memory_snapshot(&[transactions]));
+    }
+
+    #[test]
+    fn expired_transactions_are_deleted() {
+        let config = EngineConfig { dispute_window: Some(std::time::Duration::from_secs(100)), ..EngineConfig::default() };
+        let store = SqliteStore::open_in_memory().unwrap();
+        let mut engine = TransactionEngine::with_stores(config, store.clone(), store).unwrap();
+        process(&mut engine, "type, client, tx, amount, timestamp\ndeposit, 1, 1, 50, 0\ndeposit, 1, 2, 10, 150\n");
+
+        assert!(engine.transaction(TransactionId::new(1)).unwrap().is_none());
+        let record = engine.transaction(TransactionId::new(2)).unwrap().unwrap();
+        assert_eq!(record.transaction().timestamp(), Some(crate::Timestamp::new(150)));
+        assert_eq!(engine.verify_invariants().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn eviction_persists_across_runs() {
+        let path = TempPath::new("eviction.db");
+        let config = EngineConfig { dispute_window: Some(std::time::Duration::from_secs(100)), ..EngineConfig::default() };
+        let days = [
+            "type, client, tx, amount, timestamp\ndeposit, 1, 1, 10, 0\ndeposit, 1, 2, 5, 1000\n",
+            "type, client, tx, amount, timestamp\ndeposit, 1, 3, 1, 1050\ndeposit, 1, 1, 10, 1060\ndeposit, 1, 4, 1, 1101\n",
+        ];
+
+        for day in days {
+            let store = SqliteStore::open(&path).unwrap();
+            let mut engine = TransactionEngine::with_stores(config.clone(), store.clone(), store).unwrap();
+            process(&mut engine, day);
+            assert_eq!(engine.verify_invariants().unwrap(), vec![]);
+        }
+
+        let store = SqliteStore::open(&path).unwrap();
+        let mut engine = TransactionEngine::with_stores(config, store.clone(), store).unwrap();
+        assert_eq!(engine.clock(), Some(crate::Timestamp::new(1101)));
+        // the deposit of the first run was evicted by the second one
+        assert!(engine.transaction(TransactionId::new(2)).unwrap().is_none());
+        assert_eq!(AccountStore::get(engine.accounts(), AccountId::new(1)).unwrap().unwrap().available(Currency::USD), crate::Amount::from(17));
+        assert!(matches!(
+            engine.handle_transaction(Transaction::dispute(TransactionId::new(1), AccountId::new(1))),
+            Err(crate::TransactionError::DisputeWindowExpired),
+        ));
+    }
+
+    #[test]
+    fn evictions_can_be_removed() {
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        let range = |first, last| crate::IdRange { first: TransactionId::new(first), last: TransactionId::new(last) };
+        let funds = |total: &str| crate::EvictedFunds { client: AccountId::new(1), currency: Currency::EUR, total: total.parse().unwrap() };
+        let previous = Evicted { ids: vec![range(3, 7)], funds: vec![funds("12.5")] };
+        store.insert_evicted(&previous).unwrap();
+        let evicted = Evicted { ids: vec![range(3, 8)], funds: vec![funds("15")] };
+        store.insert_evicted(&evicted).unwrap();
+
+        store.remove_evicted(&evicted).unwrap();
+        assert_eq!(store.evicted().unwrap(), Evicted::default());
+        store.insert_evicted(&previous).unwrap();
+        assert_eq!(store.evicted().unwrap(), previous);
+    }
+
+    #[test]
+    fn unknown_status_fails() {
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        AccountStore::insert(&mut store, Account::new(AccountId::new(1))).unwrap();
+        store.connection.execute("UPDATE accounts SET status = 'suspended'", []).unwrap();
+
+        assert!(matches!(AccountStore::get(&store, AccountId::new(1)), Err(StoreError::Database(_))));
+    }
+
+    #[test]
+    fn balances_can_be_queried() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let mut engine = TransactionEngine::with_stores(EngineConfig::default(), store.clone(), store.clone()).unwrap();
+        process(&mut engine, DAY_ONE);
+
+        let balances = store.connection
+            .prepare("SELECT client, available, held, total, status FROM accounts ORDER BY client")
+            .unwrap()
+            .query_map([], |row| Ok((
+                row.get::<_, u16>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+            )))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(balances, vec![
+            (1, "10.5".to_owned(), "50".to_owned(), "60.5".to_owned(), "active".to_owned()),
+            (2, "0".to_owned(), "0".to_owned(), "0".to_owned(), "locked".to_owned()),
+            (3, "10".to_owned(), "0".to_owned(), "10".to_owned(), "frozen".to_owned()),
+        ]);
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+
+use crate::{Account, AccountId, Currency, Evicted, LedgerAccount, LedgerLine, StoredTransaction, Timestamp, TransactionId};
+
+/// Possible errors to occur while reading from or writing to a store
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("The stored data is malformed: {0}")]
+    Format(#[from] serde_json::Error),
+    #[error(transparent)]
+    Database(#[from] rusqlite::Error),
+    #[error("The record takes up {size} bytes, but at most {max} bytes can be stored")]
+    RecordTooLarge { size: usize, max: usize },
+}
+
+/// The storage of all accounts of a [`TransactionEngine`](crate::TransactionEngine)
+///
+/// Stores hand out copies of their accounts, so the engine can check a transaction
+/// before any changes are written back.
+pub trait AccountStore {
+    /// The account with the specified id, if it exists
+    fn get(&self, id: AccountId) -> Result<Option<Account>, StoreError>;
+
+    /// Inserts or replaces an account
+    fn insert(&mut self, account: Account) -> Result<(), StoreError>;
+
+    /// Removes an account, if it exists
+    fn remove(&mut self, id: AccountId) -> Result<(), StoreError>;
+
+    /// All accounts, ordered by their ids
+    fn accounts(&self) -> Result<Vec<Account>, StoreError>;
+
+    /// All balances of the general ledger, see [`Ledger`](crate::Ledger)
+    ///
+    /// Stores that don't outlive their engine, like the in-memory store, don't have to keep
+    /// the ledger, since the engine keeps it as well. Engines on stores without a ledger
+    /// open the ledger accounts of existing clients with their current funds.
+    fn ledger(&self) -> Result<Vec<LedgerLine>, StoreError> {
+        Ok(Vec::new())
+    }
+
+    /// Inserts or replaces balances of the general ledger
+    fn insert_ledger(&mut self, _lines: &[LedgerLine]) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    /// Removes balances of the general ledger, if they exist
+    fn remove_ledger(&mut self, _lines: &[(LedgerAccount, Currency)]) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+/// The storage of all saved deposits and withdrawals of a [`TransactionEngine`](crate::TransactionEngine)
+///
+/// Since every transaction id can be used once, this store can grow up to `u32::MAX`
+/// records, so implementations don't have to keep all records in memory.
+pub trait TransactionStore {
+    /// The record of the transaction with the specified id, if it exists
+    fn get(&self, id: TransactionId) -> Result<Option<StoredTransaction>, StoreError>;
+
+    /// Whether there's a record of the transaction with the specified id
+    fn contains(&self, id: TransactionId) -> Result<bool, StoreError> {
+        Ok(self.get(id)?.is_some())
+    }
+
+    /// Inserts or replaces the record of a transaction
+    fn insert(&mut self, record: StoredTransaction) -> Result<(), StoreError>;
+
+    /// Removes the record of a transaction, if it exists
+    fn remove(&mut self, id: TransactionId) -> Result<(), StoreError>;
+
+    /// All records, ordered by their transaction ids
+    fn records(&self) -> Box<dyn Iterator<Item = Result<StoredTransaction, StoreError>> + '_>;
+
+    /// The ids and funds of the transactions that were evicted from the store
+    ///
+    /// See [`EngineConfig::dispute_window`](crate::EngineConfig::dispute_window). Like the
+    /// ledger, stores that don't outlive their engine don't have to keep them.
+    fn evicted(&self) -> Result<Evicted, StoreError> {
+        Ok(Evicted::default())
+    }
+
+    /// Adds ranges of evicted ids, and replaces the evicted funds of the specified clients
+    /// and currencies
+    ///
+    /// Every range replaces all ranges that start within it. The records of the ids have to
+    /// be removed separately.
+    fn insert_evicted(&mut self, _evicted: &Evicted) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    /// Removes ranges of evicted ids by their first ids, and the evicted funds of the
+    /// specified clients and currencies, if they exist
+    ///
+    /// This undoes an [`TransactionStore::insert_evicted`] whose transaction failed.
+    fn remove_evicted(&mut self, _evicted: &Evicted) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    /// The clock of the engine, see [`TransactionEngine::clock`](crate::TransactionEngine::clock)
+    fn clock(&self) -> Result<Option<Timestamp>, StoreError> {
+        Ok(None)
+    }
+
+    /// Replaces the clock of the engine
+    fn set_clock(&mut self, _clock: Option<Timestamp>) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+impl AccountStore for HashMap<AccountId, Account> {
+    fn get(&self, id: AccountId) -> Result<Option<Account>, StoreError> {
+        Ok(HashMap::get(self, &id).cloned())
+    }
+
+    fn insert(&mut self, account: Account) -> Result<(), StoreError> {
+        HashMap::insert(self, account.id(), account);
+        Ok(())
+    }
+
+    fn remove(&mut self, id: AccountId) -> Result<(), StoreError> {
+        HashMap::remove(self, &id);
+        Ok(())
+    }
+
+    fn accounts(&self) -> Result<Vec<Account>, StoreError> {
+        let mut accounts = self
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        accounts.sort_by_key(Account::id);
+
+        Ok(accounts)
+    }
+}
+
+impl TransactionStore for HashMap<TransactionId, StoredTransaction> {
+    fn get(&self, id: TransactionId) -> Result<Option<StoredTransaction>, StoreError> {
+        Ok(HashMap::get(self, &id).cloned())
+    }
+
+    fn contains(&self, id: TransactionId) -> Result<bool, StoreError> {
+        Ok(self.contains_key(&id))
+    }
+
+    fn insert(&mut self, record: StoredTransaction) -> Result<(), StoreError> {
+        HashMap::insert(self, record.transaction().id(), record);
+        Ok(())
+    }
+
+    fn remove(&mut self, id: TransactionId) -> Result<(), StoreError> {
+        HashMap::remove(self, &id);
+        Ok(())
+    }
+
+    fn records(&self) -> Box<dyn Iterator<Item = Result<StoredTransaction, StoreError>> + '_> {
+        let mut records = self
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        records.sort_by_key(|record| record.transaction().id());
+
+        Box::new(records.into_iter().map(Ok))
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::{Account, AccountId, AccountStore, StoreError, Transaction, TransactionEngine, TransactionStore};
+
+/// A file path in the temporary directory, whose file is removed again when it's dropped
+///
+/// Paths are unique per process and name, so tests running in parallel don't interfere.
+pub(crate) struct TempPath(PathBuf);
+
+impl TempPath {
+    /// A fresh path, without a file left over from an earlier run
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("transaction-engine-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        Self(path)
+    }
+}
+
+impl std::ops::Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Reads transactions from CSV rows, whose fields may be padded with whitespace
+pub(crate) fn read(transactions: &str) -> Vec<Transaction> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .trim(csv::Trim::All)
+        .from_reader(transactions.as_bytes());
+
+    reader
+        .deserialize()
+        .map(Result::unwrap)
+        .collect()
+}
+
+/// Processes transactions from CSV rows, no matter whether they are accepted or rejected
+pub(crate) fn process<A: AccountStore, T: TransactionStore>(engine: &mut TransactionEngine<A, T>, transactions: &str) {
+    for transaction in read(transactions) {
+        let _ = engine.handle_transaction(transaction);
+    }
+}
+
+/// An account store that cannot write the account of one client
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct FailingAccounts {
+    pub(crate) accounts: HashMap<AccountId, Account>,
+    pub(crate) failing: Option<AccountId>,
+}
+
+impl AccountStore for FailingAccounts {
+    fn get(&self, id: AccountId) -> Result<Option<Account>, StoreError> {
+        AccountStore::get(&self.accounts, id)
+    }
+
+    fn insert(&mut self, account: Account) -> Result<(), StoreError> {
+        if self.failing == Some(account.id()) {
+            return Err(StoreError::Io(std::io::Error::other("the disk is full")));
+        }
+        AccountStore::insert(&mut self.accounts, account)
+    }
+
+    fn remove(&mut self, id: AccountId) -> Result<(), StoreError> {
+        AccountStore::remove(&mut self.accounts, id)
+    }
+
+    fn accounts(&self) -> Result<Vec<Account>, StoreError> {
+        self.accounts.accounts()
+    }
+}
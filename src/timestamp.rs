@@ -0,0 +1,44 @@
+use std::time::Duration;
+
+/// A point in time, in seconds since the Unix epoch
+///
+/// Transactions may carry a timestamp, which decides whether they can still be disputed.
+/// See [`EngineConfig::dispute_window`](crate::EngineConfig::dispute_window).
+#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(u64);
+
+impl Timestamp {
+    /// Creates a timestamp from the seconds since the Unix epoch
+    pub fn new(seconds: u64) -> Self {
+        Self(seconds)
+    }
+
+    /// The seconds since the Unix epoch
+    pub fn value(self) -> u64 {
+        self.0
+    }
+
+    /// The time that passed since an earlier timestamp, or zero if it's actually later
+    pub fn elapsed_since(self, earlier: Self) -> Duration {
+        Duration::from_secs(self.0.saturating_sub(earlier.0))
+    }
+}
+
+impl std::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn elapsed_time_saturates() {
+        let (earlier, later) = (Timestamp::new(100), Timestamp::new(160));
+
+        assert_eq!(later.elapsed_since(earlier), Duration::from_secs(60));
+        assert_eq!(earlier.elapsed_since(later), Duration::ZERO);
+    }
+}
@@ -1,11 +1,38 @@
-use crate::{account::AccountId, Amount};
+use crate::{account::AccountId, Amount, Currency, DisputeOpening, RedisputePolicy, Timestamp, TransactionError, TransactionState};
+
+/// Possible errors to occur while building a transaction, see [`TransactionBuilder`]
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum TransactionBuildError {
+    #[error("A {0} requires the field `{1}`")]
+    MissingField(TransactionType, &'static str),
+    #[error("A {0} cannot have the field `{1}`")]
+    UnexpectedField(TransactionType, &'static str),
+}
 
 /// The unique identifier of a transaction
-#[derive(Clone, Copy, Debug, serde::Deserialize, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct TransactionId(u32);
 
+impl TransactionId {
+    /// Creates an id from its numeric value
+    pub fn new(value: u32) -> Self {
+        Self(value)
+    }
+
+    /// The numeric value of the id
+    pub fn value(self) -> u32 {
+        self.0
+    }
+}
+
+impl std::fmt::Display for TransactionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 /// The different types of transactions supported by the transaction engine
-#[derive(Clone, Copy, Debug, serde::Deserialize, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
     /// A credit to the client's asset account
@@ -18,13 +45,41 @@ pub enum TransactionType {
     Resolve,
     /// The final step of a dispute and the client reversing a transaction
     Chargeback,
+    /// An operator lifting a lock or freeze from the client's account
+    Unlock,
+    /// An operator temporarily freezing the client's account, requires a reason
+    Freeze,
+    /// An operator closing the client's account and paying out the available funds
+    Close,
+    /// A conversion of funds from the transaction currency to a target currency
+    Convert,
+    /// A transfer of funds from the client's account to a destination client's account
+    Transfer,
+}
+
+impl std::fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Deposit => "deposit",
+            Self::Withdrawal => "withdrawal",
+            Self::Dispute => "dispute",
+            Self::Resolve => "resolve",
+            Self::Chargeback => "chargeback",
+            Self::Unlock => "unlock",
+            Self::Freeze => "freeze",
+            Self::Close => "close",
+            Self::Convert => "convert",
+            Self::Transfer => "transfer",
+        };
+        f.write_str(name)
+    }
 }
 
 /// A transactions
 ///
 /// Transactions are orders to the transaction engine to modify the funds and
 /// the state of a clients account.
-#[derive(Debug, serde::Deserialize)]
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
 pub struct Transaction {
     #[serde(rename = "tx")]
     id: TransactionId,
@@ -32,9 +87,129 @@ pub struct Transaction {
     transaction_type: TransactionType,
     client: AccountId,
     amount: Option<Amount>,
+    #[serde(default, deserialize_with = "deserialize_currency")]
+    currency: Currency,
+    target_currency: Option<Currency>,
+    destination: Option<AccountId>,
+    reason: Option<String>,
+    timestamp: Option<Timestamp>,
 }
 
 impl Transaction {
+    /// Creates a deposit in the default currency
+    pub fn deposit(id: TransactionId, client: AccountId, amount: Amount) -> Self {
+        Self::new(id, TransactionType::Deposit, client, Some(amount), Currency::default(), None)
+    }
+
+    /// Creates a withdrawal in the default currency
+    pub fn withdrawal(id: TransactionId, client: AccountId, amount: Amount) -> Self {
+        Self::new(id, TransactionType::Withdrawal, client, Some(amount), Currency::default(), None)
+    }
+
+    /// Creates a dispute of the transaction with the specified id
+    pub fn dispute(id: TransactionId, client: AccountId) -> Self {
+        Self::new(id, TransactionType::Dispute, client, None, Currency::default(), None)
+    }
+
+    /// Creates a resolve of the dispute of the transaction with the specified id
+    pub fn resolve(id: TransactionId, client: AccountId) -> Self {
+        Self::new(id, TransactionType::Resolve, client, None, Currency::default(), None)
+    }
+
+    /// Creates a chargeback of the transaction with the specified id
+    pub fn chargeback(id: TransactionId, client: AccountId) -> Self {
+        Self::new(id, TransactionType::Chargeback, client, None, Currency::default(), None)
+    }
+
+    /// Creates an unlock of the client's account
+    pub fn unlock(id: TransactionId, client: AccountId) -> Self {
+        Self::new(id, TransactionType::Unlock, client, None, Currency::default(), None)
+    }
+
+    /// Creates a freeze of the client's account
+    pub fn freeze(id: TransactionId, client: AccountId, reason: impl Into<String>) -> Self {
+        Self::new(id, TransactionType::Freeze, client, None, Currency::default(), Some(reason.into()))
+    }
+
+    /// Creates a close of the client's account
+    pub fn close(id: TransactionId, client: AccountId) -> Self {
+        Self::new(id, TransactionType::Close, client, None, Currency::default(), None)
+    }
+
+    /// Creates a conversion of an amount from one currency to another
+    pub fn convert(id: TransactionId, client: AccountId, amount: Amount, from: Currency, to: Currency) -> Self {
+        Self::new(id, TransactionType::Convert, client, Some(amount), from, None).with_target_currency(Some(to))
+    }
+
+    /// Creates a transfer in the default currency from the client to the destination
+    pub fn transfer(id: TransactionId, client: AccountId, amount: Amount, destination: AccountId) -> Self {
+        Self::new(id, TransactionType::Transfer, client, Some(amount), Currency::default(), None).with_destination(Some(destination))
+    }
+
+    /// Moves the amount of the transaction to another currency
+    ///
+    /// Like in CSV input, the currency of disputes, resolves, chargebacks and administrative
+    /// transactions is ignored.
+    pub fn in_currency(self, currency: Currency) -> Self {
+        Self { currency, ..self }
+    }
+
+    /// Sets the time the transaction happened at
+    pub fn at(self, timestamp: Timestamp) -> Self {
+        self.with_timestamp(Some(timestamp))
+    }
+
+    /// Starts building a transaction field by field
+    ///
+    /// Unlike the constructors for each type, the builder checks at runtime that the fields
+    /// fit the type:
+    /// ```
+    /// # use transaction_engine::{AccountId, Transaction, TransactionBuildError, TransactionId, TransactionType};
+    /// let deposit = Transaction::builder(TransactionType::Deposit, TransactionId::new(1), AccountId::new(1))
+    ///     .amount("1.5".parse()?)
+    ///     .build()?;
+    /// let close = Transaction::builder(TransactionType::Close, TransactionId::new(2), AccountId::new(1))
+    ///     .amount("1.5".parse()?)
+    ///     .build();
+    /// assert_eq!(close, Err(TransactionBuildError::UnexpectedField(TransactionType::Close, "amount")));
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn builder(transaction_type: TransactionType, id: TransactionId, client: AccountId) -> TransactionBuilder {
+        TransactionBuilder {
+            transaction: Self::new(id, transaction_type, client, None, Currency::default(), None),
+        }
+    }
+
+    /// Creates a transaction without checking whether the fields fit the type
+    pub(crate) fn new(id: TransactionId, transaction_type: TransactionType, client: AccountId, amount: Option<Amount>, currency: Currency, reason: Option<String>) -> Self {
+        Self {
+            id,
+            transaction_type,
+            client,
+            amount,
+            currency,
+            target_currency: None,
+            destination: None,
+            reason,
+            timestamp: None,
+        }
+    }
+
+    /// Sets the currency a conversion converts to
+    pub(crate) fn with_target_currency(self, target_currency: Option<Currency>) -> Self {
+        Self { target_currency, ..self }
+    }
+
+    /// Sets the client a transfer sends funds to
+    pub(crate) fn with_destination(self, destination: Option<AccountId>) -> Self {
+        Self { destination, ..self }
+    }
+
+    /// Sets the time the transaction happened at
+    pub(crate) fn with_timestamp(self, timestamp: Option<Timestamp>) -> Self {
+        Self { timestamp, ..self }
+    }
+
     /// The unique id of a transaction
     pub fn id(&self) -> TransactionId {
         self.id
@@ -55,4 +230,495 @@ impl Transaction {
     pub fn amount(&self) -> Option<Amount> {
         self.amount
     }
+
+    /// The currency of the amount
+    ///
+    /// Transactions without a currency are in the default currency. Disputes, resolves and
+    /// chargebacks always affect the currency of the referenced transaction, so their own
+    /// currency is ignored.
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    /// The currency a conversion converts to
+    /// Will only be populated for conversions
+    pub fn target_currency(&self) -> Option<Currency> {
+        self.target_currency
+    }
+
+    /// The client a transfer sends funds to
+    /// Will only be populated for transfers
+    pub fn destination(&self) -> Option<AccountId> {
+        self.destination
+    }
+
+    /// The reason of an administrative transaction
+    /// Will only be populated for freezes
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
+
+    /// The time the transaction happened at, if known
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
+    }
+
+    /// The transaction as a flat row without a dispute state, i.e. for CSV
+    ///
+    /// See [`StoredTransaction::row`] for the rows of saved transactions.
+    pub fn row(&self) -> TransactionRow {
+        TransactionRow {
+            transaction_type: self.transaction_type,
+            client: self.client,
+            id: self.id,
+            amount: self.amount,
+            currency: self.currency,
+            target_currency: self.target_currency,
+            destination: self.destination,
+            reason: self.reason.clone(),
+            timestamp: self.timestamp,
+            state: None,
+            disputes: None,
+            disputed: None,
+            charged_back: None,
+            opened_at: None,
+            opened_sequence: None,
+            converted: None,
+        }
+    }
+}
+
+/// Builds a [`Transaction`] field by field, see [`Transaction::builder`]
+#[derive(Clone, Debug)]
+pub struct TransactionBuilder {
+    transaction: Transaction,
+}
+
+impl TransactionBuilder {
+    /// Sets the amount of a deposit, withdrawal, conversion or transfer
+    pub fn amount(mut self, amount: Amount) -> Self {
+        self.transaction.amount = Some(amount);
+        self
+    }
+
+    /// Sets the currency of the amount, see [`Transaction::in_currency`]
+    pub fn currency(mut self, currency: Currency) -> Self {
+        self.transaction.currency = currency;
+        self
+    }
+
+    /// Sets the currency a conversion converts to
+    pub fn target_currency(mut self, currency: Currency) -> Self {
+        self.transaction.target_currency = Some(currency);
+        self
+    }
+
+    /// Sets the client a transfer sends funds to
+    pub fn destination(mut self, destination: AccountId) -> Self {
+        self.transaction.destination = Some(destination);
+        self
+    }
+
+    /// Sets the reason of a freeze
+    pub fn reason(mut self, reason: impl Into<String>) -> Self {
+        self.transaction.reason = Some(reason.into());
+        self
+    }
+
+    /// Sets the time the transaction happened at, which is allowed for every type
+    pub fn timestamp(mut self, timestamp: Timestamp) -> Self {
+        self.transaction.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Checks that the transaction has the fields its type requires, and no others than those it allows
+    pub fn build(self) -> Result<Transaction, TransactionBuildError> {
+        let transaction = self.transaction;
+        let transaction_type = transaction.transaction_type;
+        let required: &[&str] = match transaction_type {
+            TransactionType::Deposit | TransactionType::Withdrawal => &["amount"],
+            TransactionType::Convert => &["amount", "target_currency"],
+            TransactionType::Transfer => &["amount", "destination"],
+            TransactionType::Freeze => &["reason"],
+            TransactionType::Dispute
+            | TransactionType::Resolve
+            | TransactionType::Chargeback
+            | TransactionType::Unlock
+            | TransactionType::Close => &[],
+        };
+        // disputes may be limited to a portion of the referenced amount
+        let optional: &[&str] = match transaction_type {
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => &["amount"],
+            _ => &[],
+        };
+        let fields = [
+            ("amount", transaction.amount.is_some()),
+            ("target_currency", transaction.target_currency.is_some()),
+            ("destination", transaction.destination.is_some()),
+            ("reason", transaction.reason.is_some()),
+        ];
+
+        for (field, present) in fields {
+            match (present, required.contains(&field)) {
+                (false, true) => return Err(TransactionBuildError::MissingField(transaction_type, field)),
+                (true, false) if !optional.contains(&field) => {
+                    return Err(TransactionBuildError::UnexpectedField(transaction_type, field))
+                }
+                _ => {}
+            }
+        }
+
+        Ok(transaction)
+    }
+}
+
+/// Reads an optional currency, where an empty CSV field means the default currency
+fn deserialize_currency<'de, D>(deserializer: D) -> Result<Currency, D::Error>
+    where D: serde::Deserializer<'de>
+{
+    Ok(<Option<Currency> as serde::Deserialize>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// A transaction saved by the transaction engine, together with its dispute state
+///
+/// Disputes may only cover a portion of the amount. The record keeps track of the portion
+/// that's currently disputed, and the portion that was charged back.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+#[serde(try_from = "SerializedStoredTransaction")]
+pub struct StoredTransaction {
+    transaction: Transaction,
+    state: TransactionState,
+    disputes: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    converted: Option<Amount>,
+    disputed: Amount,
+    charged_back: Amount,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    opened: Option<DisputeOpening>,
+}
+
+/// The serialized form of a [`StoredTransaction`], which is checked when it's restored
+#[derive(serde::Deserialize)]
+struct SerializedStoredTransaction {
+    transaction: Transaction,
+    state: TransactionState,
+    disputes: u32,
+    converted: Option<Amount>,
+    disputed: Amount,
+    charged_back: Amount,
+    opened: Option<DisputeOpening>,
+}
+
+impl TryFrom<SerializedStoredTransaction> for StoredTransaction {
+    type Error = String;
+
+    fn try_from(record: SerializedStoredTransaction) -> Result<Self, Self::Error> {
+        Ok(Self::restore(record.transaction, record.state, record.disputes, record.converted)
+            .with_portions(record.disputed, record.charged_back)?
+            .with_opening(record.opened))
+    }
+}
+
+impl StoredTransaction {
+    /// Creates a new, settled record of a transaction
+    pub(crate) fn new(transaction: Transaction) -> Self {
+        Self {
+            transaction,
+            state: TransactionState::Settled,
+            disputes: 0,
+            converted: None,
+            disputed: Amount::ZERO,
+            charged_back: Amount::ZERO,
+            opened: None,
+        }
+    }
+
+    /// Creates a new record of a conversion, together with the amount it credited
+    pub(crate) fn conversion(transaction: Transaction, converted: Amount) -> Self {
+        Self {
+            converted: Some(converted),
+            ..Self::new(transaction)
+        }
+    }
+
+    /// Recreates a previously stored record
+    ///
+    /// The record has no disputed or charged back portions, unless they are set using
+    /// [`StoredTransaction::with_portions`].
+    pub(crate) fn restore(transaction: Transaction, state: TransactionState, disputes: u32, converted: Option<Amount>) -> Self {
+        Self {
+            transaction,
+            state,
+            disputes,
+            converted,
+            disputed: Amount::ZERO,
+            charged_back: Amount::ZERO,
+            opened: None,
+        }
+    }
+
+    /// Sets the disputed and charged back portions of a restored record
+    ///
+    /// Fails if the portions together exceed the amount, so a corrupted record cannot break
+    /// the undisputed portion.
+    pub(crate) fn with_portions(self, disputed: Amount, charged_back: Amount) -> Result<Self, String> {
+        let amount = self.transaction.amount.unwrap_or_default();
+        match disputed.checked_add(charged_back) {
+            Some(portions) if portions <= amount => Ok(Self { disputed, charged_back, ..self }),
+            _ => Err(format!("the disputed and charged back portions exceed the amount of transaction {}", self.transaction.id)),
+        }
+    }
+
+    /// Sets when the open dispute of a restored record was opened, if it's known
+    pub(crate) fn with_opening(self, opened: Option<DisputeOpening>) -> Self {
+        Self { opened, ..self }
+    }
+
+    /// The saved transaction
+    pub fn transaction(&self) -> &Transaction {
+        &self.transaction
+    }
+
+    /// The current state of the transaction
+    pub fn state(&self) -> TransactionState {
+        self.state
+    }
+
+    /// The number of disputes that were opened for the transaction so far
+    pub fn disputes(&self) -> u32 {
+        self.disputes
+    }
+
+    /// When the open dispute of the transaction was opened
+    ///
+    /// Further portions disputed while the dispute is open don't change this.
+    pub fn opened(&self) -> Option<DisputeOpening> {
+        self.opened
+    }
+
+    /// The portion of the amount that's currently disputed
+    pub fn disputed(&self) -> Amount {
+        self.disputed
+    }
+
+    /// The portion of the amount that was charged back
+    pub fn charged_back(&self) -> Amount {
+        self.charged_back
+    }
+
+    /// The portion of the amount that's neither disputed nor charged back
+    pub fn undisputed(&self) -> Amount {
+        // restoring a record and all dispute steps make sure the portions never exceed the amount
+        self.transaction.amount.unwrap_or_default() - self.disputed - self.charged_back
+    }
+
+    /// The amount credited in the target currency, if the transaction is a conversion
+    pub fn converted(&self) -> Option<Amount> {
+        self.converted
+    }
+
+    /// The record as a flat row, i.e. for CSV
+    pub fn row(&self) -> TransactionRow {
+        TransactionRow {
+            state: Some(self.state),
+            disputes: Some(self.disputes),
+            disputed: Some(self.disputed),
+            charged_back: Some(self.charged_back),
+            opened_at: self.opened.and_then(|opened| opened.timestamp),
+            opened_sequence: self.opened.map(|opened| opened.sequence),
+            converted: self.converted,
+            ..self.transaction.row()
+        }
+    }
+
+    /// Disputes a portion of the undisputed amount, by default all of it
+    ///
+    /// Further portions can be disputed while a dispute is open. Returns the disputed portion.
+    pub(crate) fn dispute(&mut self, amount: Option<Amount>, policy: RedisputePolicy, opening: DisputeOpening) -> Result<Amount, TransactionError> {
+        let undisputed = self.undisputed();
+        let state = match self.state {
+            // a transaction that was partially charged back cannot be disputed anymore
+            _ if !self.charged_back.is_zero() => return Err(TransactionError::ChargedBack),
+            TransactionState::Disputed if !undisputed.is_zero() => TransactionState::Disputed,
+            state => state.dispute(self.disputes, policy)?,
+        };
+        let portion = amount.unwrap_or(undisputed);
+        if portion.is_zero() {
+            return Err(TransactionError::ZeroAmount);
+        }
+        if portion > undisputed {
+            return Err(TransactionError::ExcessiveAmount);
+        }
+
+        // further portions of an open dispute don't count as a new dispute
+        if !self.state.is_disputed() {
+            self.disputes += 1;
+            self.opened = Some(opening);
+        }
+        self.disputed += portion;
+        self.state = state;
+
+        Ok(portion)
+    }
+
+    /// Resolves a portion of the disputed amount, by default all of it
+    ///
+    /// Returns the resolved portion.
+    pub(crate) fn resolve(&mut self, amount: Option<Amount>) -> Result<Amount, TransactionError> {
+        let state = self.state.resolve()?;
+        let portion = self.take_disputed(amount)?;
+        self.complete(state);
+
+        Ok(portion)
+    }
+
+    /// Charges back a portion of the disputed amount, by default all of it
+    ///
+    /// Returns the charged back portion.
+    pub(crate) fn charge_back(&mut self, amount: Option<Amount>) -> Result<Amount, TransactionError> {
+        let state = self.state.charge_back()?;
+        let portion = self.take_disputed(amount)?;
+        self.charged_back += portion;
+        self.complete(state);
+
+        Ok(portion)
+    }
+
+    /// Removes a portion from the disputed amount
+    fn take_disputed(&mut self, amount: Option<Amount>) -> Result<Amount, TransactionError> {
+        let portion = amount.unwrap_or(self.disputed);
+        if portion.is_zero() {
+            return Err(TransactionError::ZeroAmount);
+        }
+        self.disputed = self.disputed
+            .checked_sub(portion)
+            .ok_or(TransactionError::ExcessiveAmount)?;
+
+        Ok(portion)
+    }
+
+    /// Leaves the disputed state once no portion is disputed anymore
+    ///
+    /// Any chargeback is final, so the transaction stays charged back even if other
+    /// portions were resolved.
+    fn complete(&mut self, state: TransactionState) {
+        if self.disputed.is_zero() {
+            self.opened = None;
+            self.state = match self.charged_back.is_zero() {
+                true => state,
+                false => TransactionState::ChargedBack,
+            };
+        }
+    }
+}
+
+/// A transaction together with the dispute state of its record, if it's saved
+///
+/// Rows serialize to flat records with the columns of the transaction input, followed by
+/// `state`, `disputes`, `disputed`, `charged_back`, `opened_at`, `opened_sequence` and
+/// `converted`. The columns after the input are empty for transactions that aren't saved.
+/// Since the input ignores unknown columns, exported rows can be read again as transactions.
+/// See [`Transaction::row`] and [`StoredTransaction::row`].
+#[derive(Clone, Debug, serde::Serialize, PartialEq, Eq)]
+pub struct TransactionRow {
+    #[serde(rename = "type")]
+    pub transaction_type: TransactionType,
+    pub client: AccountId,
+    #[serde(rename = "tx")]
+    pub id: TransactionId,
+    pub amount: Option<Amount>,
+    pub currency: Currency,
+    pub target_currency: Option<Currency>,
+    pub destination: Option<AccountId>,
+    pub reason: Option<String>,
+    pub timestamp: Option<Timestamp>,
+    pub state: Option<TransactionState>,
+    pub disputes: Option<u32>,
+    pub disputed: Option<Amount>,
+    pub charged_back: Option<Amount>,
+    pub opened_at: Option<Timestamp>,
+    pub opened_sequence: Option<u64>,
+    pub converted: Option<Amount>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(row: &str) -> Transaction {
+        csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .from_reader(format!("type, client, tx, amount, currency, target_currency, destination, reason, timestamp\n{}", row).as_bytes())
+            .deserialize()
+            .next()
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn constructors_match_csv() {
+        let (id, client) = (TransactionId::new(7), AccountId::new(3));
+        let amount = "1.5".parse().unwrap();
+
+        assert_eq!(Transaction::deposit(id, client, amount), parse("deposit, 3, 7, 1.5,,,,"));
+        assert_eq!(Transaction::withdrawal(id, client, amount).in_currency(Currency::EUR), parse("withdrawal, 3, 7, 1.5, EUR,,,"));
+        assert_eq!(Transaction::dispute(id, client), parse("dispute, 3, 7,,,,,"));
+        assert_eq!(Transaction::freeze(id, client, "fraud"), parse("freeze, 3, 7,,,,, fraud"));
+        assert_eq!(Transaction::convert(id, client, amount, Currency::EUR, Currency::USD), parse("convert, 3, 7, 1.5, EUR, USD,,"));
+        assert_eq!(Transaction::transfer(id, client, amount, AccountId::new(4)), parse("transfer, 3, 7, 1.5,,, 4,"));
+        assert_eq!(Transaction::dispute(id, client).at(Timestamp::new(1_700_000_000)), parse("dispute, 3, 7,,,,,, 1700000000"));
+    }
+
+    #[test]
+    fn rows_can_be_read_again() {
+        let transfer = Transaction::transfer(TransactionId::new(7), AccountId::new(3), Amount::from(5), AccountId::new(4));
+        let mut record = StoredTransaction::new(transfer.at(Timestamp::new(1_700_000_000)));
+        let opening = DisputeOpening { timestamp: Some(Timestamp::new(1_700_000_100)), sequence: 2 };
+        record.dispute(Some(Amount::from(2)), RedisputePolicy::Never, opening).unwrap();
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.serialize(record.row()).unwrap();
+        let csv = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+
+        assert_eq!(
+            csv,
+            "type,client,tx,amount,currency,target_currency,destination,reason,timestamp,state,disputes,disputed,charged_back,opened_at,opened_sequence,converted\n\
+             transfer,3,7,5,USD,,4,,1700000000,disputed,1,2,0,1700000100,2,\n",
+        );
+        let transaction = csv::Reader::from_reader(csv.as_bytes())
+            .deserialize::<Transaction>()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(&transaction, record.transaction());
+    }
+
+    #[test]
+    fn builder_checks_fields() {
+        let builder = |transaction_type| Transaction::builder(transaction_type, TransactionId::new(1), AccountId::new(1));
+        let amount = Amount::from(10);
+
+        assert_eq!(
+            builder(TransactionType::Deposit).amount(amount).currency(Currency::GBP).build(),
+            Ok(Transaction::deposit(TransactionId::new(1), AccountId::new(1), amount).in_currency(Currency::GBP)),
+        );
+        assert_eq!(
+            builder(TransactionType::Deposit).build(),
+            Err(TransactionBuildError::MissingField(TransactionType::Deposit, "amount")),
+        );
+        assert!(builder(TransactionType::Chargeback).amount(amount).build().is_ok());
+        assert_eq!(
+            builder(TransactionType::Close).amount(amount).build(),
+            Err(TransactionBuildError::UnexpectedField(TransactionType::Close, "amount")),
+        );
+        assert_eq!(
+            builder(TransactionType::Transfer).amount(amount).build(),
+            Err(TransactionBuildError::MissingField(TransactionType::Transfer, "destination")),
+        );
+        assert_eq!(
+            builder(TransactionType::Withdrawal).amount(amount).reason("fraud").build(),
+            Err(TransactionBuildError::UnexpectedField(TransactionType::Withdrawal, "reason")),
+        );
+        assert!(builder(TransactionType::Freeze).reason("fraud").build().is_ok());
+    }
 }